@@ -1,5 +1,12 @@
 extern crate exitcode;
 use clap::{Parser, Subcommand};
+use kvs::{Engine, Error, KvStore, LogEncoder};
+use std::{
+    io::Read,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -7,46 +14,329 @@ use clap::{Parser, Subcommand};
 struct Args {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Never compact on writes, only by `kvs compact`
+    #[clap(long, global = true)]
+    no_auto_compact: bool,
+
+    /// The store dir, `~` for the home dir, the current dir by default
+    #[clap(long, global = true)]
+    dir: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// A missing key exits with 0, a broken store with a non-zero code
     #[clap(arg_required_else_help = true)]
     Get {
-        /// The remote to clone
+        /// The key to get
         key: String,
+
+        /// Print it instead if the key is not found
+        #[clap(long)]
+        default: Option<String>,
     },
 
     #[clap(arg_required_else_help = true)]
     Set {
-        /// The remote to clone
+        /// The key to set
         key: String,
-        value: String,
+
+        /// The value to set, unless read by one of the flags below
+        #[clap(required_unless_present_any = &["value-from-file", "value-from-stdin"])]
+        value: Option<String>,
+
+        /// Read the value from the file, as is
+        #[clap(long, conflicts_with_all = &["value", "value-from-stdin"])]
+        value_from_file: Option<PathBuf>,
+
+        /// Read the value from stdin until its end, as is
+        #[clap(long, conflicts_with = "value")]
+        value_from_stdin: bool,
     },
 
     #[clap(arg_required_else_help = true)]
     Rm {
-        /// The remote to clone
+        /// The key to remove
         key: String,
     },
+
+    /// Compact the store
+    Compact {
+        /// Don't print the progress
+        #[clap(long)]
+        quiet: bool,
+
+        /// Merge all the segments and drop the removed keys
+        #[clap(long)]
+        full: bool,
+    },
+
+    /// Print the records of a segment file with their offsets,
+    /// without opening the store
+    #[clap(arg_required_else_help = true)]
+    DumpSegment {
+        /// The segment file, e.g. data_0
+        file: PathBuf,
+
+        /// Print each record as a json line
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Set random keys into a temporary store then get them,
+    /// printing the throughput of each phase
+    Bench {
+        /// The number of sets, and of gets
+        #[clap(long, default_value_t = 10000)]
+        ops: usize,
+
+        /// The bytes of each value
+        #[clap(long, default_value_t = 100)]
+        value_size: usize,
+
+        /// `value` keeps the values apart from the keys, same as `kv_sep`
+        #[clap(long, default_value = "ptr", possible_values = ["ptr", "value", "kv_sep"])]
+        engine: String,
+    },
 }
 
 fn main() {
     let args = Args::parse();
+    let dir = store_dir(args.dir);
+    let open_store = || open_store(dir.clone(), !args.no_auto_compact);
     match args.command {
-        Commands::Get { key } => {
-            eprintln!("get key:{}, unimplemented", key);
-            std::process::exit(exitcode::SOFTWARE);
+        Commands::Get { key, default } => match open_store().get(key) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => match default {
+                Some(default) => println!("{}", default),
+                None => println!("Key not found"),
+            },
+            Err(e) => {
+                eprintln!("get failed: {}", e);
+                std::process::exit(e.exit_code());
+            }
+        },
+
+        Commands::Set {
+            key,
+            value,
+            value_from_file,
+            value_from_stdin,
+        } => {
+            let value = read_value(value, value_from_file, value_from_stdin);
+            if let Err(e) = open_store().set(key, value) {
+                eprintln!("set failed: {}", e);
+                std::process::exit(e.exit_code());
+            }
         }
 
-        Commands::Set { key, value } => {
-            eprintln!("set key:{} value:{}, unimplemented", key, value);
-            std::process::exit(exitcode::SOFTWARE);
+        Commands::Rm { key } => match open_store().remove(key) {
+            Ok(()) => {}
+            Err(e @ Error::KeyNotFound { .. }) => {
+                println!("Key not found");
+                std::process::exit(e.exit_code());
+            }
+            Err(e) => {
+                eprintln!("rm failed: {}", e);
+                std::process::exit(e.exit_code());
+            }
+        },
+
+        Commands::Compact { quiet, full } => {
+            if !quiet {
+                println!("compacting store in {}", dir.display());
+            }
+
+            let stats = KvStore::open(&dir).and_then(|mut store| {
+                if full {
+                    store.full_compact()
+                } else {
+                    store.compact()
+                }
+            });
+            match stats {
+                Ok(stats) => {
+                    if !quiet {
+                        println!(
+                            "before: {} bytes in {} segments",
+                            stats.before_bytes, stats.before_segments
+                        );
+                        println!(
+                            "after: {} bytes in {} segments",
+                            stats.after_bytes, stats.after_segments
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("compact failed: {}", e);
+                    std::process::exit(e.exit_code());
+                }
+            }
         }
 
-        Commands::Rm { key } => {
-            eprintln!("rm key:{}, unimplemented", key);
-            std::process::exit(exitcode::SOFTWARE);
+        Commands::DumpSegment { file, json } => dump_segment(file, json),
+
+        Commands::Bench {
+            ops,
+            value_size,
+            engine,
+        } => {
+            let engine = match engine.as_str() {
+                "value" | "kv_sep" => Engine::KvSep,
+                _ => Engine::Ptr,
+            };
+            bench(ops, value_size, engine);
         }
     }
 }
+
+/// the temporary store is removed whether the bench fails or not
+fn bench(ops: usize, value_size: usize, engine: Engine) {
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let dir = std::env::temp_dir().join(format!("kvs-bench-{}-{}", std::process::id(), seed));
+    let res = run_bench(&dir, ops, value_size, engine, seed);
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        eprintln!("remove bench dir {} failed: {}", dir.display(), e);
+    }
+    if let Err(e) = res {
+        eprintln!("bench failed: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run_bench(
+    dir: &std::path::Path,
+    ops: usize,
+    value_size: usize,
+    engine: Engine,
+    seed: u64,
+) -> kvs::Result<()> {
+    let mut store = KvStore::builder().engine(engine).open(dir)?;
+    let mut rng = XorShift(seed | 1);
+    let key_space = ops.max(1) as u64;
+
+    let start = Instant::now();
+    for _ in 0..ops {
+        let key = format!("key{}", rng.next() % key_space);
+        let value: String = (0..value_size)
+            .map(|_| (b'a' + (rng.next() % 26) as u8) as char)
+            .collect();
+        store.set(key, value)?;
+    }
+    print_throughput("set", ops, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..ops {
+        let _ = store.get(format!("key{}", rng.next() % key_space))?;
+    }
+    print_throughput("get", ops, start.elapsed());
+    Ok(())
+}
+
+fn print_throughput(phase: &str, ops: usize, elapsed: Duration) {
+    let ops_per_sec = ops as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    println!(
+        "{}: {} ops in {:.3}s, {:.0} ops/sec",
+        phase,
+        ops,
+        elapsed.as_secs_f64(),
+        ops_per_sec
+    );
+}
+
+/// good enough to pick the keys and values of a bench
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn dump_segment(file: PathBuf, json: bool) {
+    let records = KvStore::read_segment(&file, Arc::new(LogEncoder)).unwrap_or_else(|e| {
+        eprintln!("dump segment failed: {}", e);
+        std::process::exit(e.exit_code());
+    });
+    for (offset, item) in records {
+        if json {
+            let line = serde_json::json!({
+                "offset": offset,
+                "cmd": item.cmd,
+                "key": item.key,
+                "value": item.value,
+            });
+            println!("{}", line);
+        } else {
+            match item.value {
+                Some(value) => println!("{} {} {} {}", offset, item.cmd, item.key, value),
+                None => println!("{} {} {}", offset, item.cmd, item.key),
+            }
+        }
+    }
+}
+
+/// the value given on the command line, or read from the file or
+/// stdin, which must be utf-8 as the values are strings
+fn read_value(value: Option<String>, from_file: Option<PathBuf>, from_stdin: bool) -> String {
+    let (source, res) = match (value, from_file) {
+        (Some(value), _) => return value,
+        (None, Some(path)) => (path.display().to_string(), std::fs::read_to_string(&path)),
+        (None, None) => {
+            debug_assert!(from_stdin);
+            let mut value = String::new();
+            let res = std::io::stdin().read_to_string(&mut value);
+            ("stdin".to_owned(), res.map(|_| value))
+        }
+    };
+    res.unwrap_or_else(|e| {
+        eprintln!("read value from {} failed: {}", source, e);
+        match e.kind() {
+            std::io::ErrorKind::InvalidData => std::process::exit(exitcode::DATAERR),
+            _ => std::process::exit(exitcode::IOERR),
+        }
+    })
+}
+
+fn current_dir() -> PathBuf {
+    std::env::current_dir().unwrap_or_else(|e| {
+        eprintln!("get current dir failed: {}", e);
+        std::process::exit(exitcode::OSERR);
+    })
+}
+
+/// the absolute store dir, a leading `~` is the home dir and a
+/// relative one is under the current dir
+fn store_dir(dir: Option<PathBuf>) -> PathBuf {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return current_dir(),
+    };
+    let dir = match dir.strip_prefix("~") {
+        Ok(rest) => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(rest),
+            None => {
+                eprintln!("expand {} failed: HOME is not set", dir.display());
+                std::process::exit(exitcode::CONFIG);
+            }
+        },
+        Err(_) => dir,
+    };
+    let dir = current_dir().join(dir);
+    // a missing dir is created by the store later
+    dir.canonicalize().unwrap_or(dir)
+}
+
+fn open_store(dir: PathBuf, auto_compact: bool) -> KvStore {
+    let store = KvStore::builder().auto_compact(auto_compact).open(dir);
+    store.unwrap_or_else(|e| {
+        eprintln!("open store failed: {}", e);
+        std::process::exit(e.exit_code());
+    })
+}
@@ -0,0 +1,80 @@
+use std::{
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// source of the current time, injected into the store so that
+/// the expiry checks and timestamps can be controlled in tests
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// the real wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// a clock only moving when told to, for tests
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> MockClock {
+        MockClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{Clock, MockClock};
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::default();
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+
+        // pass a ttl without sleeping
+        let ttl = Duration::from_secs(60);
+        let expire_at = clock.now() + ttl;
+        clock.advance(Duration::from_secs(59));
+        assert!(clock.now() < expire_at);
+        clock.advance(Duration::from_secs(1));
+        assert!(clock.now() >= expire_at);
+
+        clock.set(SystemTime::UNIX_EPOCH);
+        assert!(clock.now() < expire_at);
+    }
+}
@@ -1,14 +1,20 @@
-use log::info;
+use log::{info, warn};
 use snafu::{location, Location, ResultExt, Snafu};
 
-use crate::kv_store::LogFiles;
-use crate::log_file::{Error as LogFileError, LogFileBuilder};
+use crate::kv_store::{parse_file_id, LogFiles};
+use crate::lock::{self, RwLockExt};
+use crate::log_file::{self, Error as LogFileError, LogFileBuilder, WriteAmpCounter};
+use flate2::{write::GzEncoder, Compression};
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::Write;
 use std::mem::replace;
 use std::{
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, TryLockError,
+    },
 };
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -26,6 +32,9 @@ pub enum Error {
         path: PathBuf,
     },
 
+    #[snafu(display("{} compaction cancelled", location))]
+    Cancelled { location: Location },
+
     #[snafu(display("{} {}", location, dscr))]
     Unknown { location: Location, dscr: String },
 }
@@ -33,7 +42,54 @@ pub enum Error {
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub trait Compactor {
-    fn compact(&self) -> Result<()>;
+    /// false if skipped, for another compaction running
+    /// or nothing to compact
+    fn compact(&self) -> Result<bool>;
+}
+
+/// shared by the store and its compactions, which check it between
+/// records and stop once cancelled, leaving the old files in place
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// all the compactions after it are cancelled too
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return CancelledSnafu.fail();
+        }
+        Ok(())
+    }
+}
+
+/// the compacting lock, none if another compaction holds it
+fn try_compacting(compacting: &Mutex<()>) -> Option<MutexGuard<'_, ()>> {
+    match compacting.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::WouldBlock) => None,
+        Err(TryLockError::Poisoned(e)) => Some(lock::recover(e)),
+    }
+}
+
+/// the store's on-disk footprint before and after a compaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+    pub before_segments: usize,
+    pub after_segments: usize,
 }
 
 /// this compactor may call LogFile's scan to get all cmds,
@@ -50,36 +106,78 @@ impl SimpleCompactor {
 
 /// just straightly return log_file
 impl Compactor for SimpleCompactor {
-    fn compact(&self) -> Result<()> {
+    fn compact(&self) -> Result<bool> {
+        // the latest immutable is assumed to be the rotated one below
+        let (compacting, cancel) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            (
+                log_files_inner.compacting.clone(),
+                log_files_inner.cancel.clone(),
+            )
+        };
+        let _compacting = match try_compacting(&compacting) {
+            Some(guard) => guard,
+            None => {
+                info!("in compact, another compaction is running, skip");
+                return Ok(false);
+            }
+        };
+        cancel.check()?;
+
         // create a new mutable, and push old mutable to immutable,
         // should finish immediately
         {
-            let mut log_files_inner = self.log_files.write().unwrap();
+            let mut log_files_inner = self.log_files.write_or_recover();
+            // nothing to compact, e.g. just rotated by another compaction
+            let mut_len = {
+                let inner = log_files_inner.mutable.read_or_recover();
+                inner
+                    .len()
+                    .context(ProcessLogFileSnafu { path: inner.path() })?
+            };
+            if mut_len == 0 {
+                info!("in compact, the mutable is empty, skip");
+                return Ok(false);
+            }
+
             let new_mut_path = log_files_inner.next_mut_path();
             let _ = File::create(new_mut_path.as_path()).context(ProcessOsFileSnafu {
                 path: new_mut_path.clone(),
             })?;
             info!("in compact, switch the mutable file to {}", new_mut_path.display());
-            let new_mut_file = LogFileBuilder::build(&new_mut_path, "ptr")
+            let mode = log_files_inner.mutable.read_or_recover().mode();
+            let new_mut_file = LogFileBuilder::build(&new_mut_path, mode, &log_files_inner.options)
                 .context(ProcessLogFileSnafu { path: new_mut_path })?;
             let old_mut_file = replace(&mut log_files_inner.mutable, new_mut_file);
+            {
+                let mut inner = old_mut_file.write_or_recover();
+                let old_mut_path = inner.path();
+                inner
+                    .seal()
+                    .context(ProcessLogFileSnafu { path: old_mut_path })?;
+            }
 
             log_files_inner.immutables.push(old_mut_file);
+            log_files_inner.continue_seq();
         }
         
         // get from the last, read lock
-        let (latest_immut_path, cmds) = {
-            let log_files_inner = self.log_files.read().unwrap();
+        let (latest_immut_path, cmds, compaction_dir, write_amp) = {
+            let log_files_inner = self.log_files.read_or_recover();
             // @todo unwrap is legal?
             let latest_immut_file = log_files_inner.immutables.last().unwrap();
-            let mut inner = latest_immut_file.write().unwrap();
+            let mut inner = latest_immut_file.write_or_recover();
             let latest_immut_path = inner.path();
 
             (
                 latest_immut_path.clone(),
-                inner.scan().context(ProcessLogFileSnafu {
-                    path: latest_immut_path,
-                })?,
+                inner.scan_versions(log_files_inner.keep_versions).context(
+                    ProcessLogFileSnafu {
+                        path: latest_immut_path,
+                    },
+                )?,
+                log_files_inner.compaction_dir.clone(),
+                log_files_inner.options.write_amp.clone(),
             )
         };
         let cmds_print_size = if cmds.len() > 10 {
@@ -106,33 +204,34 @@ impl Compactor for SimpleCompactor {
         };
         compact_file_name.push_str(".compact");
         let mut latest_immut_compact_path = latest_immut_path;
-        latest_immut_compact_path.set_file_name(compact_file_name);
-        let mut latest_immut_compact_file = File::create(latest_immut_compact_path.as_path())
-            .context(ProcessOsFileSnafu {
-                path: latest_immut_compact_path.clone(),
-            })?;
-        
-        for cmd in cmds {
-            latest_immut_compact_file
-                .write_all(cmd.as_bytes())
-                .context(ProcessOsFileSnafu {
-                    path: latest_immut_compact_path.clone(),
-                })?;
+        latest_immut_compact_path.set_file_name(&compact_file_name);
+
+        // write into the scratch dir if any, a partial file is removed
+        // on failure, then move it next to the file for the atomic rename
+        match compaction_dir {
+            Some(compaction_dir) => {
+                let scratch_path = compaction_dir.join(&compact_file_name);
+                write_compact_file(&scratch_path, &cmds, &write_amp, &cancel)?;
+                move_file(&scratch_path, &latest_immut_compact_path)?;
+            }
+            None => write_compact_file(&latest_immut_compact_path, &cmds, &write_amp, &cancel)?,
+        }
+
+        // the last chance to keep the old file
+        if let Err(e) = cancel.check() {
+            let _ = fs::remove_file(&latest_immut_compact_path);
+            return Err(e);
         }
 
-        // pop the old log_file, push the compacted_log_file
-        // will remove or change the file, so should close
-        // the old log file first
+        // rename the compacted file over the old one, which is atomic as
+        // both are in the same dir, then swap the file in memory
         {
-            let mut log_files_inner = self.log_files.write().unwrap();
-            let old_immut_file = log_files_inner.immutables.pop().unwrap();
-            let old_immut_path = { old_immut_file.read().unwrap().path() };
-            drop(old_immut_file);
+            let mut log_files_inner = self.log_files.write_or_recover();
+            let (old_immut_path, mode) = {
+                let inner = log_files_inner.immutables.last().unwrap().read_or_recover();
+                (inner.path(), inner.mode())
+            };
 
-            // remove and rename
-            fs::remove_file(old_immut_path.as_path()).context(ProcessOsFileSnafu {
-                path: old_immut_path.clone(),
-            })?;
             fs::rename(
                 latest_immut_compact_path.as_path(),
                 old_immut_path.as_path(),
@@ -140,16 +239,619 @@ impl Compactor for SimpleCompactor {
             .context(ProcessOsFileSnafu {
                 path: latest_immut_compact_path,
             })?;
-            let new_immut_file = LogFileBuilder::build(old_immut_path.as_path(), "ptr").context(
-                ProcessLogFileSnafu {
-                    path: old_immut_path.clone(),
-                },
-            )?;
-            log_files_inner.immutables.push(new_immut_file);
+            let new_immut_file =
+                LogFileBuilder::build(old_immut_path.as_path(), mode, &log_files_inner.options)
+                    .context(ProcessLogFileSnafu {
+                        path: old_immut_path.clone(),
+                    })?;
+            new_immut_file
+                .write_or_recover()
+                .seal()
+                .context(ProcessLogFileSnafu {
+                    path: old_immut_path,
+                })?;
+            let old_immut_file = replace(
+                log_files_inner.immutables.last_mut().unwrap(),
+                new_immut_file,
+            );
+            drop(old_immut_file);
+            let last = log_files_inner.immutables.len() - 1;
+            compress_if_enabled(&mut log_files_inner, last)?;
         }
 
-        Ok(())
+        Ok(true)
+    }
+}
+
+/// write and sync the cmds into a new file, removing it on failure
+/// or once cancelled
+fn write_compact_file(
+    path: &Path,
+    cmds: &[String],
+    write_amp: &WriteAmpCounter,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let write = || -> Result<()> {
+        let mut file = File::create(path).context(ProcessOsFileSnafu { path })?;
+        for cmd in cmds {
+            cancel.check()?;
+            file.write_all(cmd.as_bytes())
+                .context(ProcessOsFileSnafu { path })?;
+            write_amp.record_physical(cmd.len());
+        }
+        file.sync_all().context(ProcessOsFileSnafu { path })
+    };
+
+    let res = write();
+    if res.is_err() {
+        warn!("in compact, write {} failed, remove it", path.display());
+        let _ = fs::remove_file(path);
+    }
+    res
+}
+
+/// the versions of a key ordered by the file id and the place in it,
+/// a remove is only kept at the front to hide the older ones
+type Versions = Vec<((usize, usize), Option<(String, Option<u64>)>)>;
+
+/// add a version of the key, keeping the newest `keep` sets after
+/// its last remove
+fn add_version(
+    versions: &mut Versions,
+    at: (usize, usize),
+    value: Option<(String, Option<u64>)>,
+    keep: usize,
+) {
+    let place = versions.partition_point(|(other, _)| *other < at);
+    let hidden = versions
+        .first()
+        .is_some_and(|(removed_at, value)| value.is_none() && *removed_at > at);
+    if hidden {
+        return;
+    }
+    if value.is_none() {
+        let _ = versions.drain(..place);
+        versions.insert(0, (at, value));
+    } else {
+        versions.insert(place, (at, value));
+    }
+    let leading = versions.first().is_some_and(|(_, value)| value.is_none()) as usize;
+    while versions.len() > keep + leading {
+        let _ = versions.remove(leading);
+    }
+}
+
+/// the keys whose newest record is a tombstone, each drops one
+/// tombstone from the merge, the others are hidden by a set
+fn removed_keys(live: &HashMap<String, Versions>) -> usize {
+    live.values()
+        .filter(|versions| versions.last().is_some_and(|(_, value)| value.is_none()))
+        .count()
+}
+
+/// rewrite the immutable into `<path>.gz` by gzip and read it from
+/// there, if the store compresses its segments. only the ptr engine
+/// reads a compressed one. a crash before the plain file is removed
+/// leaves both, of the same id and records
+fn compress_if_enabled(log_files_inner: &mut LogFiles, i: usize) -> Result<()> {
+    let (path, mode) = {
+        let inner = log_files_inner.immutables[i].read_or_recover();
+        (inner.path(), inner.mode())
+    };
+    if !log_files_inner.compress_segments || mode != "ptr" || log_file::is_compressed(&path) {
+        return Ok(());
     }
+
+    let mut gz_path = path.clone().into_os_string();
+    gz_path.push(".gz");
+    let gz_path = PathBuf::from(gz_path);
+    let mut tmp_path = gz_path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let compress = || -> std::io::Result<()> {
+        let mut encoder = GzEncoder::new(File::create(&tmp_path)?, Compression::default());
+        let _ = std::io::copy(&mut File::open(&path)?, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::rename(&tmp_path, &gz_path)
+    };
+    if let Err(e) = compress() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e).context(ProcessOsFileSnafu { path: gz_path });
+    }
+
+    let compressed = LogFileBuilder::build(&gz_path, mode, &log_files_inner.options).context(
+        ProcessLogFileSnafu {
+            path: gz_path.clone(),
+        },
+    )?;
+    let old_file = replace(&mut log_files_inner.immutables[i], compressed);
+    let old_paths = old_file.read_or_recover().file_paths();
+    drop(old_file);
+    for old_path in old_paths {
+        fs::remove_file(old_path.as_path()).context(ProcessOsFileSnafu { path: old_path })?;
+    }
+    info!(
+        "in compact, compress {} into {}",
+        path.display(),
+        gz_path.display()
+    );
+    Ok(())
+}
+
+/// rename if on the same file system, or copy and remove the source,
+/// neither is left on failure
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    info!("in compact, copy {} to {}", from.display(), to.display());
+    let copy = || -> std::io::Result<()> {
+        let _ = fs::copy(from, to)?;
+        File::open(to)?.sync_all()
+    };
+    let res = copy().context(ProcessOsFileSnafu { path: to });
+    if res.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    let _ = fs::remove_file(from);
+    res
+}
+
+/// this compactor merges all the files into a new immutable one,
+/// then switches to a new empty mutable. a tombstone can only be dropped
+/// when no older file holds its key, which is always true in a full merge.
+/// it blocks the store until finished, and the recent op ids are not kept
+struct FullCompactor {
+    log_files: Arc<RwLock<LogFiles>>,
+}
+
+impl FullCompactor {
+    pub fn new(log_files: Arc<RwLock<LogFiles>>) -> FullCompactor {
+        FullCompactor { log_files }
+    }
+}
+
+impl Compactor for FullCompactor {
+    fn compact(&self) -> Result<bool> {
+        let (compacting, cancel) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            (
+                log_files_inner.compacting.clone(),
+                log_files_inner.cancel.clone(),
+            )
+        };
+        let _compacting = match try_compacting(&compacting) {
+            Some(guard) => guard,
+            None => {
+                info!("in compact, another compaction is running, skip");
+                return Ok(false);
+            }
+        };
+
+        let mut log_files_inner = self.log_files.write_or_recover();
+
+        // the files are numbered by their ids rather than their places,
+        // the mutable is the newest
+        let log_files: Vec<_> = log_files_inner
+            .immutables
+            .iter()
+            .chain(std::iter::once(&log_files_inner.mutable))
+            .collect();
+        let mut ids_and_sizes = Vec::with_capacity(log_files.len());
+        for (i, log_file) in log_files.iter().enumerate() {
+            let inner = log_file.read_or_recover();
+            let path = inner.path();
+            let id = path
+                .file_name()
+                .and_then(|f_name| parse_file_id(&f_name.to_string_lossy()))
+                .unwrap_or(i);
+            let size = inner.len().context(ProcessLogFileSnafu { path })?;
+            ids_and_sizes.push((id, size));
+        }
+
+        // replay the files in the merge order, the versions of a key are
+        // ordered by their files and places, whichever file is read first.
+        // a removed key is dropped from the live set together with its
+        // tombstone
+        let keep_versions = log_files_inner.keep_versions.max(1);
+        let mut live: HashMap<String, Versions> = HashMap::new();
+        // the op ids are carried into the merged records
+        let mut op_ids: HashMap<(usize, usize), String> = HashMap::new();
+        for i in merge_order(&ids_and_sizes, log_files_inner.merge_order) {
+            let id = ids_and_sizes[i].0;
+            let inner = log_files[i].read_or_recover();
+            let mut place = 0_usize;
+            let mut replay = |key: String, value: Option<(String, Option<u64>)>, op_id| {
+                place += 1;
+                if let Some(op_id) = op_id {
+                    let _ = op_ids.insert((id, place), op_id);
+                }
+                add_version(
+                    live.entry(key).or_default(),
+                    (id, place),
+                    value,
+                    keep_versions,
+                );
+            };
+            for item in inner.iter() {
+                cancel.check()?;
+                let item = item.context(ProcessLogFileSnafu { path: inner.path() })?;
+                match item.cmd.as_str() {
+                    "set" => {
+                        // an empty value is kept, while a missing one is invalid
+                        let value = match item.value {
+                            Some(value) => value,
+                            None => {
+                                return Err(Error::Unknown {
+                                    location: location!(),
+                                    dscr: format!(
+                                        "set without value for key {} in {}",
+                                        item.key,
+                                        inner.path().display()
+                                    ),
+                                })
+                            }
+                        };
+                        replay(item.key, Some((value, item.ts)), item.op_id);
+                    }
+                    "rm" => replay(item.key, None, item.op_id),
+                    _ => warn!("in full compact, skip unknown log {:?}", item),
+                }
+            }
+        }
+        drop(log_files);
+        let dropped_tombstones = removed_keys(&live);
+        let mut live: Vec<_> = live
+            .into_iter()
+            .flat_map(|(key, versions)| {
+                versions
+                    .into_iter()
+                    .filter_map(move |(at, value)| value.map(|value| (at, key.clone(), value)))
+            })
+            .collect();
+        // written in the order they are read, so the output is the same
+        // every time whatever the order of the map
+        live.sort_by_key(|(at, _, _)| *at);
+        info!(
+            "in full compact, live keys:{}, dropped tombstones:{}",
+            live.len(),
+            dropped_tombstones
+        );
+
+        // write the live set into a new immutable
+        let mode = log_files_inner.mutable.read_or_recover().mode();
+        let merged_path = log_files_inner.next_mut_path();
+        let _ = File::create(merged_path.as_path()).context(ProcessOsFileSnafu {
+            path: merged_path.clone(),
+        })?;
+        let merged_file = LogFileBuilder::build(&merged_path, mode, &log_files_inner.options)
+            .context(ProcessLogFileSnafu {
+                path: merged_path.clone(),
+            })?;
+        let merge = || -> Result<()> {
+            let mut inner = merged_file.write_or_recover();
+            // the merged records are numbered after all the old ones
+            inner.advance_seq(log_files_inner.last_seq());
+            // the merged records keep their timestamps, the versions of
+            // a key are written from the oldest
+            for (at, key, (value, ts)) in live {
+                cancel.check()?;
+                inner
+                    .copy_set(key, value, ts, op_ids.get(&at).cloned())
+                    .context(ProcessLogFileSnafu {
+                        path: merged_path.clone(),
+                    })?;
+            }
+            inner.seal().context(ProcessLogFileSnafu {
+                path: merged_path.clone(),
+            })
+        };
+        // the old files are untouched so far, drop the partial merge
+        if let Err(e) = merge() {
+            warn!(
+                "in full compact, merge into {} failed, remove it",
+                merged_path.display()
+            );
+            let merged_paths = merged_file.read_or_recover().file_paths();
+            drop(merged_file);
+            for merged_path in merged_paths {
+                let _ = fs::remove_file(merged_path);
+            }
+            return Err(e);
+        }
+
+        // switch to a new mutable
+        let new_mut_path = log_files_inner.next_mut_path();
+        let _ = File::create(new_mut_path.as_path()).context(ProcessOsFileSnafu {
+            path: new_mut_path.clone(),
+        })?;
+        info!(
+            "in full compact, merged into {}, switch the mutable file to {}",
+            merged_path.display(),
+            new_mut_path.display()
+        );
+        let new_mut_file = LogFileBuilder::build(&new_mut_path, mode, &log_files_inner.options)
+            .context(ProcessLogFileSnafu { path: new_mut_path })?;
+
+        // close the old files first, then remove them
+        // @todo a crash before all removed may bring back the removed keys
+        let old_mut_file = replace(&mut log_files_inner.mutable, new_mut_file);
+        let old_immut_files = replace(&mut log_files_inner.immutables, vec![merged_file]);
+        log_files_inner.continue_seq();
+        compress_if_enabled(&mut log_files_inner, 0)?;
+        for old_file in old_immut_files
+            .into_iter()
+            .chain(std::iter::once(old_mut_file))
+        {
+            let old_paths = old_file.read_or_recover().file_paths();
+            drop(old_file);
+            for old_path in old_paths {
+                fs::remove_file(old_path.as_path())
+                    .context(ProcessOsFileSnafu { path: old_path })?;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// this compactor merges the given immutables into one in place of the
+/// newest of them, e.g. the ones holding the most garbage. the others
+/// stay between them, so a key found in a file newer than its record is
+/// dropped, and a tombstone is kept only if an older file holds its key.
+/// it blocks the store until finished
+pub struct MultiCompactor {
+    log_files: Arc<RwLock<LogFiles>>,
+    paths: Vec<PathBuf>,
+}
+
+impl MultiCompactor {
+    pub fn new(log_files: Arc<RwLock<LogFiles>>, paths: Vec<PathBuf>) -> MultiCompactor {
+        MultiCompactor { log_files, paths }
+    }
+}
+
+impl Compactor for MultiCompactor {
+    fn compact(&self) -> Result<bool> {
+        let (compacting, cancel) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            (
+                log_files_inner.compacting.clone(),
+                log_files_inner.cancel.clone(),
+            )
+        };
+        let _compacting = match try_compacting(&compacting) {
+            Some(guard) => guard,
+            None => {
+                info!("in multi compact, another compaction is running, skip");
+                return Ok(false);
+            }
+        };
+
+        let mut log_files_inner = self.log_files.write_or_recover();
+
+        // the files may be gone by another compaction since picked
+        let selected: Vec<usize> = log_files_inner
+            .immutables
+            .iter()
+            .enumerate()
+            .filter(|(_, log_file)| self.paths.contains(&log_file.read_or_recover().path()))
+            .map(|(i, _)| i)
+            .collect();
+        let newest = match selected.last() {
+            Some(newest) => *newest,
+            None => {
+                info!("in multi compact, no file to merge, skip");
+                return Ok(false);
+            }
+        };
+
+        // replay the selected files from the oldest, the mutable
+        // is after all the immutables
+        let keep_versions = log_files_inner.keep_versions.max(1);
+        let mut live: HashMap<String, Versions> = HashMap::new();
+        let mut op_ids: HashMap<(usize, usize), String> = HashMap::new();
+        for i in selected.iter().copied() {
+            let inner = log_files_inner.immutables[i].read_or_recover();
+            let mut place = 0_usize;
+            for item in inner.iter() {
+                cancel.check()?;
+                let item = item.context(ProcessLogFileSnafu { path: inner.path() })?;
+                let value = match (item.cmd.as_str(), item.value) {
+                    ("set", Some(value)) => Some((value, item.ts)),
+                    ("rm", _) => None,
+                    _ => {
+                        warn!("in multi compact, skip unknown log of key {}", item.key);
+                        continue;
+                    }
+                };
+                place += 1;
+                if let Some(op_id) = item.op_id {
+                    let _ = op_ids.insert((i, place), op_id);
+                }
+                add_version(
+                    live.entry(item.key).or_default(),
+                    (i, place),
+                    value,
+                    keep_versions,
+                );
+            }
+        }
+
+        // the versions older than the newest other file holding the key
+        // are hidden by it, a tombstone is only needed to hide one
+        let others: Vec<_> = log_files_inner
+            .immutables
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !selected.contains(i))
+            .chain(std::iter::once((
+                log_files_inner.immutables.len(),
+                &log_files_inner.mutable,
+            )))
+            .rev()
+            .collect();
+        let mut records = Vec::new();
+        for (key, versions) in live {
+            // a file failing to load its index can't be taken as not
+            // holding the key, or a needed tombstone would be dropped
+            let mut barrier = None;
+            for (i, log_file) in others.iter() {
+                let log_file = log_file.read_or_recover();
+                let holds = log_file
+                    .try_contains_key(&key)
+                    .context(ProcessLogFileSnafu {
+                        path: log_file.path(),
+                    })?;
+                if holds {
+                    barrier = Some(*i);
+                    break;
+                }
+            }
+            records.extend(
+                versions
+                    .into_iter()
+                    .filter(|((i, _), value)| {
+                        barrier.map_or(value.is_some(), |barrier| *i > barrier)
+                    })
+                    .map(|(at, value)| (at, key.clone(), value)),
+            );
+        }
+        drop(others);
+        records.sort_by_key(|(at, _, _)| *at);
+        info!(
+            "in multi compact, merge files:{:?} into {} records",
+            selected,
+            records.len()
+        );
+
+        // write into a file next to the newest selected one, then rename
+        // it into its place once the old files are removed
+        let (mut target_path, mode) = {
+            let inner = log_files_inner.immutables[newest].read_or_recover();
+            (inner.path(), inner.mode())
+        };
+        // a compressed file is merged into a plain one of its id
+        if log_file::is_compressed(&target_path) {
+            target_path.set_extension("");
+        }
+        let mut merged_path = target_path.clone().into_os_string();
+        merged_path.push(".compact");
+        let merged_path = PathBuf::from(merged_path);
+        let _ = File::create(merged_path.as_path()).context(ProcessOsFileSnafu {
+            path: merged_path.clone(),
+        })?;
+        let merged_file = LogFileBuilder::build(&merged_path, mode, &log_files_inner.options)
+            .context(ProcessLogFileSnafu {
+                path: merged_path.clone(),
+            })?;
+        let merge = || -> Result<()> {
+            let mut inner = merged_file.write_or_recover();
+            inner.advance_seq(log_files_inner.last_seq());
+            for (at, key, value) in records {
+                cancel.check()?;
+                let op_id = op_ids.get(&at).cloned();
+                match value {
+                    Some((value, ts)) => inner.copy_set(key, value, ts, op_id),
+                    None => inner.tombstone_with_op_id(key, op_id),
+                }
+                .context(ProcessLogFileSnafu {
+                    path: merged_path.clone(),
+                })?;
+            }
+            inner.flush().context(ProcessLogFileSnafu {
+                path: merged_path.clone(),
+            })
+        };
+        let res = merge();
+        let merged_paths = merged_file.read_or_recover().file_paths();
+        drop(merged_file);
+        // the old files are untouched so far, drop the partial merge
+        if let Err(e) = res {
+            warn!(
+                "in multi compact, merge into {} failed, remove it",
+                merged_path.display()
+            );
+            for merged_path in merged_paths {
+                let _ = fs::remove_file(merged_path);
+            }
+            return Err(e);
+        }
+
+        // rename the merged file over the target first, the rename over
+        // an existing file is atomic, so the merged records are always on
+        // disk. the files in memory are only replaced once all is done
+        let target_name = target_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let merged_name = merged_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let mut renamed_paths = Vec::new();
+        for merged_path in merged_paths {
+            // e.g. `data_3.compact.vlog` into `data_3.vlog`
+            let name = merged_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let renamed = merged_path.with_file_name(name.replacen(&*merged_name, &target_name, 1));
+            fs::rename(&merged_path, &renamed).context(ProcessOsFileSnafu { path: merged_path })?;
+            renamed_paths.push(renamed);
+        }
+
+        // then remove the older files from the oldest, and the files of the
+        // target not replaced, e.g. a compressed one or a stale snapshot.
+        // @todo a crash before all removed may bring back the keys whose
+        // tombstones are dropped by the merge
+        for i in selected.iter().copied() {
+            let old_paths = log_files_inner.immutables[i].read_or_recover().file_paths();
+            for old_path in old_paths {
+                if renamed_paths.contains(&old_path) {
+                    continue;
+                }
+                fs::remove_file(old_path.as_path())
+                    .context(ProcessOsFileSnafu { path: old_path })?;
+            }
+        }
+
+        let new_immut_file = LogFileBuilder::build(&target_path, mode, &log_files_inner.options)
+            .context(ProcessLogFileSnafu {
+                path: target_path.clone(),
+            })?;
+        new_immut_file
+            .write_or_recover()
+            .seal()
+            .context(ProcessLogFileSnafu {
+                path: target_path.clone(),
+            })?;
+        for i in selected.iter().rev() {
+            drop(log_files_inner.immutables.remove(*i));
+        }
+        let place = newest + 1 - selected.len();
+        log_files_inner.immutables.insert(place, new_immut_file);
+        log_files_inner.continue_seq();
+        compress_if_enabled(&mut log_files_inner, place)?;
+        info!(
+            "in multi compact, merged {} files into {}",
+            selected.len(),
+            target_path.display()
+        );
+
+        Ok(true)
+    }
+}
+
+/// the indexes of the files in the order to merge them
+fn merge_order(ids_and_sizes: &[(usize, u64)], order: MergeOrder) -> Vec<usize> {
+    let mut indexes: Vec<_> = (0..ids_and_sizes.len()).collect();
+    match order {
+        MergeOrder::ById => indexes.sort_by_key(|i| ids_and_sizes[*i].0),
+        MergeOrder::BySize => indexes.sort_by_key(|i| (ids_and_sizes[*i].1, ids_and_sizes[*i].0)),
+    }
+    indexes
 }
 
 pub struct CompactorBuilder;
@@ -158,18 +860,39 @@ impl CompactorBuilder {
     pub fn build(log_files: Arc<RwLock<LogFiles>>, mode: CompactorMode) -> Box<dyn Compactor> {
         match mode {
             CompactorMode::Simple => Box::new(SimpleCompactor::new(log_files)),
+            CompactorMode::Full => Box::new(FullCompactor::new(log_files)),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CompactorMode {
     Simple,
+    /// merge all the files and drop the tombstones
+    Full,
+}
+
+/// the order a full compaction reads the files in, the newest record
+/// of a key wins by the file id in either order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// from the oldest file to the newest
+    ById,
+    /// from the smallest file to the largest, the tiered way
+    BySize,
 }
 
 #[cfg(test)]
 mod tests {
-    use super::CompactorBuilder;
-    use crate::{kv_store::LogFiles, log_file::LogFileBuilder};
+    use super::{
+        add_version, merge_order, Compactor, CompactorBuilder, MergeOrder, MultiCompactor, Versions,
+    };
+    use crate::{
+        clock::MockClock,
+        kv_store::LogFiles,
+        log_file::{LogFileBuilder, LogFileOptions},
+    };
+    use std::collections::HashMap;
     use std::sync::{Arc, RwLock};
     use tempfile::TempDir;
 
@@ -193,24 +916,33 @@ mod tests {
             .unwrap();
 
         // create log_files, write some data into data_0
-        let mut_log_file = LogFileBuilder::build(mut_file.path(), "ptr").unwrap();
+        let mut_log_file =
+            LogFileBuilder::build(mut_file.path(), "ptr", &LogFileOptions::default()).unwrap();
         let old_mut_file_size = {
             let mut inner = mut_log_file.write().unwrap();
-            for i in 0..500 as u32 {
-                inner.set("key1".to_string(), i.to_string()).unwrap();
+            for i in 0..500_u32 {
+                inner
+                    .set_with_op_id("key1".to_string(), i.to_string(), None)
+                    .unwrap();
             }
-            for i in 0..500 as u32 {
-                inner.set("key2".to_string(), i.to_string()).unwrap()
+            for i in 0..500_u32 {
+                inner
+                    .set_with_op_id("key2".to_string(), i.to_string(), None)
+                    .unwrap()
             }
             inner.len().unwrap()
         };
 
-        let immut_log_files = vec![LogFileBuilder::build(imut_file.path(), "ptr").unwrap()];
+        let immut_log_files =
+            vec![
+                LogFileBuilder::build(imut_file.path(), "ptr", &LogFileOptions::default()).unwrap(),
+            ];
         let test_log_files = Arc::new(RwLock::new(LogFiles::new(
             mut_log_file,
             immut_log_files,
             2,
             temp_dir.path().into(),
+            LogFileOptions::default(),
         )));
 
         // compact
@@ -250,7 +982,7 @@ mod tests {
             // let mut new_last_imut_inner = test_log_files.read().unwrap()
             //     .immutables[1].write().unwrap();
             let log_files_inner = test_log_files.read().unwrap();
-            let mut new_last_imut_inner = log_files_inner.immutables[1].write().unwrap();
+            let new_last_imut_inner = log_files_inner.immutables[1].write().unwrap();
 
             assert!(new_last_imut_inner.len().unwrap() < old_mut_file_size);
             println!(
@@ -259,15 +991,350 @@ mod tests {
                 old_mut_file_size
             );
             assert_eq!(
-                new_last_imut_inner.get("key1".to_owned()).unwrap().unwrap(),
+                new_last_imut_inner.get("key1").unwrap().unwrap(),
                 499.to_string()
             );
             assert_eq!(
-                new_last_imut_inner.get("key2".to_owned()).unwrap().unwrap(),
+                new_last_imut_inner.get("key2").unwrap().unwrap(),
                 499.to_string()
             );
         }
 
         // check current log_files' structure
     }
+
+    #[test]
+    fn full_compact_drops_tombstones() {
+        let temp_dir = TempDir::new().unwrap();
+        let new_log_file = |name: &str, content: &str| {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            LogFileBuilder::build(&path, "ptr", &LogFileOptions::default()).unwrap()
+        };
+
+        // key1 is set in the older file and removed in the newer one,
+        // key3 is set and removed in the same file
+        let imut_log_file = new_log_file("data_0", "");
+        {
+            let mut inner = imut_log_file.write().unwrap();
+            inner
+                .set_with_op_id("key1".to_owned(), "value1".to_owned(), None)
+                .unwrap();
+            inner
+                .set_with_op_id("key2".to_owned(), "value2".to_owned(), None)
+                .unwrap();
+        }
+        let mut_log_file = new_log_file("data_1", "{\"cmd\":\"rm\",\"key\":\"key1\"}\n");
+        {
+            let mut inner = mut_log_file.write().unwrap();
+            inner
+                .set_with_op_id("key3".to_owned(), "value3".to_owned(), None)
+                .unwrap();
+            inner.remove_with_op_id("key3".to_owned(), None).unwrap();
+        }
+
+        let test_log_files = Arc::new(RwLock::new(LogFiles::new(
+            mut_log_file,
+            vec![imut_log_file],
+            2,
+            temp_dir.path().into(),
+            LogFileOptions::default(),
+        )));
+        CompactorBuilder::build(test_log_files.clone(), super::CompactorMode::Full)
+            .compact()
+            .unwrap();
+
+        let log_files_inner = test_log_files.read().unwrap();
+        assert_eq!(log_files_inner.immutables.len(), 1);
+        let merged = log_files_inner.immutables[0].read().unwrap();
+        assert!(merged.path().ends_with("data_2"));
+        assert!(log_files_inner
+            .mutable
+            .read()
+            .unwrap()
+            .path()
+            .ends_with("data_3"));
+
+        // neither the removed keys nor their tombstones are left
+        let items: Vec<_> = merged.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].cmd, "set");
+        assert_eq!(items[0].key, "key2");
+        assert!(!temp_dir.path().join("data_0").exists());
+        assert!(!temp_dir.path().join("data_1").exists());
+    }
+
+    #[test]
+    fn multi_compact_unloaded_barrier() {
+        let temp_dir = TempDir::new().unwrap();
+        let new_log_file = |name: &str, content: &str, options: &LogFileOptions| {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            LogFileBuilder::build(&path, "ptr", options).unwrap()
+        };
+
+        // the tombstone in data_2 hides key1 in data_1, which is left out
+        // of the merge and fails to build its index on the first access
+        let lazy = LogFileOptions {
+            lazy_index: true,
+            max_record_len: 32,
+            ..Default::default()
+        };
+        let immutables = vec![
+            new_log_file(
+                "data_0",
+                "{\"cmd\":\"set\",\"key\":\"key2\",\"value\":\"value2\"}\n",
+                &LogFileOptions::default(),
+            ),
+            new_log_file(
+                "data_1",
+                "{\"cmd\":\"set\",\"key\":\"key1\",\"value\":\"value1\"}\n",
+                &lazy,
+            ),
+            new_log_file(
+                "data_2",
+                "{\"cmd\":\"rm\",\"key\":\"key1\"}\n",
+                &LogFileOptions::default(),
+            ),
+        ];
+        let mut_log_file = new_log_file("data_3", "", &LogFileOptions::default());
+        let test_log_files = Arc::new(RwLock::new(LogFiles::new(
+            mut_log_file,
+            immutables,
+            2,
+            temp_dir.path().into(),
+            LogFileOptions::default(),
+        )));
+
+        // the merge is aborted rather than dropping the tombstone
+        let paths = vec![
+            temp_dir.path().join("data_0"),
+            temp_dir.path().join("data_2"),
+        ];
+        assert!(MultiCompactor::new(test_log_files.clone(), paths)
+            .compact()
+            .is_err());
+        let log_files_inner = test_log_files.read().unwrap();
+        assert_eq!(log_files_inner.immutables.len(), 3);
+        assert!(temp_dir.path().join("data_0").exists());
+        let newest = log_files_inner.immutables[2].read().unwrap();
+        let items: Vec<_> = newest.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].cmd, "rm");
+    }
+
+    #[test]
+    fn add_version_any_order() {
+        // (file id, place) and the value, none for a remove
+        let records = [
+            ((1, 1), Some("value1")),
+            ((1, 2), None),
+            ((2, 1), Some("value2")),
+            ((2, 2), Some("value3")),
+            ((3, 1), Some("value4")),
+            ((3, 2), Some("value5")),
+        ];
+        let replay = |order: &[usize]| {
+            let mut versions = Versions::new();
+            for &i in order {
+                let (at, value) = records[i];
+                let value = value.map(|value| (value.to_owned(), None));
+                add_version(&mut versions, at, value, 3);
+            }
+            versions
+                .into_iter()
+                .filter_map(|(_, value)| value.map(|(value, _)| value))
+                .collect::<Vec<_>>()
+        };
+
+        let newest = ["value3", "value4", "value5"];
+        assert_eq!(replay(&[0, 1, 2, 3, 4, 5]), newest);
+        assert_eq!(replay(&[4, 5, 0, 1, 2, 3]), newest);
+        assert_eq!(replay(&[2, 3, 4, 5, 1, 0]), newest);
+        // the sets after the remove are fewer than kept
+        assert_eq!(replay(&[2, 0, 1]), ["value2"]);
+    }
+
+    #[test]
+    fn removed_keys() {
+        // a tombstone counts only if it's the newest record of its key
+        let records = [
+            ("key1", (1, 1), true),
+            ("key1", (1, 2), false),
+            ("key2", (1, 3), false),
+            ("key2", (2, 1), true),
+            ("key3", (1, 4), false),
+            ("key3", (2, 2), false),
+            ("key4", (2, 3), true),
+        ];
+        let mut live: HashMap<String, Versions> = HashMap::new();
+        for (key, at, is_set) in records {
+            let value = is_set.then(|| ("value".to_owned(), None));
+            add_version(live.entry(key.to_owned()).or_default(), at, value, 1);
+        }
+        assert_eq!(super::removed_keys(&live), 2);
+    }
+
+    #[test]
+    fn full_compact_merge_order() {
+        // a large oldest file, a small newer one and a medium mutable
+        let sizes = [(0, 3000), (1, 100), (2, 1000)];
+        assert_eq!(merge_order(&sizes, MergeOrder::ById), vec![0, 1, 2]);
+        assert_eq!(merge_order(&sizes, MergeOrder::BySize), vec![1, 2, 0]);
+
+        for order in [MergeOrder::ById, MergeOrder::BySize] {
+            let temp_dir = TempDir::new().unwrap();
+            let new_log_file = |name: &str, content: &str| {
+                let path = temp_dir.path().join(name);
+                std::fs::write(&path, content).unwrap();
+                LogFileBuilder::build(&path, "ptr", &LogFileOptions::default()).unwrap()
+            };
+
+            let oldest = new_log_file("data_0", "");
+            {
+                let mut inner = oldest.write().unwrap();
+                for i in 0..100_u32 {
+                    inner
+                        .set_with_op_id(format!("key{}", i), "old".to_owned(), None)
+                        .unwrap();
+                }
+            }
+            let newer = new_log_file("data_1", "{\"cmd\":\"rm\",\"key\":\"key2\"}\n");
+            newer
+                .write()
+                .unwrap()
+                .set_with_op_id("key1".to_owned(), "newer".to_owned(), None)
+                .unwrap();
+            let mutable = new_log_file("data_2", "");
+            {
+                let mut inner = mutable.write().unwrap();
+                for i in 3..20_u32 {
+                    inner
+                        .set_with_op_id(format!("key{}", i), "newest".to_owned(), None)
+                        .unwrap();
+                }
+            }
+
+            // the immutables are placed out of order, their ids decide
+            let test_log_files = Arc::new(RwLock::new(
+                LogFiles::new(
+                    mutable,
+                    vec![newer, oldest],
+                    3,
+                    temp_dir.path().into(),
+                    LogFileOptions::default(),
+                )
+                .with_merge_order(order),
+            ));
+            CompactorBuilder::build(test_log_files.clone(), super::CompactorMode::Full)
+                .compact()
+                .unwrap();
+
+            let log_files_inner = test_log_files.read().unwrap();
+            let merged = log_files_inner.immutables[0].read().unwrap();
+            assert_eq!(merged.get("key0").unwrap(), Some("old".to_owned()));
+            assert_eq!(merged.get("key1").unwrap(), Some("newer".to_owned()));
+            assert_eq!(merged.get("key2").unwrap(), None);
+            assert_eq!(merged.get("key3").unwrap(), Some("newest".to_owned()));
+            assert_eq!(merged.get("key20").unwrap(), Some("old".to_owned()));
+        }
+    }
+
+    #[test]
+    fn skip_while_compacting() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut_path = temp_dir.path().join("data_0");
+        let _ = std::fs::File::create(&mut_path).unwrap();
+        let mut_log_file =
+            LogFileBuilder::build(&mut_path, "ptr", &LogFileOptions::default()).unwrap();
+        mut_log_file
+            .write()
+            .unwrap()
+            .set_with_op_id("key1".to_owned(), "value1".to_owned(), None)
+            .unwrap();
+        let test_log_files = Arc::new(RwLock::new(LogFiles::new(
+            mut_log_file,
+            Vec::new(),
+            1,
+            temp_dir.path().into(),
+            LogFileOptions::default(),
+        )));
+
+        let compacting = test_log_files.read().unwrap().compacting.clone();
+        {
+            let _compacting = compacting.lock().unwrap();
+            for mode in [super::CompactorMode::Simple, super::CompactorMode::Full] {
+                let compactor = CompactorBuilder::build(test_log_files.clone(), mode);
+                assert!(!compactor.compact().unwrap());
+            }
+            let log_files_inner = test_log_files.read().unwrap();
+            assert!(log_files_inner.immutables.is_empty());
+            assert!(log_files_inner
+                .mutable
+                .read()
+                .unwrap()
+                .path()
+                .ends_with("data_0"));
+        }
+
+        let compactor =
+            CompactorBuilder::build(test_log_files.clone(), super::CompactorMode::Simple);
+        assert!(compactor.compact().unwrap());
+        assert_eq!(test_log_files.read().unwrap().immutables.len(), 1);
+    }
+
+    #[test]
+    fn deterministic_output() {
+        let compact_once = |mode| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut_path = temp_dir.path().join("data_0");
+            let _ = std::fs::File::create(&mut_path).unwrap();
+            // the records are stamped by the same time
+            let options = LogFileOptions {
+                clock: Arc::new(MockClock::default()),
+                ..Default::default()
+            };
+            let mut_log_file = LogFileBuilder::build(&mut_path, "ptr", &options).unwrap();
+            {
+                let mut inner = mut_log_file.write().unwrap();
+                for i in 0..300_u32 {
+                    inner
+                        .set_with_op_id(format!("key{}", i % 100), i.to_string(), None)
+                        .unwrap();
+                }
+                for i in 0..10_u32 {
+                    inner.remove_with_op_id(format!("key{}", i), None).unwrap();
+                }
+            }
+            let test_log_files = Arc::new(RwLock::new(LogFiles::new(
+                mut_log_file,
+                Vec::new(),
+                1,
+                temp_dir.path().into(),
+                LogFileOptions::default(),
+            )));
+            let compactor = CompactorBuilder::build(test_log_files.clone(), mode);
+            assert!(compactor.compact().unwrap());
+            drop(test_log_files);
+            let mut paths: Vec<_> = std::fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            paths.sort();
+            paths
+                .into_iter()
+                .flat_map(|path| std::fs::read(path).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let compacted = compact_once(super::CompactorMode::Simple);
+        assert_eq!(compacted, compact_once(super::CompactorMode::Simple));
+        // the latest record of each key, removed ones as tombstones
+        assert_eq!(compacted.iter().filter(|b| **b == b'\n').count(), 100);
+
+        let compacted = compact_once(super::CompactorMode::Full);
+        assert_eq!(compacted, compact_once(super::CompactorMode::Full));
+        // the removed keys are dropped with their tombstones
+        assert_eq!(compacted.iter().filter(|b| **b == b'\n').count(), 90);
+    }
 }
@@ -0,0 +1,101 @@
+use log::{error, info};
+use snafu::{Location, ResultExt, Snafu};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::kv_store::LogFiles;
+use crate::lock::RwLockExt;
+use crate::log_file::Error as LogFileError;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{} flush log_file {}: {}", location, path.display(), source))]
+    FlushLogFile {
+        source: LogFileError,
+        location: Location,
+        path: PathBuf,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// a background thread flushing the log files every interval,
+/// and persisting their indexes if enabled. it flushes once more
+/// and exits when dropped
+pub struct Flusher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub fn start(log_files: Arc<RwLock<LogFiles>>, interval: Duration, snapshot: bool) -> Flusher {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            info!(
+                "flusher start, interval:{:?}, snapshot:{}",
+                interval, snapshot
+            );
+            loop {
+                let stopping = match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+                };
+
+                if let Err(e) = flush(&log_files, snapshot) {
+                    error!("flusher flush failed, e:{}", e);
+                }
+                if stopping {
+                    info!("flusher stop");
+                    return;
+                }
+            }
+        });
+
+        Flusher {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("flusher thread panicked");
+            }
+        }
+    }
+}
+
+/// each file is write locked in turn, the sets to the mutable
+/// wait for it as they do for each other
+pub fn flush(log_files: &RwLock<LogFiles>, snapshot: bool) -> Result<()> {
+    let log_files_inner = log_files.read_or_recover();
+    // reset before syncing, a write racing with it is counted again
+    log_files_inner.unsynced_writes.store(0, Ordering::SeqCst);
+    for log_file in
+        std::iter::once(&log_files_inner.mutable).chain(log_files_inner.immutables.iter())
+    {
+        let mut inner = log_file.write_or_recover();
+        inner
+            .flush()
+            .context(FlushLogFileSnafu { path: inner.path() })?;
+        if snapshot {
+            inner
+                .snapshot()
+                .context(FlushLogFileSnafu { path: inner.path() })?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use log::{error, info};
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::lock::MutexExt;
+
+/// a background thread running the deferred compaction once no write
+/// is recorded for the idle duration, so a write burst isn't slowed
+/// down by it. it exits when dropped
+pub struct IdleCompactor {
+    /// the last write waiting for the compaction, none if there is none
+    last_write: Arc<Mutex<Option<Instant>>>,
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IdleCompactor {
+    pub fn start(idle: Duration, mut compact: impl FnMut() + Send + 'static) -> IdleCompactor {
+        let last_write = Arc::new(Mutex::new(None::<Instant>));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let pending = last_write.clone();
+        let tick = (idle / 4).max(Duration::from_millis(1));
+        let handle = thread::spawn(move || {
+            info!("idle compactor start, idle:{:?}", idle);
+            loop {
+                match stopped.recv_timeout(tick) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                        info!("idle compactor stop");
+                        return;
+                    }
+                }
+
+                let due = {
+                    let mut pending = pending.lock_or_recover();
+                    let due = pending.is_some_and(|at| at.elapsed() >= idle);
+                    if due {
+                        *pending = None;
+                    }
+                    due
+                };
+                if due {
+                    compact();
+                }
+            }
+        });
+
+        IdleCompactor {
+            last_write,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// defer the compaction until the writes pause again
+    pub fn record_write(&self) {
+        *self.last_write.lock_or_recover() = Some(Instant::now());
+    }
+}
+
+impl Drop for IdleCompactor {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("idle compactor thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use std::{borrow::Cow, fmt::Debug};
+
+/// canonicalizes the keys given to the store, e.g. case-folding them, so
+/// the keys of the same canonical form are the same key. it's applied on
+/// every read and write, the files only hold the canonical keys, so it
+/// must not change once a store is written
+pub trait KeyNormalizer: Debug + Send + Sync {
+    fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str>;
+}
+
+/// keeps the keys as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityKeys;
+
+impl KeyNormalizer for IdentityKeys {
+    fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(key)
+    }
+}
@@ -1,15 +1,32 @@
-use log::{debug, error, info};
-use snafu::{Location, ResultExt, Snafu};
+use log::{debug, error, info, warn};
+use snafu::{location, Location, OptionExt, ResultExt, Snafu};
 use std::{
-    fs::File,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
     path::{Path, PathBuf},
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use walkdir::WalkDir;
 
-use crate::log_file::{LogFile, LogFileBuilder};
+use crate::clock::{Clock, SystemClock};
+use crate::flusher::{self, Flusher};
+use crate::idle_compactor::IdleCompactor;
+use crate::key_normalizer::{IdentityKeys, KeyNormalizer};
+use crate::lock::{MutexExt, RwLockExt};
+use crate::log_file::{
+    self, LogFile, LogFileBuilder, LogFileOptions, LogFileStats, LogItem, RecordCodec, ValueRange,
+    WriteAmpStats,
+};
 use crate::{
-    compactor::{CompactorBuilder, CompactorMode},
+    compactor::{
+        self, CancellationToken, CompactionStats, Compactor, CompactorBuilder, CompactorMode,
+        MergeOrder, MultiCompactor,
+    },
     log_file::Error as LogFileError,
 };
 
@@ -22,9 +39,24 @@ pub enum Error {
         path: PathBuf,
     },
 
+    #[snafu(display("{} create store dir {} failed: {}", location, path.display(), source))]
+    CreateDir {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} create segment {} failed: {}", location, path.display(), source))]
+    CreateSegment {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
     #[snafu(display("{} set {} {} in store: {}", location, key, value, source))]
     Set {
-        source: LogFileError,
+        #[snafu(source(from(LogFileError, Box::new)))]
+        source: Box<LogFileError>,
         location: Location,
         key: String,
         value: String,
@@ -39,23 +71,469 @@ pub enum Error {
 
     #[snafu(display("{} rm {} in store: {}", location, key, source))]
     Rm {
-        source: LogFileError,
+        #[snafu(source(from(LogFileError, Box::new)))]
+        source: Box<LogFileError>,
+        location: Location,
+        key: String,
+    },
+
+    #[snafu(display("{} value {} of key {} is not a number", location, value, key))]
+    NotANumber {
+        source: std::num::ParseIntError,
+        location: Location,
+        key: String,
+        value: String,
+    },
+
+    #[snafu(display("{} incr {} of key {} by {} overflows", location, number, key, delta))]
+    IncrOverflow {
         location: Location,
         key: String,
+        number: u64,
+        delta: u64,
+    },
+
+    #[snafu(display("{} key {} not found", location, key))]
+    KeyNotFound { location: Location, key: String },
+
+    #[snafu(display("{} get store's size failed: {}", location, source))]
+    Size {
+        source: LogFileError,
+        location: Location,
     },
 
     #[snafu(display("{} compact mut_file {} failed: {}", location, path.display(), source))]
     Compact {
-        source: crate::compactor::Error,
+        #[snafu(source(from(crate::compactor::Error, Box::new)))]
+        source: Box<crate::compactor::Error>,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} compaction cancelled, the files are kept", location))]
+    CompactionCancelled { location: Location },
+
+    #[snafu(display("{} store {} is of codec {}, not {}", location, path.display(), recorded, codec))]
+    CodecMismatch {
         location: Location,
         path: PathBuf,
+        recorded: String,
+        codec: &'static str,
+    },
+
+    #[snafu(display("{} record codec in {} failed: {}", location, path.display(), source))]
+    RecordCodec {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display(
+        "{} store {} is of layout version {}, only up to {} is supported, upgrade kvs to open it",
+        location,
+        path.display(),
+        recorded,
+        supported
+    ))]
+    UnsupportedLayout {
+        location: Location,
+        path: PathBuf,
+        recorded: String,
+        supported: u32,
+    },
+
+    #[snafu(display(
+        "{} next id {} of store {} is not above its segment id {}",
+        location,
+        next_id,
+        path.display(),
+        max_id
+    ))]
+    NextId {
+        location: Location,
+        path: PathBuf,
+        next_id: usize,
+        max_id: usize,
+    },
+
+    #[snafu(display("{} record layout version in {} failed: {}", location, path.display(), source))]
+    LayoutVersion {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} read segment {} failed: {}", location, path.display(), source))]
+    ReadSegment {
+        source: LogFileError,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} query segment {} failed: {}", location, path.display(), source))]
+    SegmentMetadata {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} rebuild into {} holding a store", location, path.display()))]
+    RebuildTarget { location: Location, path: PathBuf },
+
+    #[snafu(display("{} no store in {}", location, path.display()))]
+    StoreNotFound { location: Location, path: PathBuf },
+
+    #[snafu(display("{} store is opened read-only", location))]
+    ReadOnly { location: Location },
+
+    #[snafu(display("{} write to {} failed, the disk is full", location, path.display()))]
+    DiskFull { location: Location, path: PathBuf },
+
+    #[snafu(display("{} flush failed: {}", location, source))]
+    Flush {
+        #[snafu(source(from(crate::flusher::Error, Box::new)))]
+        source: Box<crate::flusher::Error>,
+        location: Location,
     },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+impl Error {
+    /// the exit code of the cli failing with it, a missing key to remove
+    /// is 1, the broken data and the os failures are told apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::KeyNotFound { .. } => 1,
+            Error::Open { .. }
+            | Error::Get { .. }
+            | Error::ReadSegment { .. }
+            | Error::NotANumber { .. }
+            | Error::IncrOverflow { .. } => exitcode::DATAERR,
+            Error::CreateDir { .. }
+            | Error::CreateSegment { .. }
+            | Error::RecordCodec { .. }
+            | Error::SegmentMetadata { .. }
+            | Error::Flush { .. }
+            | Error::DiskFull { .. } => exitcode::IOERR,
+            Error::CodecMismatch { .. }
+            | Error::UnsupportedLayout { .. }
+            | Error::NextId { .. } => exitcode::CONFIG,
+            Error::StoreNotFound { .. } => exitcode::NOINPUT,
+            Error::ReadOnly { .. } => exitcode::NOPERM,
+            _ => exitcode::SOFTWARE,
+        }
+    }
+}
+
+/// a clone shares the same files, so it can be sent to other threads
+#[derive(Clone)]
 pub struct KvStore {
     log_files: Arc<RwLock<LogFiles>>,
+    /// applied to every key given by the caller
+    key_normalizer: Arc<dyn KeyNormalizer>,
+    /// shared by the clones, stopped when the last one is dropped
+    _flusher: Option<Arc<Flusher>>,
+    /// runs the compactions deferred until the writes pause, if enabled
+    idle_compactor: Option<Arc<IdleCompactor>>,
+    max_immutable_segments: Option<usize>,
+    skip_redundant_sets: bool,
+    compaction_threshold: u64,
+    auto_compact: bool,
+    compactor_mode: CompactorMode,
+    sync_every_writes: Option<usize>,
+    read_only: bool,
+    snapshot_index: bool,
+    multi_compact_segments: usize,
+}
+
+/// the engine of the segments, which decides how a value is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// the index points at the records in the segment
+    Ptr,
+    /// the values are kept in a value log next to the segment
+    KvSep,
+}
+
+impl Engine {
+    /// the mode to build the segments by `LogFileBuilder`
+    fn mode(&self) -> &'static str {
+        match self {
+            Engine::Ptr => "ptr",
+            Engine::KvSep => "kv_sep",
+        }
+    }
+}
+
+/// when the writes are synced to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// left to the os, or `flush_and_checkpoint`
+    Manual,
+    /// synced in background every interval
+    Interval(Duration),
+    /// synced before each write returns, the slowest
+    EveryWrite,
+    /// synced once every n writes, the writes in between may be lost
+    /// together on a crash
+    EveryN(usize),
+}
+
+/// what to do when opening a dir holding no store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// create the dir tree if missing and start from nothing
+    CreateIfMissing,
+    /// fail unless the dir holds a store, for the callers expecting
+    /// an initialized one
+    MustExist,
+}
+
+/// options to open the store with, `KvStore::open` uses the default
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    pub log_file: LogFileOptions,
+    pub clock: Arc<dyn Clock>,
+    /// canonicalizes the keys on every read and write, e.g. case-folds
+    /// them. the keys already written are not changed by another one
+    pub key_normalizer: Arc<dyn KeyNormalizer>,
+    /// flush the files in background every interval, none disables it
+    pub flush_interval: Option<Duration>,
+    /// persist the indexes when flushing in background,
+    /// so opening the files only replays the records after that
+    pub snapshot_index: bool,
+    /// a scratch dir for the compaction output, which is moved into the
+    /// data dir when finished. none writes it in the data dir directly
+    pub compaction_dir: Option<PathBuf>,
+    /// merge all the files by a full compaction once the immutables
+    /// exceed it, to bound the files visited by a read. none is unlimited
+    pub max_immutable_segments: Option<usize>,
+    /// a warning is logged if opening takes longer
+    pub slow_open_threshold: Duration,
+    /// the threads building the indexes of the immutables when opening,
+    /// 1 builds them one by one
+    pub open_parallelism: usize,
+    /// read the key before setting it, and skip the set if the value is
+    /// unchanged. it saves the log from the repeated sets at the cost of
+    /// a read per set
+    pub skip_redundant_sets: bool,
+    pub engine: Engine,
+    /// the mutable is compacted once it grows over it
+    pub compaction_threshold: u64,
+    /// compact the mutable once it is over the threshold, otherwise
+    /// the files only change by `compact` and `full_compact`
+    pub auto_compact: bool,
+    /// defer the compaction of the mutable over the threshold until no
+    /// write for this long, run in background. none compacts it by the
+    /// write going over
+    pub compact_idle: Option<Duration>,
+    /// sync the mutable once this many writes are not synced, 1 syncs
+    /// before each write returns. none leaves it to the os
+    pub sync_every_writes: Option<usize>,
+    /// reject all the writes, including the compactions
+    pub read_only: bool,
+    pub open_mode: OpenMode,
+    /// how the mutable over the threshold is compacted
+    pub compactor_mode: CompactorMode,
+    /// the order a full compaction reads the files in
+    pub merge_order: MergeOrder,
+    /// the versions of each key kept by the compactions for
+    /// `get_version`, 1 keeps the latest only. a compaction of the
+    /// mutable keeps them in the ptr engine only
+    pub keep_versions: usize,
+    /// the id of the next segment, e.g. to keep the segments restored
+    /// from a backup apart from the ones rotated after. it must be
+    /// above the ids in the dir, none continues from the newest
+    pub next_id: Option<usize>,
+    /// the immutables with the most garbage merged by `multi_compact`
+    pub multi_compact_segments: usize,
+    /// gzip each immutable once written by a compaction, the records
+    /// compress far better together than one by one. a compressed
+    /// segment is read into memory when opened, the mutable is never
+    /// compressed. ignored by the kv_sep engine
+    pub compress_segments: bool,
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            log_file: LogFileOptions::default(),
+            clock: Arc::new(SystemClock),
+            key_normalizer: Arc::new(IdentityKeys),
+            flush_interval: None,
+            snapshot_index: false,
+            compaction_dir: None,
+            max_immutable_segments: None,
+            slow_open_threshold: Duration::from_secs(10),
+            open_parallelism: 1,
+            skip_redundant_sets: false,
+            engine: Engine::Ptr,
+            compaction_threshold: 1024 * 1024,
+            auto_compact: true,
+            compact_idle: None,
+            sync_every_writes: None,
+            read_only: false,
+            open_mode: OpenMode::CreateIfMissing,
+            compactor_mode: CompactorMode::Simple,
+            merge_order: MergeOrder::ById,
+            keep_versions: 1,
+            next_id: None,
+            multi_compact_segments: 2,
+            compress_segments: false,
+        }
+    }
+}
+
+/// chainable options to open a store with, a shorthand of `KvStoreConfig`
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreBuilder {
+    config: KvStoreConfig,
+}
+
+impl KvStoreBuilder {
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.config.engine = engine;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        let (flush_interval, sync_every_writes) = match sync_policy {
+            SyncPolicy::Manual => (None, None),
+            SyncPolicy::Interval(interval) => (Some(interval), None),
+            SyncPolicy::EveryWrite => (None, Some(1)),
+            SyncPolicy::EveryN(n) => (None, Some(n.max(1))),
+        };
+        self.config.flush_interval = flush_interval;
+        self.config.sync_every_writes = sync_every_writes;
+        self
+    }
+
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.config.compaction_threshold = bytes;
+        self
+    }
+
+    pub fn auto_compact(mut self, auto_compact: bool) -> Self {
+        self.config.auto_compact = auto_compact;
+        self
+    }
+
+    pub fn compact_when_idle(mut self, idle: Duration) -> Self {
+        self.config.compact_idle = Some(idle);
+        self
+    }
+
+    pub fn max_immutable_segments(mut self, max_segments: usize) -> Self {
+        self.config.max_immutable_segments = Some(max_segments);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    pub fn open_mode(mut self, open_mode: OpenMode) -> Self {
+        self.config.open_mode = open_mode;
+        self
+    }
+
+    pub fn merge_order(mut self, merge_order: MergeOrder) -> Self {
+        self.config.merge_order = merge_order;
+        self
+    }
+
+    pub fn keep_versions(mut self, keep_versions: usize) -> Self {
+        self.config.keep_versions = keep_versions.max(1);
+        self
+    }
+
+    pub fn compress_segments(mut self, compress_segments: bool) -> Self {
+        self.config.compress_segments = compress_segments;
+        self
+    }
+
+    pub fn multi_compact_segments(mut self, segments: usize) -> Self {
+        self.config.multi_compact_segments = segments.max(1);
+        self
+    }
+
+    pub fn next_id(mut self, next_id: usize) -> Self {
+        self.config.next_id = Some(next_id);
+        self
+    }
+
+    pub fn compactor_mode(mut self, compactor_mode: CompactorMode) -> Self {
+        self.config.compactor_mode = compactor_mode;
+        self
+    }
+
+    pub fn key_normalizer(mut self, key_normalizer: Arc<dyn KeyNormalizer>) -> Self {
+        self.config.key_normalizer = key_normalizer;
+        self
+    }
+
+    pub fn codec(mut self, codec: Arc<dyn RecordCodec>) -> Self {
+        self.config.log_file.codec = codec;
+        self
+    }
+
+    pub fn open_parallelism(mut self, threads: usize) -> Self {
+        self.config.open_parallelism = threads.max(1);
+        self
+    }
+
+    pub fn lazy_index(mut self, lazy_index: bool) -> Self {
+        self.config.log_file.lazy_index = lazy_index;
+        self
+    }
+
+    pub fn read_buf_size(mut self, bytes: usize) -> Self {
+        self.config.log_file.read_buf_size = bytes;
+        self
+    }
+
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_config(path, self.config)
+    }
+}
+
+/// the time spent on opening each file, oldest first and the mutable
+/// last, most of it is on building the index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenReport {
+    pub segments: Vec<(PathBuf, Duration)>,
+    pub total: Duration,
+}
+
+/// the reads and writes of each segment since opened, oldest first
+/// and the mutable last, to find the hot ones worth compacting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvStoreStats {
+    pub total: LogFileStats,
+    pub segments: Vec<(PathBuf, LogFileStats)>,
+    /// counted since opened, including the files compacted away
+    pub write_amp: WriteAmpStats,
+    /// the segments whose index is built, all of them unless the
+    /// index is lazy
+    pub loaded_segments: usize,
+    /// bytes of all the segments, 0 if a segment failed to get its size
+    pub total_size: u64,
+}
+
+/// bytes of the segments, and how many a compaction could free
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub total: u64,
+    /// held by the latest records of the live keys
+    pub live: u64,
+    /// held by the overwritten and removed records, and the tombstones
+    pub reclaimable: u64,
 }
 
 pub struct LogFiles {
@@ -63,6 +541,30 @@ pub struct LogFiles {
     pub immutables: Vec<Box<RwLock<dyn LogFile>>>,
     pub next_id: usize,
     pub dir_path: PathBuf,
+    /// used to build the new log files when compacting
+    pub options: LogFileOptions,
+    /// held during a whole compaction, a compaction releases the lock of
+    /// log files between its steps and must not interleave with another.
+    /// a compaction triggered while it is held is skipped
+    pub compacting: Arc<Mutex<()>>,
+    /// where the compactions write their output, none for the data dir
+    pub compaction_dir: Option<PathBuf>,
+    /// the order a full compaction reads the files in
+    pub merge_order: MergeOrder,
+    /// the versions of each key kept by the compactions
+    pub keep_versions: usize,
+    /// gzip the immutables written by the compactions, ptr engine only
+    pub compress_segments: bool,
+    /// the live keys of the store, so checking the presence won't visit
+    /// the files. it is updated along with the mutable, a compaction
+    /// doesn't change it. none if the index is lazy, as collecting them
+    /// builds the index of every file
+    pub live_keys: Option<RwLock<HashSet<String>>>,
+    /// the writes to the mutable since it was synced, only counted
+    /// if the store syncs every n writes
+    pub unsynced_writes: AtomicUsize,
+    /// checked by the compactions to stop early
+    pub cancel: CancellationToken,
 }
 
 impl LogFiles {
@@ -71,15 +573,185 @@ impl LogFiles {
         immutables: Vec<Box<RwLock<dyn LogFile>>>,
         next_id: usize,
         dir_path: PathBuf,
+        options: LogFileOptions,
     ) -> Self {
-        LogFiles {
+        let live_keys = (!options.lazy_index).then(|| RwLock::new(HashSet::new()));
+        let log_files = LogFiles {
             mutable,
             immutables,
             next_id,
             dir_path,
+            options,
+            compacting: Arc::new(Mutex::new(())),
+            compaction_dir: None,
+            merge_order: MergeOrder::ById,
+            keep_versions: 1,
+            compress_segments: false,
+            live_keys,
+            unsynced_writes: AtomicUsize::new(0),
+            cancel: CancellationToken::new(),
+        };
+        log_files.continue_seq();
+        log_files.rebuild_live_keys();
+        log_files
+    }
+
+    /// bytes of the mutable and all the immutables
+    pub fn total_size(&self) -> Result<u64> {
+        let mut bytes = self.mutable.read_or_recover().len().context(SizeSnafu)?;
+        for immut in self.immutables.iter() {
+            bytes += immut.read_or_recover().len().context(SizeSnafu)?;
+        }
+        Ok(bytes)
+    }
+
+    /// the bytes of the records still live, counted from the indexes
+    /// without reading the files. a compressed file holds less on disk
+    /// than its live records, its savings aren't reclaimable
+    pub fn estimate_disk_usage(&self) -> Result<DiskUsage> {
+        let total = self.total_size()?;
+        let mut newer_keys = HashSet::new();
+        let mut live = 0;
+        for log_file in std::iter::once(&self.mutable).chain(self.immutables.iter().rev()) {
+            let inner = log_file.read_or_recover();
+            for (key, is_live) in inner.key_states() {
+                if is_live && !newer_keys.contains(&key) {
+                    live += inner.live_bytes(&key);
+                }
+                let _ = newer_keys.insert(key);
+            }
+        }
+        Ok(DiskUsage {
+            total,
+            live,
+            reclaimable: total.saturating_sub(live),
+        })
+    }
+
+    /// the share of the records of each immutable, oldest first, which
+    /// hold no live value, removed or overwritten in the same or a
+    /// newer file. each file is read through to count its records
+    pub fn garbage_ratios(&self) -> Result<Vec<(PathBuf, f64)>> {
+        let mut newer_keys: HashSet<String> = self
+            .mutable
+            .read_or_recover()
+            .key_states()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let mut ratios = Vec::with_capacity(self.immutables.len());
+        for log_file in self.immutables.iter().rev() {
+            let inner = log_file.read_or_recover();
+            let path = inner.path();
+            let mut records = 0_usize;
+            for item in inner.iter() {
+                let _ = item.context(ReadSegmentSnafu { path: path.clone() })?;
+                records += 1;
+            }
+            let mut live = 0_usize;
+            for (key, is_live) in inner.key_states() {
+                if is_live && !newer_keys.contains(&key) {
+                    live += 1;
+                }
+                let _ = newer_keys.insert(key);
+            }
+            let ratio = match records {
+                0 => 0.0,
+                records => 1.0 - live as f64 / records as f64,
+            };
+            ratios.push((path, ratio));
+        }
+        ratios.reverse();
+        Ok(ratios)
+    }
+
+    /// replay the key states from the oldest file to the newest
+    pub fn rebuild_live_keys(&self) {
+        if let Some(tracked) = self.live_keys.as_ref() {
+            *tracked.write_or_recover() = self.replay_live_keys();
+        }
+    }
+
+    /// the live keys of the store, replayed from the files if not tracked
+    pub fn collect_live_keys(&self) -> HashSet<String> {
+        match self.live_keys.as_ref() {
+            Some(tracked) => tracked.read_or_recover().clone(),
+            None => self.replay_live_keys(),
+        }
+    }
+
+    fn replay_live_keys(&self) -> HashSet<String> {
+        let mut live_keys = HashSet::new();
+        for log_file in self.immutables.iter().chain(std::iter::once(&self.mutable)) {
+            for (key, live) in log_file.read_or_recover().key_states() {
+                if live {
+                    let _ = live_keys.insert(key);
+                } else {
+                    let _ = live_keys.remove(&key);
+                }
+            }
+        }
+        live_keys
+    }
+
+    /// whether the key is live, none if the live keys are not tracked
+    pub fn is_live(&self, key: &str) -> Option<bool> {
+        self.live_keys
+            .as_ref()
+            .map(|live_keys| live_keys.read_or_recover().contains(key))
+    }
+
+    pub fn mark_live(&self, key: String) {
+        if let Some(live_keys) = self.live_keys.as_ref() {
+            let _ = live_keys.write_or_recover().insert(key);
+        }
+    }
+
+    pub fn mark_removed(&self, key: &str) {
+        if let Some(live_keys) = self.live_keys.as_ref() {
+            let _ = live_keys.write_or_recover().remove(key);
         }
     }
 
+    pub fn with_compaction_dir(mut self, compaction_dir: Option<PathBuf>) -> Self {
+        self.compaction_dir = compaction_dir;
+        self
+    }
+
+    pub fn with_keep_versions(mut self, keep_versions: usize) -> Self {
+        self.keep_versions = keep_versions.max(1);
+        self
+    }
+
+    pub fn with_compress_segments(mut self, compress_segments: bool) -> Self {
+        self.compress_segments = compress_segments;
+        self
+    }
+
+    pub fn with_merge_order(mut self, merge_order: MergeOrder) -> Self {
+        self.merge_order = merge_order;
+        self
+    }
+
+    /// the highest sequence number written to the files, it's in the
+    /// newest file numbered as the mutable continues from the others
+    /// whenever switched. the older ones are not visited, so a lazy
+    /// index of them is not built
+    pub fn last_seq(&self) -> u64 {
+        std::iter::once(&self.mutable)
+            .chain(self.immutables.iter().rev())
+            .map(|log_file| log_file.read_or_recover().last_seq())
+            .find(|seq| *seq > 0)
+            .unwrap_or(0)
+    }
+
+    /// number the writes to the mutable after the immutables',
+    /// called whenever the mutable is switched
+    pub fn continue_seq(&self) {
+        let last_seq = self.last_seq();
+        self.mutable.write_or_recover().advance_seq(last_seq);
+    }
+
     pub fn next_mut_path(&mut self) -> PathBuf {
         let mut next_mut_path = self.dir_path.to_owned();
         next_mut_path.push(format!("data_{}", self.next_id));
@@ -89,174 +761,1435 @@ impl LogFiles {
     }
 }
 
+/// the writes left unsynced by syncing every n writes are synced
+/// when the last handle of the store is dropped
+impl Drop for LogFiles {
+    fn drop(&mut self) {
+        if *self.unsynced_writes.get_mut() == 0 {
+            return;
+        }
+
+        let mut inner = self.mutable.write_or_recover();
+        if let Err(e) = inner.flush() {
+            error!(
+                "kv_store sync {} on drop failed, e:{}",
+                inner.path().display(),
+                e
+            );
+        }
+    }
+}
+
 impl KvStore {
+    fn new(log_files: LogFiles, config: &KvStoreConfig) -> KvStore {
+        let log_files = Arc::new(RwLock::new(log_files));
+        let flusher = config.flush_interval.map(|interval| {
+            Arc::new(Flusher::start(
+                log_files.clone(),
+                interval,
+                config.snapshot_index,
+            ))
+        });
+        let idle_compactor = config
+            .compact_idle
+            .filter(|_| config.auto_compact && !config.read_only)
+            .map(|idle| {
+                let log_files = log_files.clone();
+                let (threshold, mode) = (config.compaction_threshold, config.compactor_mode);
+                let max_immutable_segments = config.max_immutable_segments;
+                Arc::new(IdleCompactor::start(idle, move || {
+                    if let Err(e) =
+                        compact_if_over(&log_files, threshold, mode, max_immutable_segments)
+                    {
+                        error!("kv_store idle compaction failed, e:{}", e);
+                    }
+                }))
+            });
+
+        KvStore {
+            log_files,
+            key_normalizer: config.key_normalizer.clone(),
+            _flusher: flusher,
+            idle_compactor,
+            max_immutable_segments: config.max_immutable_segments,
+            skip_redundant_sets: config.skip_redundant_sets,
+            compaction_threshold: config.compaction_threshold,
+            auto_compact: config.auto_compact,
+            compactor_mode: config.compactor_mode,
+            sync_every_writes: config.sync_every_writes,
+            read_only: config.read_only,
+            snapshot_index: config.snapshot_index,
+            multi_compact_segments: config.multi_compact_segments,
+        }
+    }
+
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
     // open
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_config(path, KvStoreConfig::default())
+    }
+
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<KvStore> {
+        KvStore::open_with_report(path, config).map(|(store, _)| store)
+    }
+
+    /// open and report the time spent on each file, mostly on building
+    /// its index, a warning is logged if the total exceeds the threshold
+    pub fn open_with_report(
+        path: impl Into<PathBuf>,
+        mut config: KvStoreConfig,
+    ) -> Result<(KvStore, OpenReport)> {
         let path: PathBuf = path.into();
-        info!("kv_store open from path:{}", path.display());
+        enter_span!("open", path = %path.display());
+        config.log_file.write_amp = Arc::default();
+        config.log_file.clock = config.clock.clone();
+        config.log_file.read_only = config.read_only;
+        info!(
+            "kv_store open from path:{}, config:{:?}",
+            path.display(),
+            config
+        );
+        let start = Instant::now();
+
+        // create the dir tree if missing, then open from nothing. a
+        // read-only store never creates one
+        let must_exist = config.open_mode == OpenMode::MustExist || config.read_only;
+        if !path.exists() {
+            if must_exist {
+                return StoreNotFoundSnafu { path }.fail();
+            }
+            info!("kv_store path:{} not found, create it", path.display());
+            fs::create_dir_all(path.as_path()).context(CreateDirSnafu { path: path.clone() })?;
+        }
 
         // the last is mutable, and others are immutable
-        let mut id_path_pairs = get_file_paths(path.as_path()).unwrap();
+        let mut id_path_pairs =
+            get_file_paths(path.as_path()).context(OpenSnafu { path: path.clone() })?;
+        if must_exist && id_path_pairs.is_empty() {
+            return StoreNotFoundSnafu { path }.fail();
+        }
+        check_codec(
+            &path,
+            &*config.log_file.codec,
+            id_path_pairs.is_empty(),
+            config.read_only,
+        )?;
+        check_layout(&path)?;
 
         // create mut and imuts
-        let create_log_file = |file_path: &Path| {
-            LogFileBuilder::build(file_path, "ptr").context(OpenSnafu { path: file_path })
-        };
+        let mut segments = Vec::with_capacity(id_path_pairs.len().max(1));
 
         // if empty, create
-        let mut next_id = 1;
-        if id_path_pairs.is_empty() {
+        let mut next_id;
+        let log_files = if id_path_pairs.is_empty() {
             info!("kv_store open from nothing");
+            next_id = check_next_id(config.next_id, 0, &path)?;
             let mut new_mut_path = path.clone();
             new_mut_path.push("data_0");
-            let _ = File::create(new_mut_path.as_path());
-
-            Ok(KvStore {
-                log_files: Arc::new(RwLock::new(LogFiles::new(
-                    create_log_file(new_mut_path.as_path())?,
-                    Vec::new(),
-                    next_id,
-                    path,
-                ))),
-            })
+            create_segment(&new_mut_path)?;
+            write_layout(&path)?;
+            let (mutable, elapsed) = open_segment(new_mut_path.as_path(), &config)?;
+            segments.push((new_mut_path, elapsed));
+
+            LogFiles::new(mutable, Vec::new(), next_id, path, config.log_file.clone())
         } else {
             info!("kv_store open from files:{:?}", id_path_pairs);
-            let last_pair = id_path_pairs.pop().unwrap();
-            next_id = last_pair.0 + 1;
-            // gen mutable
-            let mut_path: PathBuf = last_pair.1.into();
-            let mutable = create_log_file(mut_path.as_path())?;
+            let mut last_pair = id_path_pairs.pop().unwrap();
+            next_id = check_next_id(config.next_id, last_pair.0, &path)?;
+
+            // a compressed segment is read-only, write to a new one. a
+            // read-only store reads it in place of the mutable
+            if log_file::is_compressed(Path::new(&last_pair.1)) && !config.read_only {
+                id_path_pairs.push(last_pair);
+                let new_mut_path = path.join(format!("data_{}", next_id));
+                create_segment(&new_mut_path)?;
+                last_pair = (next_id, String::from(new_mut_path.to_string_lossy()));
+                next_id += 1;
+            }
 
             // gen immutables
-            let mut immutables = Vec::with_capacity(id_path_pairs.len());
-            for pair in id_path_pairs {
-                let imut_path: PathBuf = pair.1.into();
-                immutables.push(create_log_file(imut_path.as_path())?)
+            let imut_paths: Vec<PathBuf> = id_path_pairs
+                .into_iter()
+                .map(|pair| pair.1.into())
+                .collect();
+            let mut immutables = Vec::with_capacity(imut_paths.len());
+            for (imut_path, (immutable, elapsed)) in imut_paths
+                .iter()
+                .zip(open_immutables(&imut_paths, &config)?)
+            {
+                segments.push((imut_path.clone(), elapsed));
+                immutables.push(immutable);
             }
 
-            Ok(KvStore {
-                log_files: Arc::new(RwLock::new(LogFiles::new(
-                    mutable, immutables, next_id, path,
-                ))),
-            })
-        }
-    }
+            // gen mutable
+            let mut_path: PathBuf = last_pair.1.into();
+            let (mutable, elapsed) = open_segment(mut_path.as_path(), &config)?;
+            segments.push((mut_path, elapsed));
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        debug!("kv_store get, key:{}", key);
-        let log_files_inner = self.log_files.read().unwrap();
-
-        // check contain as this order: mut, imut.rev
-        let find_target = || {
-            if contains_key(&log_files_inner.mutable, &key) {
-                debug!("get {} in kv_store, found in mutable", key);
-                Some(&log_files_inner.mutable)
-            } else {
-                for immut in log_files_inner.immutables.iter() {
-                    if contains_key(immut, &key) {
-                        debug!("get {} in kv_store, found in immutable", key);
-                        return Some(immut);
-                    }
-                }
+            LogFiles::new(mutable, immutables, next_id, path, config.log_file.clone())
+        }
+        .with_compaction_dir(config.compaction_dir.clone())
+        .with_merge_order(config.merge_order)
+        .with_keep_versions(config.keep_versions)
+        .with_compress_segments(config.compress_segments);
 
-                debug!("get {} in kv_store, not found", key);
-                None
-            }
+        let report = OpenReport {
+            segments,
+            total: start.elapsed(),
         };
-
-        match find_target() {
-            Some(t) => {
-                let mut inner = t.write().unwrap();
-                inner
-                    .get(key.clone())
-                    .context(GetSnafu { key: key.clone() })
-                    .map_err(|e: Error| {
-                        error!("get {} in kv_store, found but encounter err, e:{}", key, e);
-                        e
-                    })
-            }
-
-            None => Ok(None),
+        if report.total > config.slow_open_threshold {
+            let slowest = report.segments.iter().max_by_key(|(_, elapsed)| *elapsed);
+            warn!(
+                "kv_store open took {:?}, the slowest file:{:?}, \
+                consider compacting or enabling the index snapshot",
+                report.total, slowest
+            );
         }
+        Ok((KvStore::new(log_files, &config), report))
     }
 
-    /// set just the mutable
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        debug!("kv_store set, key:{}, value:{}", key, value);
+    /// pick up the files changed by another process (e.g. a primary
+    /// writing to the dir followed by this store), the loaded immutables
+    /// still on disk are kept, the others are opened again
+    pub fn reload(&mut self) -> Result<()> {
+        let compacting = self.log_files.read_or_recover().compacting.clone();
+        let _compacting = compacting.lock_or_recover();
 
-        // finish set basic logic
-        let (mut_len, mut_path) = {
-            let log_files_inner = self.log_files.read().unwrap();
+        let mut log_files_inner = self.log_files.write_or_recover();
+        let dir_path = log_files_inner.dir_path.clone();
+        info!("kv_store reload from path:{}", dir_path.display());
+        let mut id_path_pairs =
+            get_file_paths(dir_path.as_path()).context(OpenSnafu { path: dir_path })?;
+        let (last_id, last_path) = match id_path_pairs.pop() {
+            Some(pair) => pair,
+            None => return Ok(()),
+        };
 
-            let mut inner = log_files_inner.mutable.write().unwrap();
-            inner.set(key.clone(), value.clone()).context(SetSnafu {
-                key: key.clone(),
-                value: value.clone(),
-            })?;
-            (inner.len().context(SetSnafu { key, value })?, inner.path())
+        let mode = log_files_inner.mutable.read_or_recover().mode();
+        let options = log_files_inner.options.clone();
+        let create_log_file = |file_path: &Path| {
+            LogFileBuilder::build(file_path, mode, &options).context(OpenSnafu { path: file_path })
         };
 
-        // check file's size, if too big, compact it
-        if mut_len > 1024 * 1024 {
-            let compactor = CompactorBuilder::build(self.log_files.clone(), CompactorMode::Simple);
-            compactor
-                .compact()
-                .context(CompactSnafu { path: mut_path })?;
+        enum Reloaded {
+            Kept(usize),
+            Opened(Box<RwLock<dyn LogFile>>),
+        }
+
+        // open all the files before touching the store, so a failed
+        // reload leaves it as it was
+        let mutable = create_log_file(Path::new(&last_path))?;
+        let loaded: HashMap<_, _> = log_files_inner
+            .immutables
+            .iter()
+            .enumerate()
+            .filter_map(|(i, immut)| {
+                let immut = immut.read_or_recover();
+                immut.is_current().then(|| (immut.path(), i))
+            })
+            .collect();
+        let mut reloaded = Vec::with_capacity(id_path_pairs.len());
+        for (_, imut_path) in id_path_pairs {
+            let imut_path = PathBuf::from(imut_path);
+            match loaded.get(&imut_path) {
+                Some(i) => {
+                    debug!("kv_store reload, keep {}", imut_path.display());
+                    reloaded.push(Reloaded::Kept(*i));
+                }
+                None => {
+                    info!("kv_store reload, load {}", imut_path.display());
+                    let immutable = create_log_file(imut_path.as_path())?;
+                    immutable
+                        .write_or_recover()
+                        .seal()
+                        .context(OpenSnafu { path: imut_path })?;
+                    reloaded.push(Reloaded::Opened(immutable));
+                }
+            }
         }
 
+        // each loaded file is kept once at most, as the paths differ
+        let mut old: Vec<_> = log_files_inner.immutables.drain(..).map(Some).collect();
+        log_files_inner.immutables = reloaded
+            .into_iter()
+            .filter_map(|reloaded| match reloaded {
+                Reloaded::Kept(i) => old[i].take(),
+                Reloaded::Opened(immutable) => Some(immutable),
+            })
+            .collect();
+        log_files_inner.mutable = mutable;
+        log_files_inner.next_id = last_id + 1;
+        log_files_inner.continue_seq();
+        log_files_inner.rebuild_live_keys();
         Ok(())
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        debug!("kv_store rm, key:{}", key);
-        let log_files_inner = self.log_files.read().unwrap();
-
-        let mut inner = log_files_inner.mutable.write().unwrap();
-        inner.remove(key.clone()).context(RmSnafu { key })
+    /// only read locks are taken, each file visited is locked once
+    /// the key is only borrowed, e.g. `store.get("key1")`
+    pub fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = self.key_normalizer.normalize(key.as_ref());
+        enter_span!("get", key = %key);
+        debug!("kv_store get, key:{}", key);
+        let log_files_inner = self.log_files.read_or_recover();
+        get_in(&log_files_inner, &key)
     }
-}
-
-fn contains_key(log_file: &RwLock<dyn LogFile>, key: &str) -> bool {
-    let inner = log_file.read().unwrap();
-    inner.contains_key(key)
-}
 
-/// file has a id, (e.g. data_1,data_2,...,data_n => 1,2,...,n)
-/// if not meet to the format, will panic! straightly
-fn check_and_get_file_id(f_name: String) -> usize {
-    let segs: Vec<_> = f_name.split('_').collect();
-    assert_eq!(segs.len(), 2);
-    assert_eq!(segs[0], "data");
-    segs[1].parse::<usize>().unwrap()
-}
+    /// same as `get`, but the value is shared with the cache of an
+    /// engine keeping one rather than copied on every read
+    pub fn get_shared(&self, key: impl AsRef<str>) -> Result<Option<Arc<str>>> {
+        let key = self.key_normalizer.normalize(key.as_ref());
+        debug!("kv_store get_shared, key:{}", key);
+        let log_files_inner = self.log_files.read_or_recover();
+        get_shared_in(&log_files_inner, &key)
+    }
 
-// get file paths and partition them
-fn get_file_paths(path: impl AsRef<Path>) -> Option<Vec<(usize, String)>> {
-    let path = path.as_ref();
-    if !path.exists() {
-        return None;
+    /// the value with the time it was last written, the unix epoch for
+    /// a record written before the timestamps or by the value engine
+    pub fn get_with_meta(&self, key: impl AsRef<str>) -> Result<Option<(String, SystemTime)>> {
+        let key = self.key_normalizer.normalize(key.as_ref());
+        debug!("kv_store get_with_meta, key:{}", key);
+        let log_files_inner = self.log_files.read_or_recover();
+        let value = get_with_ts_in(&log_files_inner, &key)?;
+        Ok(value.map(|(value, ts)| {
+            let since = Duration::from_millis(ts.unwrap_or(0));
+            (value, SystemTime::UNIX_EPOCH + since)
+        }))
     }
 
-    let mut id_path_pairs = Vec::new();
-    for entry in WalkDir::new(path)
-        .max_depth(1)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| !e.file_type().is_dir())
-    {
-        let id = check_and_get_file_id(String::from(entry.file_name().to_string_lossy()));
-        let f_path = String::from(entry.path().to_string_lossy());
-        // @todo check and get id
-        id_path_pairs.push((id, f_path))
+    /// every live pair in the order of the keys, the newest value of
+    /// each. only the keys are collected up front, the values are read
+    /// as iterated, and a key removed since is skipped
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let mut keys: Vec<_> = self
+            .log_files
+            .read_or_recover()
+            .collect_live_keys()
+            .into_iter()
+            .collect();
+        keys.sort();
+        keys.into_iter().filter_map(move |key| {
+            let log_files_inner = self.log_files.read_or_recover();
+            match get_in(&log_files_inner, &key) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
+
+    /// the n-th newest value of the key, 0 for the latest. a remove ends
+    /// the history of the key, and the older versions are only left by
+    /// the compactions keeping them, see `keep_versions`. it reads the
+    /// records of every file, for the audits rather than the hot reads
+    pub fn get_version(&self, key: impl AsRef<str>, n: usize) -> Result<Option<String>> {
+        let key = self.key_normalizer.normalize(key.as_ref());
+        let key = key.as_ref();
+        debug!("kv_store get_version, key:{}, n:{}", key, n);
+        let log_files_inner = self.log_files.read_or_recover();
+        let mut newer = n;
+        for log_file in
+            std::iter::once(&log_files_inner.mutable).chain(log_files_inner.immutables.iter().rev())
+        {
+            let mut versions = Vec::new();
+            for item in log_file.read_or_recover().iter() {
+                let item = item.context(GetSnafu { key })?;
+                if item.key == key {
+                    versions.push(item);
+                }
+            }
+            for item in versions.into_iter().rev() {
+                match item.cmd.as_str() {
+                    "set" if newer == 0 => return Ok(item.value),
+                    "set" => newer -= 1,
+                    "rm" => return Ok(None),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// checked without visiting the files, unless the index is lazy
+    pub fn contains_key(&self, key: &str) -> bool {
+        let key = self.key_normalizer.normalize(key);
+        let log_files_inner = self.log_files.read_or_recover();
+        match log_files_inner.is_live(&key) {
+            Some(live) => live,
+            None => get_in(&log_files_inner, &key).is_ok_and(|value| value.is_some()),
+        }
+    }
+
+    /// where the latest value of the key lies on disk, so a server can send
+    /// the bytes from the file without decoding them into a `String`. none
+    /// if the key is missing, or its value can't be read positionally, use
+    /// `get` then. a compaction may remove the file after, and the read of
+    /// the range fails then
+    pub fn value_range(&self, key: &str) -> Option<ValueRange> {
+        let key = self.key_normalizer.normalize(key);
+        let log_files_inner = self.log_files.read_or_recover();
+        if log_files_inner.is_live(&key) == Some(false) {
+            return None;
+        }
+        std::iter::once(&log_files_inner.mutable)
+            .chain(log_files_inner.immutables.iter().rev())
+            .map(|log_file| log_file.read_or_recover())
+            .find(|log_file| log_file.contains_key(&key))
+            .and_then(|log_file| log_file.value_range(&key))
+    }
+
+    /// get the keys in one pass under the same lock, so they are read
+    /// from one view of the store, a missing key gets a `None` in place
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        debug!("kv_store get_many, keys:{:?}", keys);
+        let log_files_inner = self.log_files.read_or_recover();
+        keys.into_iter()
+            .map(|key| get_in(&log_files_inner, &self.key_normalizer.normalize(&key)))
+            .collect()
+    }
+
+    /// set just the mutable
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_with_op_id(key, value, None)
+    }
+
+    /// set with a client-supplied op id, retrying it with
+    /// the same id after it has been applied is a no-op. the ids are
+    /// kept with the records, so the id of a record overwritten or
+    /// removed is forgotten once a compaction drops the record
+    pub fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> Result<()> {
+        let key = self.normalize_owned(key);
+        enter_span!("set", key = %key);
+        debug!(
+            "kv_store set, key:{}, value:{}, op_id:{:?}",
+            key, value, op_id
+        );
+
+        self.check_writable()?;
+
+        // finish set basic logic
+        let (mut_len, mut_path) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            if is_applied(&log_files_inner, op_id.as_deref()) {
+                info!(
+                    "kv_store set key:{} with applied op_id:{:?}, skip it",
+                    key, op_id
+                );
+                return Ok(());
+            }
+
+            let mut inner = log_files_inner.mutable.write_or_recover();
+            if self.skip_redundant_sets
+                && get_locked(&log_files_inner, &mut *inner, &key)?.as_ref() == Some(&value)
+            {
+                debug!("kv_store set key:{} with an unchanged value, skip it", key);
+                return Ok(());
+            }
+            inner
+                .set_with_op_id(key.clone(), value.clone(), op_id)
+                .context(SetSnafu {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .map_err(disk_full_or)?;
+            self.sync_if_due(&log_files_inner, &mut *inner)
+                .context(SetSnafu {
+                    key: key.clone(),
+                    value: value.clone(),
+                })?;
+            log_files_inner.mark_live(key.clone());
+            log_files_inner
+                .options
+                .write_amp
+                .record_logical(key.len() + value.len());
+            (inner.len().context(SetSnafu { key, value })?, inner.path())
+        };
+
+        self.compact_if_full(mut_len, mut_path)
+    }
+
+    /// check file's size, if too big, compact it
+    fn compact_if_full(&mut self, mut_len: u64, mut_path: PathBuf) -> Result<()> {
+        if !self.auto_compact || mut_len <= self.compaction_threshold {
+            return Ok(());
+        }
+        if let Some(idle_compactor) = self.idle_compactor.as_ref() {
+            idle_compactor.record_write();
+            return Ok(());
+        }
+
+        compact_mutable(
+            &self.log_files,
+            self.compactor_mode,
+            self.max_immutable_segments,
+            mut_path,
+        )
+    }
+
+    /// the value of the key, or the default if missing
+    pub fn get_with_default(&self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// the value of the key parsed as a number
+    pub fn get_u64(&self, key: String) -> Result<Option<u64>> {
+        match self.get(&key)? {
+            Some(value) => parse_u64(key, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// add the delta to the number of the key, which starts from 0 if
+    /// missing, and return the new number, concurrent incrs won't lose
+    /// any delta
+    pub fn incr(&mut self, key: String, delta: u64) -> Result<u64> {
+        debug!("kv_store incr, key:{}, delta:{}", key, delta);
+        self.read_modify_write(key.clone(), |current| {
+            let number = match current {
+                Some(value) => parse_u64(key.clone(), value)?,
+                None => 0,
+            };
+            let new_number =
+                number
+                    .checked_add(delta)
+                    .context(IncrOverflowSnafu { key, number, delta })?;
+            Ok((Some(new_number.to_string()), new_number))
+        })
+    }
+
+    /// set only if the key is missing in all the files,
+    /// return whether it is set
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        debug!("kv_store set_if_absent, key:{}", key);
+        self.read_modify_write(key, |current| match current {
+            Some(_) => Ok((None, false)),
+            None => Ok((Some(value), true)),
+        })
+    }
+
+    /// set the key and return its previous value, none if it was missing
+    pub fn replace(&mut self, key: String, value: String) -> Result<Option<String>> {
+        debug!("kv_store replace, key:{}", key);
+        self.read_modify_write(key, |current| Ok((Some(value), current)))
+    }
+
+    /// read the key and set the value decided from it, none for no write.
+    /// the mutable is write locked in between, so no other write interleaves
+    fn read_modify_write<T>(
+        &mut self,
+        key: String,
+        f: impl FnOnce(Option<String>) -> Result<(Option<String>, T)>,
+    ) -> Result<T> {
+        self.check_writable()?;
+        let key = self.normalize_owned(key);
+        let (ret, mut_len, mut_path) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            let mut inner = log_files_inner.mutable.write_or_recover();
+            let current = get_locked(&log_files_inner, &mut *inner, &key)?;
+
+            let (value, ret) = f(current)?;
+            let value = match value {
+                Some(value) => value,
+                None => return Ok(ret),
+            };
+            inner
+                .set_with_op_id(key.clone(), value.clone(), None)
+                .context(SetSnafu {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .map_err(disk_full_or)?;
+            self.sync_if_due(&log_files_inner, &mut *inner)
+                .context(SetSnafu {
+                    key: key.clone(),
+                    value: value.clone(),
+                })?;
+            log_files_inner.mark_live(key.clone());
+            log_files_inner
+                .options
+                .write_amp
+                .record_logical(key.len() + value.len());
+            (
+                ret,
+                inner.len().context(SetSnafu { key, value })?,
+                inner.path(),
+            )
+        };
+
+        self.compact_if_full(mut_len, mut_path)?;
+        Ok(ret)
+    }
+
+    /// cancel it to stop the running and later compactions of the store,
+    /// e.g. on shutdown, the files of a cancelled one are kept as before
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.log_files.read_or_recover().cancel.clone()
+    }
+
+    /// compact the mutable file manually, it is rotated into
+    /// an immutable one with only the latest records kept.
+    /// skipped if another compaction is running
+    pub fn compact(&mut self) -> Result<CompactionStats> {
+        self.compact_with_mode(CompactorMode::Simple)
+    }
+
+    /// merge all the files into one, dropping the removed keys
+    /// together with their tombstones
+    pub fn full_compact(&mut self) -> Result<CompactionStats> {
+        self.compact_with_mode(CompactorMode::Full)
+    }
+
+    fn compact_with_mode(&mut self, mode: CompactorMode) -> Result<CompactionStats> {
+        self.check_writable()?;
+        let (before_bytes, before_segments, mut_len, mut_path) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            let (bytes, segments) = disk_usage(&log_files_inner)?;
+            let inner = log_files_inner.mutable.read_or_recover();
+            (
+                bytes,
+                segments,
+                inner.len().context(SizeSnafu)?,
+                inner.path(),
+            )
+        };
+        enter_span!("compact", mode = ?mode, segment = %mut_path.display());
+        info!(
+            "kv_store compact, before bytes:{}, segments:{}",
+            before_bytes, before_segments
+        );
+
+        // nothing to compact in an empty mutable, unless merging all
+        if mut_len > 0 || matches!(mode, CompactorMode::Full) {
+            let compactor = CompactorBuilder::build(self.log_files.clone(), mode);
+            compactor
+                .compact()
+                .map_err(|e| compact_error(e, mut_path))?;
+        }
+        if matches!(mode, CompactorMode::Simple) {
+            merge_if_too_many(&self.log_files, self.max_immutable_segments)?;
+        }
+
+        let (after_bytes, after_segments) = disk_usage(&self.log_files.read_or_recover())?;
+        info!(
+            "kv_store compact, after bytes:{}, segments:{}",
+            after_bytes, after_segments
+        );
+
+        Ok(CompactionStats {
+            before_bytes,
+            after_bytes,
+            before_segments,
+            after_segments,
+        })
+    }
+
+    /// the share of garbage of each immutable, oldest first
+    pub fn garbage_ratios(&self) -> Result<Vec<(PathBuf, f64)>> {
+        self.log_files.read_or_recover().garbage_ratios()
+    }
+
+    /// how many bytes a full compaction would free, estimated from the
+    /// indexes, to decide whether it's worth running
+    pub fn estimate_disk_usage(&self) -> Result<DiskUsage> {
+        self.log_files.read_or_recover().estimate_disk_usage()
+    }
+
+    /// merge the immutables holding the most garbage, up to
+    /// `multi_compact_segments` of them, reclaiming the most space
+    /// for the bytes rewritten. the mutable and the others are kept
+    pub fn multi_compact(&mut self) -> Result<CompactionStats> {
+        self.check_writable()?;
+        let (before_bytes, before_segments, mut ratios) = {
+            let log_files_inner = self.log_files.read_or_recover();
+            let (bytes, segments) = disk_usage(&log_files_inner)?;
+            (bytes, segments, log_files_inner.garbage_ratios()?)
+        };
+        enter_span!("compact", mode = "multi");
+        info!(
+            "kv_store multi compact, before bytes:{}, segments:{}",
+            before_bytes, before_segments
+        );
+
+        // the worst first, a file without garbage isn't worth it
+        ratios.retain(|(_, ratio)| *ratio > 0.0);
+        ratios.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ratios.truncate(self.multi_compact_segments);
+        if let Some((worst_path, _)) = ratios.first() {
+            info!("kv_store multi compact, merge {:?}", ratios);
+            let worst_path = worst_path.clone();
+            let paths = ratios.into_iter().map(|(path, _)| path).collect();
+            MultiCompactor::new(self.log_files.clone(), paths)
+                .compact()
+                .map_err(|e| compact_error(e, worst_path))?;
+        }
+
+        let (after_bytes, after_segments) = disk_usage(&self.log_files.read_or_recover())?;
+        info!(
+            "kv_store multi compact, after bytes:{}, segments:{}",
+            after_bytes, after_segments
+        );
+
+        Ok(CompactionStats {
+            before_bytes,
+            after_bytes,
+            before_segments,
+            after_segments,
+        })
+    }
+
+    /// sync the written records to the disk, and return the highest
+    /// sequence number among them, all the writes up to it are durable
+    pub fn flush_and_checkpoint(&self) -> Result<u64> {
+        // taken before flushing, the writes after it may not be synced
+        let last_seq = self.log_files.read_or_recover().last_seq();
+        flusher::flush(&self.log_files, false).context(FlushSnafu)?;
+        debug!("kv_store checkpoint at seq:{}", last_seq);
+        Ok(last_seq)
+    }
+
+    /// count a write to the mutable, which is locked by the caller,
+    /// and sync it once enough writes are not synced
+    fn sync_if_due(
+        &self,
+        log_files: &LogFiles,
+        mutable: &mut dyn LogFile,
+    ) -> std::result::Result<(), LogFileError> {
+        let every = match self.sync_every_writes {
+            Some(every) => every,
+            None => return Ok(()),
+        };
+        let unsynced = log_files.unsynced_writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if unsynced >= every {
+            mutable.flush()?;
+            log_files.unsynced_writes.store(0, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// the canonical key, the given one is kept if already canonical
+    fn normalize_owned(&self, key: String) -> String {
+        match self.key_normalizer.normalize(&key) {
+            Cow::Borrowed(_) => key,
+            Cow::Owned(normalized) => normalized,
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return ReadOnlySnafu.fail();
+        }
+        Ok(())
+    }
+
+    /// release the spare capacity of the indexes and the live keys,
+    /// which stays after the keys are removed or compacted away
+    pub fn shrink_to_fit(&self) {
+        let log_files_inner = self.log_files.read_or_recover();
+        for log_file in log_files_inner
+            .immutables
+            .iter()
+            .chain(std::iter::once(&log_files_inner.mutable))
+        {
+            log_file.write_or_recover().shrink_to_fit();
+        }
+        if let Some(live_keys) = log_files_inner.live_keys.as_ref() {
+            live_keys.write_or_recover().shrink_to_fit();
+        }
+    }
+
+    /// whether any handle other than this one is alive,
+    /// the background flusher holds the files as well
+    pub(crate) fn is_shared(&self) -> bool {
+        let background = self._flusher.is_some() as usize + self.idle_compactor.is_some() as usize;
+        Arc::strong_count(&self.log_files) > 1 + background
+    }
+
+    /// sync the files and persist their indexes before the store
+    /// is dropped, so opening it again only replays a little
+    pub(crate) fn persist(&self) -> Result<()> {
+        flusher::flush(&self.log_files, true).context(FlushSnafu)
+    }
+
+    /// stop the background threads, then sync the files and snapshot
+    /// their indexes if enabled. unlike dropping the store, which only
+    /// logs them, the errors are returned
+    pub fn close(self) -> Result<()> {
+        let KvStore {
+            log_files,
+            _flusher,
+            idle_compactor,
+            snapshot_index,
+            ..
+        } = self;
+        drop(idle_compactor);
+        drop(_flusher);
+        flusher::flush(&log_files, snapshot_index).context(FlushSnafu)
+    }
+
+    pub fn stats(&self) -> KvStoreStats {
+        let log_files_inner = self.log_files.read_or_recover();
+        let mut loaded_segments = 0;
+        let segments: Vec<_> = log_files_inner
+            .immutables
+            .iter()
+            .chain(std::iter::once(&log_files_inner.mutable))
+            .map(|log_file| {
+                let inner = log_file.read_or_recover();
+                if inner.is_loaded() {
+                    loaded_segments += 1;
+                }
+                (inner.path(), inner.stats())
+            })
+            .collect();
+
+        let mut total = LogFileStats::default();
+        for (_, stats) in segments.iter() {
+            total.add(stats);
+        }
+        let total_size = log_files_inner.total_size().unwrap_or_else(|e| {
+            warn!("get total size of the store failed: {}", e);
+            0
+        });
+        KvStoreStats {
+            total,
+            segments,
+            write_amp: log_files_inner.options.write_amp.get(),
+            loaded_segments,
+            total_size,
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_with_op_id(key, None)
+    }
+
+    /// remove with a client-supplied op id, retrying it with
+    /// the same id after it has been applied is a no-op
+    pub fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
+        let key = self.normalize_owned(key);
+        debug!("kv_store rm, key:{}, op_id:{:?}", key, op_id);
+        self.check_writable()?;
+        let log_files_inner = self.log_files.read_or_recover();
+        if is_applied(&log_files_inner, op_id.as_deref()) {
+            info!(
+                "kv_store rm key:{} with applied op_id:{:?}, skip it",
+                key, op_id
+            );
+            return Ok(());
+        }
+
+        // a miss is reported as `KeyNotFound`, while `get` returns `None`
+        let mut inner = log_files_inner.mutable.write_or_recover();
+        let live = match log_files_inner.is_live(&key) {
+            Some(live) => live,
+            None => get_locked(&log_files_inner, &mut *inner, &key)?.is_some(),
+        };
+        if !live {
+            return KeyNotFoundSnafu { key }.fail();
+        }
+
+        // the key may be in an immutable, the tombstone hides it
+        inner
+            .tombstone_with_op_id(key.clone(), op_id)
+            .context(RmSnafu { key: key.clone() })
+            .map_err(disk_full_or)?;
+        self.sync_if_due(&log_files_inner, &mut *inner)
+            .context(RmSnafu { key: key.clone() })?;
+        log_files_inner.mark_removed(&key);
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// the records of a single segment with their offsets, read without
+    /// opening the store, e.g. to debug a broken one
+    pub fn read_segment(
+        path: impl AsRef<Path>,
+        codec: Arc<dyn RecordCodec>,
+    ) -> Result<Vec<(u64, LogItem)>> {
+        let path = path.as_ref();
+        log_file::read_records(path, codec).context(ReadSegmentSnafu { path })
+    }
+
+    /// recover a store from the segments whose ids can't be trusted, they
+    /// are replayed in the order of their ids if all named `data_<id>`,
+    /// otherwise of their modified times. the latest value of each key is
+    /// written into a single segment in `out_dir`, which must hold no store
+    pub fn rebuild_from(segments: &[PathBuf], out_dir: impl Into<PathBuf>) -> Result<KvStore> {
+        let out_dir: PathBuf = out_dir.into();
+        info!(
+            "kv_store rebuild from segments:{:?} into {}",
+            segments,
+            out_dir.display()
+        );
+        if out_dir.exists()
+            && !get_file_paths(&out_dir)
+                .context(OpenSnafu {
+                    path: out_dir.clone(),
+                })?
+                .is_empty()
+        {
+            return RebuildTargetSnafu { path: out_dir }.fail();
+        }
+
+        // replay from the oldest, the newest value wins
+        let options = LogFileOptions::default();
+        let mut live = HashMap::new();
+        for path in order_segments(segments)? {
+            for item in log_file::iter_log(&path, options.codec.clone()) {
+                let item = item.context(ReadSegmentSnafu { path: path.clone() })?;
+                match (item.cmd.as_str(), item.value) {
+                    ("set", Some(value)) => {
+                        let _ = live.insert(item.key, value);
+                    }
+                    ("rm", _) => {
+                        let _ = live.remove(&item.key);
+                    }
+                    (cmd, _) => warn!(
+                        "kv_store rebuild skip {} of key {} in {}",
+                        cmd,
+                        item.key,
+                        path.display()
+                    ),
+                }
+            }
+        }
+
+        fs::create_dir_all(&out_dir).context(CreateDirSnafu {
+            path: out_dir.clone(),
+        })?;
+        let out_path = out_dir.join("data_0");
+        {
+            create_segment(&out_path)?;
+            write_layout(&out_dir)?;
+            let log_file = LogFileBuilder::build(&out_path, "ptr", &options)
+                .context(OpenSnafu { path: &out_path })?;
+            let mut inner = log_file.write_or_recover();
+            info!("kv_store rebuild live keys:{}", live.len());
+            for (key, value) in live {
+                inner
+                    .set_with_op_id(key.clone(), value.clone(), None)
+                    .context(SetSnafu { key, value })?;
+            }
+            inner.flush().context(OpenSnafu { path: &out_path })?;
+        }
+
+        KvStore::open(out_dir)
+    }
+}
+
+/// by the ids if all the names carry a distinct one, or by the modified times
+fn order_segments(segments: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut ids: Vec<_> = segments
+        .iter()
+        .filter_map(|path| {
+            let id = parse_file_id(path.file_name()?.to_str()?)?;
+            Some((id, path.clone()))
+        })
+        .collect();
+    ids.sort_by_key(|(id, _)| *id);
+    ids.dedup_by_key(|(id, _)| *id);
+    if ids.len() == segments.len() {
+        return Ok(ids.into_iter().map(|(_, path)| path).collect());
+    }
+
+    warn!("kv_store rebuild, segments are misnamed, order them by modified times");
+    let mut mtimes = Vec::with_capacity(segments.len());
+    for path in segments {
+        let mtime = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .context(SegmentMetadataSnafu { path: path.clone() })?;
+        mtimes.push((mtime, path.clone()));
+    }
+    mtimes.sort_by_key(|(mtime, _)| *mtime);
+    Ok(mtimes.into_iter().map(|(_, path)| path).collect())
+}
+
+/// the file recording the codec of a store
+const CODEC_FILE: &str = "CODEC";
+
+/// the codec is recorded when the store is created, and checked when
+/// opened, so it won't be misread. a store without it is of json, and
+/// gets it recorded unless opened read-only
+fn check_codec(
+    dir_path: &Path,
+    codec: &dyn RecordCodec,
+    is_new: bool,
+    read_only: bool,
+) -> Result<()> {
+    let codec_path = dir_path.join(CODEC_FILE);
+    let recorded = match fs::read_to_string(&codec_path) {
+        Ok(recorded) => Some(recorded.trim().to_owned()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(RecordCodecSnafu { path: codec_path }),
+    };
+
+    let recorded = match recorded {
+        Some(recorded) => recorded,
+        None => {
+            let recorded = if is_new { codec.name() } else { "json" };
+            if recorded == codec.name() {
+                if !read_only {
+                    info!(
+                        "kv_store record codec:{} in {}",
+                        recorded,
+                        codec_path.display()
+                    );
+                    fs::write(&codec_path, recorded)
+                        .context(RecordCodecSnafu { path: codec_path })?;
+                }
+                return Ok(());
+            }
+            recorded.to_owned()
+        }
+    };
+
+    if recorded != codec.name() {
+        return CodecMismatchSnafu {
+            path: dir_path,
+            recorded,
+            codec: codec.name(),
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+/// create an empty segment by renaming a synced temp file, so a crash
+/// never leaves a half created one, the temp file isn't a segment
+fn create_segment(path: &Path) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    File::create(&tmp_path)
+        .and_then(|file| file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .context(CreateSegmentSnafu { path })
+}
+
+/// the id of the next segment, above all the ids in the dir
+fn check_next_id(next_id: Option<usize>, max_id: usize, path: &Path) -> Result<usize> {
+    match next_id {
+        Some(next_id) if next_id <= max_id => NextIdSnafu {
+            path,
+            next_id,
+            max_id,
+        }
+        .fail(),
+        Some(next_id) => {
+            info!("kv_store next id starts from {}", next_id);
+            Ok(next_id)
+        }
+        None => Ok(max_id + 1),
+    }
+}
+
+/// the file recording the on-disk layout version of a store
+const VERSION_FILE: &str = "VERSION";
+
+/// the layout written by this build
+const LAYOUT_VERSION: u32 = 1;
+
+/// the layout version is recorded when the store is created, and
+/// checked on every open, so a store of a newer layout is rejected
+/// rather than misread. a store without it is of version 1
+fn check_layout(dir_path: &Path) -> Result<()> {
+    let version_path = dir_path.join(VERSION_FILE);
+    let recorded = match fs::read_to_string(&version_path) {
+        Ok(recorded) => recorded.trim().to_owned(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(LayoutVersionSnafu { path: version_path }),
+    };
+
+    // an older layout is to be migrated here once the layout changes
+    match recorded.parse::<u32>() {
+        Ok(LAYOUT_VERSION) => Ok(()),
+        _ => UnsupportedLayoutSnafu {
+            path: dir_path,
+            recorded,
+            supported: LAYOUT_VERSION,
+        }
+        .fail(),
+    }
+}
+
+fn write_layout(dir_path: &Path) -> Result<()> {
+    let version_path = dir_path.join(VERSION_FILE);
+    info!(
+        "kv_store layout version:{} in {}",
+        LAYOUT_VERSION,
+        version_path.display()
+    );
+    fs::write(&version_path, LAYOUT_VERSION.to_string())
+        .context(LayoutVersionSnafu { path: version_path })
+}
+
+/// a write failed by a full disk is reported by itself, so the callers
+/// can react to it rather than taking the store as broken
+fn disk_full_or(e: Error) -> Error {
+    let source = match &e {
+        Error::Set { source, .. } | Error::Rm { source, .. } => source,
+        _ => return e,
+    };
+    match &**source {
+        LogFileError::DiskFull { path, .. } => Error::DiskFull {
+            location: location!(),
+            path: path.clone(),
+        },
+        _ => e,
+    }
+}
+
+/// compact the mutable if it is over the threshold, by the idle compactor
+fn compact_if_over(
+    log_files: &Arc<RwLock<LogFiles>>,
+    threshold: u64,
+    mode: CompactorMode,
+    max_immutable_segments: Option<usize>,
+) -> Result<()> {
+    let (mut_len, mut_path) = {
+        let log_files_inner = log_files.read_or_recover();
+        let inner = log_files_inner.mutable.read_or_recover();
+        (inner.len().context(SizeSnafu)?, inner.path())
+    };
+    if mut_len <= threshold {
+        return Ok(());
+    }
+    compact_mutable(log_files, mode, max_immutable_segments, mut_path)
+}
+
+fn compact_mutable(
+    log_files: &Arc<RwLock<LogFiles>>,
+    mode: CompactorMode,
+    max_immutable_segments: Option<usize>,
+    mut_path: PathBuf,
+) -> Result<()> {
+    enter_span!("compact", mode = ?mode, segment = %mut_path.display());
+    let compactor = CompactorBuilder::build(log_files.clone(), mode);
+    // a skipped one is left to the running compaction,
+    // a cancelled one doesn't fail the write
+    let compacted = match compactor.compact() {
+        Ok(compacted) => compacted,
+        Err(compactor::Error::Cancelled { .. }) => {
+            info!("kv_store compaction cancelled, skip");
+            false
+        }
+        Err(e) => return Err(compact_error(e, mut_path)),
+    };
+    if compacted {
+        merge_if_too_many(log_files, max_immutable_segments)?;
+    }
+    Ok(())
+}
+
+/// a full compaction leaves one immutable, so a limit below 1 is taken as 1
+fn merge_if_too_many(
+    log_files: &Arc<RwLock<LogFiles>>,
+    max_immutable_segments: Option<usize>,
+) -> Result<()> {
+    let max_segments = match max_immutable_segments {
+        Some(max_segments) => max_segments.max(1),
+        None => return Ok(()),
+    };
+    let (segments, mut_path) = {
+        let log_files_inner = log_files.read_or_recover();
+        let mut_path = log_files_inner.mutable.read_or_recover().path();
+        (log_files_inner.immutables.len(), mut_path)
+    };
+    if segments <= max_segments {
+        return Ok(());
+    }
+
+    info!(
+        "kv_store immutables:{} exceed {}, merge them",
+        segments, max_segments
+    );
+    enter_span!("compact", mode = ?CompactorMode::Full, segment = %mut_path.display());
+    let compactor = CompactorBuilder::build(log_files.clone(), CompactorMode::Full);
+    match compactor.compact() {
+        Ok(_) => Ok(()),
+        Err(compactor::Error::Cancelled { .. }) => {
+            info!("kv_store merge cancelled, skip");
+            Ok(())
+        }
+        Err(e) => Err(compact_error(e, mut_path)),
+    }
+}
+
+/// a cancelled compaction is told from a failed one
+fn compact_error(e: compactor::Error, path: PathBuf) -> Error {
+    match e {
+        compactor::Error::Cancelled { .. } => Error::CompactionCancelled {
+            location: location!(),
+        },
+        source => Error::Compact {
+            source: Box::new(source),
+            location: location!(),
+            path,
+        },
+    }
+}
+
+fn parse_u64(key: String, value: String) -> Result<u64> {
+    value
+        .parse::<u64>()
+        .context(NotANumberSnafu { key, value: &value })
+}
+
+/// a value with the unix millis it was written at
+type Stamped = (String, Option<u64>);
+
+/// lookup from the newest file, the first file knowing the key decides
+fn get_in(log_files: &LogFiles, key: &str) -> Result<Option<String>> {
+    get_with_ts_in(log_files, key).map(|value| value.map(|(value, _)| value))
+}
+
+/// same lookup as `get_in`, reading the value shared by the file
+fn get_shared_in(log_files: &LogFiles, key: &str) -> Result<Option<Arc<str>>> {
+    if log_files.is_live(key) == Some(false) {
+        return Ok(None);
+    }
+
+    let newest_first = std::iter::once(&log_files.mutable).chain(log_files.immutables.iter().rev());
+    for log_file in newest_first {
+        let log_file = log_file.read_or_recover();
+        if log_file.contains_key(key) {
+            return log_file.get_shared(key).context(GetSnafu { key });
+        }
+    }
+    Ok(None)
+}
+
+/// the value with the timestamp of its record
+fn get_with_ts_in(log_files: &LogFiles, key: &str) -> Result<Option<Stamped>> {
+    // a missing key needs no file visited
+    if log_files.is_live(key) == Some(false) {
+        debug!("get {} in kv_store, not found", key);
+        return Ok(None);
+    }
+
+    // check contain as this order: mut, imut.rev
+    if let Some(value) = get_from_file(&log_files.mutable, key, &log_files.options) {
+        return value;
+    }
+    get_in_immutables(log_files, key)
+}
+
+/// get with the mutable already write locked by the caller
+fn get_locked(
+    log_files: &LogFiles,
+    mutable: &mut dyn LogFile,
+    key: &str,
+) -> Result<Option<String>> {
+    let value = match get_from(mutable, key) {
+        Some(Err(_)) if log_files.options.rebuild_index_on_bad_read => {
+            rebuild_and_get(mutable, key, &log_files.options)
+        }
+        Some(value) => value,
+        None => get_in_immutables(log_files, key),
+    };
+    value.map(|value| value.map(|(value, _)| value))
+}
+
+fn get_in_immutables(log_files: &LogFiles, key: &str) -> Result<Option<Stamped>> {
+    for immut in log_files.immutables.iter().rev() {
+        if let Some(value) = get_from_file(immut, key, &log_files.options) {
+            return value;
+        }
+    }
+
+    debug!("get {} in kv_store, not found", key);
+    Ok(None)
+}
+
+/// same as `get_from`, but a failed read may be caused by a broken
+/// index, rebuild it and retry once if enabled
+fn get_from_file(
+    log_file: &RwLock<dyn LogFile>,
+    key: &str,
+    options: &LogFileOptions,
+) -> Option<Result<Option<Stamped>>> {
+    // the read lock is released before taking the write lock
+    let value = get_from(&*log_file.read_or_recover(), key);
+    match value {
+        Some(Err(_)) if options.rebuild_index_on_bad_read => Some(rebuild_and_get(
+            &mut *log_file.write_or_recover(),
+            key,
+            options,
+        )),
+        value => value,
+    }
+}
+
+fn rebuild_and_get(
+    log_file: &mut dyn LogFile,
+    key: &str,
+    options: &LogFileOptions,
+) -> Result<Option<Stamped>> {
+    warn!(
+        "get {} in {} failed, rebuild its index and retry",
+        key,
+        log_file.path().display()
+    );
+    log_file.rebuild_index(options).context(GetSnafu { key })?;
+    get_from(log_file, key).unwrap_or(Ok(None))
+}
+
+/// none if the file doesn't know the key
+fn get_from(log_file: &dyn LogFile, key: &str) -> Option<Result<Option<Stamped>>> {
+    if !log_file.contains_key(key) {
+        return None;
+    }
+
+    debug!(
+        "get {} in kv_store, found in {}",
+        key,
+        log_file.path().display()
+    );
+    Some(
+        log_file
+            .get_with_ts(key)
+            .context(GetSnafu { key })
+            .map_err(|e: Error| {
+                error!("get {} in kv_store, found but encounter err, e:{}", key, e);
+                e
+            }),
+    )
+}
+
+/// a segment opened and the time spent on it
+type Opened = (Box<RwLock<dyn LogFile>>, Duration);
+
+/// open a segment and the time spent on it, mostly on building its index
+fn open_segment(file_path: &Path, config: &KvStoreConfig) -> Result<Opened> {
+    let start = Instant::now();
+    let log_file = LogFileBuilder::build(file_path, config.engine.mode(), &config.log_file)
+        .context(OpenSnafu { path: file_path })?;
+    Ok((log_file, start.elapsed()))
+}
+
+/// open and seal the immutables in order, split into a contiguous chunk
+/// per thread as each is independent. the error of the first failed
+/// one in order is returned
+fn open_immutables(paths: &[PathBuf], config: &KvStoreConfig) -> Result<Vec<Opened>> {
+    let open_chunk = |paths: &[PathBuf]| -> Result<Vec<_>> {
+        paths
+            .iter()
+            .map(|path| {
+                let (immutable, elapsed) = open_segment(path, config)?;
+                immutable
+                    .write_or_recover()
+                    .seal()
+                    .context(OpenSnafu { path })?;
+                Ok((immutable, elapsed))
+            })
+            .collect()
+    };
+
+    let threads = config.open_parallelism.max(1).min(paths.len());
+    if threads <= 1 {
+        return open_chunk(paths);
+    }
+    let chunk_len = paths.len().div_ceil(threads);
+    let chunks: Vec<_> = std::thread::scope(|s| {
+        let handles: Vec<_> = paths
+            .chunks(chunk_len)
+            .map(|chunk| s.spawn(move || open_chunk(chunk)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    });
+
+    let mut immutables = Vec::with_capacity(paths.len());
+    for chunk in chunks {
+        immutables.extend(chunk?);
+    }
+    Ok(immutables)
+}
+
+/// total bytes and count of the segments
+fn disk_usage(log_files: &LogFiles) -> Result<(u64, usize)> {
+    Ok((log_files.total_size()?, log_files.immutables.len() + 1))
+}
+
+/// the op may be applied to any file, check them all
+fn is_applied(log_files: &LogFiles, op_id: Option<&str>) -> bool {
+    let op_id = match op_id {
+        Some(op_id) => op_id,
+        None => return false,
+    };
+
+    log_files.mutable.read_or_recover().contains_op_id(op_id)
+        || log_files
+            .immutables
+            .iter()
+            .any(|immut| immut.read_or_recover().contains_op_id(op_id))
+}
+
+/// file has a id, (e.g. data_1,data_2,...,data_n => 1,2,...,n),
+/// none if the name is not a segment's. the names only come from the
+/// dir listing, a key never makes up a path. a compressed segment is
+/// named with `.gz` (e.g. data_1.gz => 1)
+pub(crate) fn parse_file_id(f_name: &str) -> Option<usize> {
+    let id = f_name.strip_prefix("data_")?;
+    let id = id.strip_suffix(".gz").unwrap_or(id);
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    id.parse::<usize>().ok()
+}
+
+// get file paths and partition them,
+// a missing or unreadable dir is reported as an error
+fn get_file_paths(path: impl AsRef<Path>) -> Result<Vec<(usize, String)>, LogFileError> {
+    let path = path.as_ref();
+
+    let mut id_path_pairs = Vec::new();
+    for entry in WalkDir::new(path).min_depth(1).max_depth(1) {
+        let entry = entry.map_err(|e| LogFileError::LogFileListDir {
+            source: Box::new(e.into()),
+            location: location!(),
+        })?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        // only segments are loaded, skip the others such as
+        // hidden files, value logs and compacting files
+        let f_name = entry.file_name().to_string_lossy();
+        let id = match parse_file_id(&f_name) {
+            Some(id) => id,
+            None => {
+                debug!("skip non-segment file:{}", entry.path().display());
+                continue;
+            }
+        };
+        let f_path = String::from(entry.path().to_string_lossy());
+        id_path_pairs.push((id, f_path))
     }
 
     // should sort now, beacuse the last will be used as mutable
     // @todo should ensure 1,2,3,4...n
-    id_path_pairs.sort_by(|a, b| a.0.cmp(&b.0));
+    id_path_pairs.sort_by_key(|pair| pair.0);
 
     info!("get paths id_path_pairs:{:?}", id_path_pairs);
 
-    Some(id_path_pairs)
+    Ok(id_path_pairs)
 }
 
 #[cfg(test)]
@@ -264,18 +2197,32 @@ mod tests {
     // use assert_cmd::assert;
     use tempfile::TempDir;
     // use crate::KvStore;
-    use super::get_file_paths;
+    use super::{get_file_paths, Error, KvStore, KvStoreConfig};
+    use crate::clock::MockClock;
+    use std::{
+        fs,
+        io::Write,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
 
     #[test]
     fn test_open() {
-        // invalid file in dir, empty ret
-        assert!(get_file_paths("rrrrrrrrrrr").is_none());
+        // invalid file in dir, err ret
+        assert!(get_file_paths("rrrrrrrrrrr").is_err());
+
+        // a dir failing to be listed is reported as an open error
+        let removed_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(removed_dir.path()).unwrap();
+        fs::remove_dir_all(removed_dir.path()).unwrap();
+        assert!(matches!(store.reload(), Err(Error::Open { .. })));
+        drop(store);
 
         // valid
         let temp_dir = TempDir::new().unwrap();
         // empty dir, empty ret
         let res = get_file_paths(temp_dir.path());
-        assert!(res.is_some());
+        assert!(res.is_ok());
         let res = res.unwrap();
         assert!(res.is_empty());
 
@@ -299,10 +2246,206 @@ mod tests {
             .unwrap();
         // let _ = KvStore::open("./test_dir");
         let res = get_file_paths(temp_dir.path());
-        assert!(res.is_some());
+        assert!(res.is_ok());
         let res = res.unwrap();
         assert!(format!("{:?}", res[0]).contains("data_0"));
         assert!(format!("{:?}", res[1]).contains("data_1"));
         assert!(format!("{:?}", res[2]).contains("data_2"));
     }
+
+    #[test]
+    fn get_takes_read_locks_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.compact().unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+        // a get needing any write lock would hang while the guards are held
+        let log_files_inner = store.log_files.read().unwrap();
+        let _mut_guard = log_files_inner.mutable.read().unwrap();
+        let _immut_guard = log_files_inner.immutables[0].read().unwrap();
+        std::thread::scope(|s| {
+            let handle = s.spawn(|| (store.get("key1").unwrap(), store.get("key2").unwrap()));
+            assert_eq!(
+                handle.join().unwrap(),
+                (Some("value1".to_owned()), Some("value2".to_owned()))
+            );
+        });
+    }
+
+    #[test]
+    fn skip_non_segment_files() {
+        let temp_dir = TempDir::new().unwrap();
+        for name in [
+            "data_0",
+            "data_2",
+            "data_10",
+            ".data_3",
+            ".hidden",
+            "data_2.vlog",
+            "data_1.compact",
+            "data_12.gz",
+            "data_4.gz.tmp",
+            "data_",
+            "data_x",
+            "LOCK",
+        ] {
+            std::fs::write(temp_dir.path().join(name), "").unwrap();
+        }
+        std::fs::create_dir(temp_dir.path().join("data_5")).unwrap();
+
+        let ids: Vec<_> = get_file_paths(temp_dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.0)
+            .collect();
+        assert_eq!(ids, vec![0, 2, 10, 12]);
+    }
+
+    #[test]
+    fn inject_clock() {
+        let temp_dir = TempDir::new().unwrap();
+        let clock = Arc::new(MockClock::default());
+        let config = KvStoreConfig {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+
+        // the log files stamp the records by the time the test sets
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            store.log_files.read().unwrap().options.clock.now(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn reload_keeps_loaded_immutables() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.compact().unwrap();
+        let immut_addr = |store: &KvStore| {
+            let log_files_inner = store.log_files.read().unwrap();
+            &*log_files_inner.immutables[0] as *const _ as *const u8 as usize
+        };
+        let before = immut_addr(&store);
+
+        store.reload().unwrap();
+        assert_eq!(immut_addr(&store), before);
+        assert_eq!(store.get("key1").unwrap(), Some("value1".to_owned()));
+    }
+
+    /// the name and the field names of a span
+    #[cfg(feature = "tracing")]
+    type CapturedSpan = (String, Vec<String>);
+
+    /// records the spans created
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct CaptureSpans(Arc<std::sync::Mutex<Vec<CapturedSpan>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for CaptureSpans {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let metadata = span.metadata();
+            let fields = metadata.fields().iter().map(|f| f.name().to_owned());
+            let mut spans = self.0.lock().unwrap();
+            spans.push((metadata.name().to_owned(), fields.collect()));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_spans() {
+        let temp_dir = TempDir::new().unwrap();
+        let capture = CaptureSpans::default();
+        tracing::subscriber::with_default(capture.clone(), || {
+            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+            assert_eq!(store.get("key1").unwrap(), Some("value1".to_owned()));
+            store.compact().unwrap();
+        });
+
+        let spans = capture.0.lock().unwrap();
+        let fields_of = |name: &str| {
+            spans
+                .iter()
+                .find(|(span, _)| span == name)
+                .map(|(_, fields)| fields.clone())
+        };
+        assert_eq!(fields_of("open"), Some(vec!["path".to_owned()]));
+        assert_eq!(fields_of("set"), Some(vec!["key".to_owned()]));
+        assert_eq!(fields_of("get"), Some(vec!["key".to_owned()]));
+        assert_eq!(
+            fields_of("compact"),
+            Some(vec!["mode".to_owned(), "segment".to_owned()])
+        );
+    }
+
+    #[test]
+    fn reload_failure_keeps_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.compact().unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+        // another process rotates to data_2, leaving a broken data_1
+        let mut mutable = fs::OpenOptions::new()
+            .append(true)
+            .open(temp_dir.path().join("data_1"))
+            .unwrap();
+        mutable.write_all(b"not a record\n").unwrap();
+        fs::File::create(temp_dir.path().join("data_2")).unwrap();
+
+        assert!(store.reload().is_err());
+        assert_eq!(store.get("key1").unwrap(), Some("value1".to_owned()));
+        assert_eq!(store.get("key2").unwrap(), Some("value2".to_owned()));
+    }
+
+    #[test]
+    fn recover_poisoned_locks() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+        // an operation panics while holding the locks
+        let log_files = store.log_files.clone();
+        let res = std::thread::spawn(move || {
+            let log_files_inner = log_files.write().unwrap();
+            let _mut_guard = log_files_inner.mutable.write().unwrap();
+            let _compacting = log_files_inner.compacting.lock().unwrap();
+            panic!("panic while holding the locks");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(store.log_files.is_poisoned());
+
+        // the later ones still work
+        assert_eq!(store.get("key1").unwrap(), Some("value1".to_owned()));
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        store.remove("key1".to_owned()).unwrap();
+        store.compact().unwrap();
+        store.full_compact().unwrap();
+        assert_eq!(store.get("key1").unwrap(), None);
+        assert_eq!(store.get("key2").unwrap(), Some("value2".to_owned()));
+    }
 }
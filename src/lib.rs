@@ -1,4 +1,30 @@
+/// enter a span till the end of the block if built with the `tracing`
+/// feature, nothing is left of it otherwise
+macro_rules! enter_span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name $(, $($fields)*)?).entered();
+    };
+}
+
+mod clock;
 mod compactor;
+mod flusher;
+mod idle_compactor;
+mod key_normalizer;
 mod kv_store;
+mod lock;
 mod log_file;
-pub use kv_store::{KvStore, Result};
+mod registry;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use compactor::{CancellationToken, CompactionStats, CompactorMode, MergeOrder};
+pub use key_normalizer::{IdentityKeys, KeyNormalizer};
+pub use kv_store::{
+    DiskUsage, Engine, Error, KvStore, KvStoreBuilder, KvStoreConfig, KvStoreStats, OpenMode,
+    OpenReport, Result, SyncPolicy,
+};
+pub use log_file::{
+    CodecError, LogEncoder, LogFileOptions, LogFileStats, LogItem, RecordCodec, UnknownCmdPolicy,
+    ValuePtr, ValueRange, WriteAmpStats,
+};
+pub use registry::StoreRegistry;
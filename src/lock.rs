@@ -0,0 +1,66 @@
+use log::warn;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// a lock poisoned by a panicked operation is taken over with a warning,
+/// rather than failing all the later operations of the store
+pub(crate) trait RwLockExt<T: ?Sized> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T: ?Sized> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(recover)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(recover)
+    }
+}
+
+pub(crate) trait MutexExt<T: ?Sized> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T: ?Sized> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(recover)
+    }
+}
+
+pub(crate) fn recover<G>(e: PoisonError<G>) -> G {
+    warn!("recover a lock poisoned by a panicked operation");
+    e.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MutexExt, RwLockExt};
+    use std::sync::{Arc, Mutex, RwLock};
+
+    #[test]
+    fn recover_poisoned() {
+        let lock = Arc::new(RwLock::new(1));
+        let mutex = Arc::new(Mutex::new(1));
+        {
+            let lock = lock.clone();
+            let mutex = mutex.clone();
+            let res = std::thread::spawn(move || {
+                let _guard = mutex.lock().unwrap();
+                let mut guard = lock.write().unwrap();
+                *guard = 2;
+                panic!("panic while holding the locks");
+            })
+            .join();
+            assert!(res.is_err());
+        }
+        assert!(lock.is_poisoned());
+        assert!(mutex.is_poisoned());
+
+        assert_eq!(*lock.read_or_recover(), 2);
+        *lock.write_or_recover() = 3;
+        assert_eq!(*lock.read_or_recover(), 3);
+        assert_eq!(*mutex.lock_or_recover(), 1);
+    }
+}
@@ -0,0 +1,847 @@
+use super::Error as LogFileError;
+use super::{
+    log_item::{self, LogItem, RecordCodec, ValuePtr},
+    op_ids::RecentOpIds,
+    stats::StatsCounter,
+    LogFile, LogFileOptions, LogFileStats, UnknownCmdPolicy, ValueRange,
+};
+use crate::clock::Clock;
+use crate::log_file::log_iter::LogIter;
+use log::{debug, info, warn};
+use snafu::{location, Location, ResultExt, Snafu};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{} new log_file with invalid path {}", location, path.display()))]
+    InvalidPath { location: Location, path: PathBuf },
+
+    #[snafu(display("{} open log_file {} failed: {}", location, path.display(), source))]
+    OpenFile {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} read log_file {} failed: {}", location, path.display(), source))]
+    ReadFile {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} write log_file {} failed: {}", location, path.display(), source))]
+    WriteFile {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} encoder failed: {}", location, source))]
+    LogEncoder {
+        source: super::log_item::Error,
+        location: Location,
+    },
+
+    #[snafu(display("{} read value of key {} failed: {}", location, key, source))]
+    ReadValue {
+        source: std::io::Error,
+        location: Location,
+        key: String,
+    },
+
+    #[snafu(display("{} iter log failed: {}", location, source))]
+    IterLog {
+        source: super::log_iter::Error,
+        location: Location,
+    },
+
+    #[snafu(display("{} remove non-exist key {}", location, key))]
+    RemoveNotExistKey { location: Location, key: String },
+
+    #[snafu(display("{} unknown log {:?}", location, item))]
+    UnknownCmd {
+        location: Location,
+        item: Box<LogItem>,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// key-value separated log file (WiscKey style), the index log
+/// at `path` holds keys and value pointers, and the value log at
+/// `path.vlog` holds the raw values, so compacting the index log
+/// never needs to copy the values
+pub struct KvSepLogFile {
+    inner: KvSepLogFileInner,
+}
+
+impl KvSepLogFile {
+    pub fn new(path: &Path, options: &LogFileOptions) -> Result<Self> {
+        Ok(KvSepLogFile {
+            inner: KvSepLogFileInner::new(path, options)?,
+        })
+    }
+}
+
+impl LogFile for KvSepLogFile {
+    fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> super::Result<()> {
+        self.inner
+            .set_with_op_id(key, value, op_id)
+            .map_err(|e| LogFileError::LogFileSet {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn get(&self, key: &str) -> super::Result<Option<String>> {
+        self.inner.get(key).map_err(|e| LogFileError::LogFileGet {
+            source: Box::new(e.into()),
+            location: location!(),
+        })
+    }
+
+    fn get_with_ts(&self, key: &str) -> super::Result<Option<(String, Option<u64>)>> {
+        self.inner
+            .get_with_ts(key)
+            .map_err(|e| LogFileError::LogFileGet {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn copy_set(
+        &mut self,
+        key: String,
+        value: String,
+        ts: Option<u64>,
+        op_id: Option<String>,
+    ) -> super::Result<()> {
+        self.inner
+            .set_with_ts(key, value, op_id, ts)
+            .map_err(|e| LogFileError::LogFileSet {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> super::Result<()> {
+        self.inner
+            .remove_with_op_id(key, op_id)
+            .map_err(|e| LogFileError::LogFileRm {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn tombstone_with_op_id(&mut self, key: String, op_id: Option<String>) -> super::Result<()> {
+        self.inner
+            .tombstone_with_op_id(key, op_id)
+            .map_err(|e| LogFileError::LogFileRm {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn scan(&mut self) -> super::Result<Vec<String>> {
+        self.inner.scan().map_err(|e| LogFileError::LogFileScan {
+            source: Box::new(e.into()),
+            location: location!(),
+        })
+    }
+
+    fn len(&self) -> super::Result<u64> {
+        self.inner.len().map_err(|e| LogFileError::LogFileLen {
+            source: Box::new(e.into()),
+            location: location!(),
+        })
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.inner.index.contains_key(key)
+    }
+
+    fn key_states(&self) -> Vec<(String, bool)> {
+        self.inner
+            .index
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.is_live()))
+            .collect()
+    }
+
+    fn contains_op_id(&self, op_id: &str) -> bool {
+        self.inner.op_ids.contains(op_id)
+    }
+
+    fn path(&self) -> PathBuf {
+        self.inner.path.clone()
+    }
+
+    fn mode(&self) -> &'static str {
+        "kv_sep"
+    }
+
+    fn file_paths(&self) -> Vec<PathBuf> {
+        vec![self.inner.path.clone(), self.inner.value_path.clone()]
+    }
+
+    fn flush(&mut self) -> super::Result<()> {
+        // values first, an index record never points to a lost value
+        self.inner
+            .value_file
+            .sync_data()
+            .and_then(|_| self.inner.index_file.sync_data())
+            .map_err(|e| LogFileError::LogFileFlush {
+                source: Box::new(e.into()),
+                location: location!(),
+            })?;
+        self.inner.stats.record_sync();
+        Ok(())
+    }
+
+    fn value_range(&self, key: &str) -> Option<ValueRange> {
+        match self.inner.index.get(key)? {
+            IndexEntry::Exist(value_ptr, _) => Some(ValueRange {
+                path: self.inner.value_path.clone(),
+                offset: value_ptr.offset,
+                len: value_ptr.len,
+                encoded: false,
+            }),
+            IndexEntry::Inline(..) | IndexEntry::Removed => None,
+        }
+    }
+
+    // the index record is encoded again to get its size, without the
+    // seq and op_id it's a bit smaller than the written one
+    fn live_bytes(&self, key: &str) -> u64 {
+        let (item, value_len) = match self.inner.index.get(key) {
+            Some(IndexEntry::Exist(value_ptr, ts)) => (
+                LogItem::new("set".to_owned(), key.to_owned(), None)
+                    .with_value_ptr(*value_ptr)
+                    .with_ts(*ts),
+                value_ptr.len,
+            ),
+            Some(IndexEntry::Inline(value, ts)) => (
+                LogItem::new("set".to_owned(), key.to_owned(), Some(value.clone())).with_ts(*ts),
+                0,
+            ),
+            Some(IndexEntry::Removed) | None => return 0,
+        };
+        let index_len = log_item::encode_record(&*self.inner.codec, &item)
+            .map_or(0, |record| record.len() as u64 + 1);
+        index_len + value_len
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.inner.index.shrink_to_fit();
+    }
+
+    fn is_current(&self) -> bool {
+        super::is_same_file(&self.inner.index_file, &self.inner.path)
+            && super::is_same_file(&self.inner.value_file, &self.inner.value_path)
+    }
+
+    /// the values are read back from the value log
+    fn stats(&self) -> LogFileStats {
+        LogFileStats {
+            live_keys: self.live_count() as u64,
+            ..self.inner.stats.get()
+        }
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq
+    }
+
+    fn advance_seq(&mut self, seq: u64) {
+        self.inner.last_seq = self.inner.last_seq.max(seq);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = super::Result<LogItem>>> {
+        let value_file = match File::open(&self.inner.value_path) {
+            Ok(value_file) => value_file,
+            Err(e) => {
+                return Box::new(std::iter::once(Err(LogFileError::LogFileIter {
+                    source: Box::new(e.into()),
+                    location: location!(),
+                })))
+            }
+        };
+
+        Box::new(
+            super::iter_log(&self.inner.path, self.inner.codec.clone()).map(move |item| {
+                let mut item = item?;
+                if let Some(value_ptr) = item.value_ptr {
+                    item.value = Some(read_value(&value_file, value_ptr).map_err(|e| {
+                        LogFileError::LogFileIter {
+                            source: Box::new(e.into()),
+                            location: location!(),
+                        }
+                    })?);
+                }
+                Ok(item)
+            }),
+        )
+    }
+}
+
+/// the live ones keep the timestamp of their records
+enum IndexEntry {
+    Exist(ValuePtr, Option<u64>),
+    /// a small value kept in the index log, and in memory
+    Inline(String, Option<u64>),
+    Removed,
+}
+
+impl IndexEntry {
+    fn is_live(&self) -> bool {
+        !matches!(self, IndexEntry::Removed)
+    }
+}
+
+pub struct KvSepLogFileInner {
+    index: HashMap<String, IndexEntry>,
+    op_ids: RecentOpIds,
+    index_file: File,
+    value_file: File,
+    path: PathBuf,
+    value_path: PathBuf,
+    /// the highest sequence number written
+    last_seq: u64,
+    codec: Arc<dyn RecordCodec>,
+    /// stamps the written records
+    clock: Arc<dyn Clock>,
+    stats: StatsCounter,
+    value_inline_threshold: usize,
+}
+
+impl KvSepLogFileInner {
+    pub fn new(path: &Path, options: &LogFileOptions) -> Result<KvSepLogFileInner> {
+        // process before to assert path exist
+        if !path.exists() {
+            return Err(Error::InvalidPath {
+                location: location!(),
+                path: path.into(),
+            });
+        }
+
+        // init cache
+        let (index, op_ids, last_seq) = build_index(path, options)?;
+
+        // open files, the value log is created along with the index log
+        info!("open kv_sep log_file:{} for writing", path.display());
+        let index_file = File::options()
+            .read(options.read_only)
+            .append(!options.read_only)
+            .open(path)
+            .context(OpenFileSnafu { path })?;
+        let value_path = value_log_path(path);
+        let value_file = File::options()
+            .read(true)
+            .append(!options.read_only)
+            .create(!options.read_only)
+            .open(value_path.as_path())
+            .context(OpenFileSnafu {
+                path: value_path.clone(),
+            })?;
+
+        Ok(KvSepLogFileInner {
+            index,
+            op_ids,
+            index_file,
+            value_file,
+            path: path.to_path_buf(),
+            value_path,
+            last_seq,
+            codec: options.codec.clone(),
+            clock: options.clock.clone(),
+            stats: StatsCounter::new(options.write_amp.clone()),
+            value_inline_threshold: options.value_inline_threshold,
+        })
+    }
+
+    #[allow(unused)]
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_with_op_id(key, value, None)
+    }
+
+    pub fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> Result<()> {
+        let ts = super::now_millis(&*self.clock);
+        self.set_with_ts(key, value, op_id, ts)
+    }
+
+    /// set stamped by the given timestamp rather than now
+    pub fn set_with_ts(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+        ts: Option<u64>,
+    ) -> Result<()> {
+        debug!("set key:{} in kv_sep_log_file", key);
+
+        if self.is_applied(op_id.as_deref()) {
+            info!("set key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
+
+        if value.len() < self.value_inline_threshold {
+            let item = LogItem::new("set".to_owned(), key, Some(value))
+                .with_op_id(op_id)
+                .with_seq(self.last_seq + 1)
+                .with_ts(ts);
+            let bytes = self.write_index(&item)?;
+            self.stats.record_write(bytes);
+            self.last_seq += 1;
+
+            let _ = self
+                .index
+                .insert(item.key, IndexEntry::Inline(item.value.unwrap(), ts));
+            if let Some(op_id) = item.op_id {
+                let _ = self.op_ids.insert(op_id);
+            }
+            return Ok(());
+        }
+
+        // append value first, so the index never points to a missing value
+        let value_ptr = ValuePtr {
+            offset: self.value_len()?,
+            len: value.len() as u64,
+        };
+        self.value_file
+            .write_all(value.as_bytes())
+            .context(WriteFileSnafu {
+                path: self.value_path.clone(),
+            })?;
+
+        // then append the pointer to the index log
+        let item = LogItem::new("set".to_owned(), key, None)
+            .with_op_id(op_id)
+            .with_value_ptr(value_ptr)
+            .with_seq(self.last_seq + 1)
+            .with_ts(ts);
+        let index_bytes = self.write_index(&item)?;
+        self.stats.record_write(index_bytes + value.len());
+        self.last_seq += 1;
+
+        // update index
+        let _ = self
+            .index
+            .insert(item.key, IndexEntry::Exist(value_ptr, ts));
+        if let Some(op_id) = item.op_id {
+            let _ = self.op_ids.insert(op_id);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_with_ts(key)
+            .map(|value| value.map(|(value, _)| value))
+    }
+
+    /// the value with the timestamp of its record
+    pub fn get_with_ts(&self, key: &str) -> Result<Option<(String, Option<u64>)>> {
+        debug!("get key:{} in kv_sep_log_file", key);
+
+        let (value_ptr, ts) = match self.index.get(key) {
+            Some(IndexEntry::Exist(value_ptr, ts)) => (*value_ptr, *ts),
+            Some(IndexEntry::Inline(value, ts)) => return Ok(Some((value.clone(), *ts))),
+            Some(IndexEntry::Removed) | None => return Ok(None),
+        };
+
+        // read the value from value log by pointer
+        self.stats.record_read();
+        read_value(&self.value_file, value_ptr)
+            .context(ReadValueSnafu { key })
+            .map(|value| Some((value, ts)))
+    }
+
+    #[allow(unused)]
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_with_op_id(key, None)
+    }
+
+    pub fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
+        debug!("rm key:{} in kv_sep_log_file", key);
+
+        if self.is_applied(op_id.as_deref()) {
+            info!("rm key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
+
+        if !self.index.get(&key).is_some_and(IndexEntry::is_live) {
+            return Err(Error::RemoveNotExistKey {
+                location: location!(),
+                key,
+            });
+        }
+        self.tombstone_with_op_id(key, op_id)
+    }
+
+    /// append a tombstone whether the key is in this file or not,
+    /// it hides the value held by the older files
+    pub fn tombstone_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
+        if self.is_applied(op_id.as_deref()) {
+            info!("rm key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
+
+        // only the index log records the tombstone
+        let item = LogItem::new("rm".to_owned(), key, None)
+            .with_op_id(op_id)
+            .with_seq(self.last_seq + 1)
+            .with_ts(super::now_millis(&*self.clock));
+        let bytes = self.write_index(&item)?;
+        self.stats.record_write(bytes);
+        self.last_seq += 1;
+
+        // update index
+        let _ = self.index.insert(item.key, IndexEntry::Removed);
+        if let Some(op_id) = item.op_id {
+            let _ = self.op_ids.insert(op_id);
+        }
+        Ok(())
+    }
+
+    /// the index log lines of the latest records,
+    /// the values stay in place in the value log
+    pub fn scan(&self) -> Result<Vec<String>> {
+        info!("scan in kv_sep_log_file");
+
+        // ordered by key, the index doesn't keep the written order
+        let mut entries: Vec<_> = self.index.iter().collect();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        let mut cmds = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            let item = match entry {
+                IndexEntry::Exist(value_ptr, ts) => {
+                    LogItem::new("set".to_owned(), key.clone(), None)
+                        .with_value_ptr(*value_ptr)
+                        .with_ts(*ts)
+                }
+                IndexEntry::Inline(value, ts) => {
+                    LogItem::new("set".to_owned(), key.clone(), Some(value.clone())).with_ts(*ts)
+                }
+                IndexEntry::Removed => LogItem::new("rm".to_owned(), key.clone(), None),
+            };
+            cmds.push(
+                log_item::encode_record(&*self.codec, &item).context(LogEncoderSnafu)? + "\n",
+            );
+        }
+
+        Ok(cmds)
+    }
+
+    /// total bytes of the index log and the value log
+    pub fn len(&self) -> Result<u64> {
+        let index_len = self
+            .index_file
+            .metadata()
+            .context(ReadFileSnafu {
+                path: self.path.clone(),
+            })?
+            .len();
+
+        Ok(index_len + self.value_len()?)
+    }
+
+    fn value_len(&self) -> Result<u64> {
+        Ok(self
+            .value_file
+            .metadata()
+            .context(ReadFileSnafu {
+                path: self.value_path.clone(),
+            })?
+            .len())
+    }
+
+    /// return the bytes written
+    fn write_index(&mut self, item: &LogItem) -> Result<usize> {
+        let json_str = log_item::encode_record(&*self.codec, item).context(LogEncoderSnafu)? + "\n";
+        self.index_file
+            .write_all(json_str.as_bytes())
+            .context(WriteFileSnafu {
+                path: self.path.clone(),
+            })?;
+        Ok(json_str.len())
+    }
+
+    fn is_applied(&self, op_id: Option<&str>) -> bool {
+        op_id.is_some_and(|op_id| self.op_ids.contains(op_id))
+    }
+}
+
+fn read_value(value_file: &File, value_ptr: ValuePtr) -> std::io::Result<String> {
+    // check the range before allocating, a broken pointer may be huge
+    let value_len = value_file.metadata()?.len();
+    if value_ptr
+        .offset
+        .checked_add(value_ptr.len)
+        .is_none_or(|end| end > value_len)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is out of the value log of {} bytes",
+                value_ptr, value_len
+            ),
+        ));
+    }
+
+    let mut buf = vec![0; super::checked_usize(value_ptr.len)?];
+    super::read_exact_at(value_file, &mut buf, value_ptr.offset)?;
+
+    String::from_utf8(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// the value log of `data_n` is `data_n.vlog`
+pub fn value_log_path(path: &Path) -> PathBuf {
+    let mut value_path = path.as_os_str().to_owned();
+    value_path.push(".vlog");
+    value_path.into()
+}
+
+fn build_index(
+    path: impl AsRef<Path>,
+    options: &LogFileOptions,
+) -> Result<(HashMap<String, IndexEntry>, RecentOpIds, u64)> {
+    let path = path.as_ref();
+    info!("build_index from kv_sep file:{}", path.display());
+
+    let file = File::open(path).context(OpenFileSnafu { path })?;
+    let file_len = file.metadata().context(ReadFileSnafu { path })?.len();
+    let mut index = HashMap::with_capacity(options.index_capacity(file_len));
+    let mut op_ids = RecentOpIds::default();
+    let mut last_seq = 0;
+    for item in LogIter::new(
+        super::buf_reader(file, options.read_buf_size),
+        options.codec.clone(),
+    )
+    .with_max_len(options.max_record_len)
+    {
+        let item = item.context(IterLogSnafu)?;
+        last_seq = last_seq.max(item.seq.unwrap_or(0));
+
+        // a duplicated op has been applied before, replay it as a no-op
+        if let Some(op_id) = &item.op_id {
+            if !op_ids.insert(op_id.clone()) {
+                continue;
+            }
+        }
+
+        match item.cmd.as_str() {
+            "set" => {
+                let entry = match (item.value_ptr, item.value.clone()) {
+                    (Some(value_ptr), _) => IndexEntry::Exist(value_ptr, item.ts),
+                    (None, Some(value)) => IndexEntry::Inline(value, item.ts),
+                    (None, None) => return UnknownCmdSnafu { item }.fail(),
+                };
+                let _ = index.insert(item.key, entry);
+            }
+            "rm" => {
+                let _ = index.insert(item.key, IndexEntry::Removed);
+            }
+            _ => match options.unknown_cmd {
+                UnknownCmdPolicy::Strict => {
+                    return Err(Error::UnknownCmd {
+                        location: location!(),
+                        item: Box::new(item),
+                    });
+                }
+                UnknownCmdPolicy::Skip => {
+                    warn!("skip unknown log {:?} in file:{}", item, path.display());
+                }
+            },
+        }
+    }
+
+    Ok((index, op_ids, last_seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{read_value, value_log_path, KvSepLogFile, KvSepLogFileInner};
+    use crate::log_file::log_item::ValuePtr;
+    use crate::log_file::{LogFile, LogFileOptions};
+
+    #[test]
+    fn crud() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            KvSepLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+
+        // set large values
+        let kv1 = ("key1".to_owned(), "a".repeat(64 * 1024));
+        let kv2 = ("key2".to_owned(), "b".repeat(64 * 1024));
+        let kv3 = ("key3".to_owned(), "".to_owned());
+        test_log_file.set(kv1.0.clone(), kv1.1.clone()).unwrap();
+        test_log_file.set(kv2.0.clone(), kv2.1.clone()).unwrap();
+        test_log_file.set(kv3.0.clone(), kv3.1.clone()).unwrap();
+
+        // get
+        assert_eq!(test_log_file.get(&kv1.0).unwrap().unwrap(), kv1.1);
+        assert_eq!(test_log_file.get(&kv2.0).unwrap().unwrap(), kv2.1);
+        assert_eq!(test_log_file.get(&kv3.0).unwrap().unwrap(), kv3.1);
+
+        // rm
+        test_log_file.remove(kv2.0.clone()).unwrap();
+        assert!(test_log_file.get(&kv2.0).unwrap().is_none());
+        assert!(test_log_file.remove(kv2.0.clone()).is_err());
+
+        // reopen to check replay
+        drop(test_log_file);
+        let test_log_file =
+            KvSepLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert_eq!(test_log_file.get(&kv1.0).unwrap().unwrap(), kv1.1);
+        assert!(test_log_file.get(&kv2.0).unwrap().is_none());
+        assert_eq!(test_log_file.get(&kv3.0).unwrap().unwrap(), kv3.1);
+
+        // the values live in the value log, the index log stays small
+        let index_len = fs::metadata(test_file.path()).unwrap().len();
+        let value_len = fs::metadata(value_log_path(test_file.path()))
+            .unwrap()
+            .len();
+        assert!(index_len < 1024);
+        assert_eq!(value_len, 2 * 64 * 1024);
+        assert_eq!(test_log_file.len().unwrap(), index_len + value_len);
+
+        fs::remove_file(value_log_path(test_file.path())).unwrap();
+    }
+
+    #[test]
+    fn scan() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            KvSepLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        for i in 0..100_u32 {
+            test_log_file
+                .set("key1".to_owned(), format!("{}{}", "v".repeat(1024), i))
+                .unwrap();
+        }
+
+        // only the latest pointer is kept, no value is copied
+        let cmds = test_log_file.scan().unwrap();
+        assert_eq!(cmds.len(), 1);
+        assert!(cmds[0].len() < 128);
+
+        // rewrite index log by scanned cmds, and the value is still there
+        drop(test_log_file);
+        fs::write(test_file.path(), cmds.concat()).unwrap();
+        let test_log_file =
+            KvSepLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert_eq!(
+            test_log_file.get("key1").unwrap().unwrap(),
+            format!("{}{}", "v".repeat(1024), 99)
+        );
+
+        fs::remove_file(value_log_path(test_file.path())).unwrap();
+    }
+
+    #[test]
+    fn iter() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            KvSepLogFile::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        test_log_file
+            .set_with_op_id("key1".to_owned(), "value1".to_owned(), None)
+            .unwrap();
+        test_log_file
+            .remove_with_op_id("key1".to_owned(), None)
+            .unwrap();
+
+        // values are resolved from the value log
+        let items: Vec<_> = test_log_file.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].cmd, "set");
+        assert_eq!(items[0].value.as_deref(), Some("value1"));
+        assert_eq!(items[1].cmd, "rm");
+        assert_eq!(items[1].key, "key1");
+
+        fs::remove_file(value_log_path(test_file.path())).unwrap();
+    }
+
+    #[test]
+    fn empty_value() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            KvSepLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        test_log_file.set("key1".to_owned(), String::new()).unwrap();
+        assert_eq!(test_log_file.get("key1").unwrap(), Some(String::new()));
+
+        // reopen
+        drop(test_log_file);
+        let test_log_file =
+            KvSepLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert_eq!(test_log_file.get("key1").unwrap(), Some(String::new()));
+
+        fs::remove_file(value_log_path(test_file.path())).unwrap();
+    }
+
+    #[test]
+    fn broken_value_ptr() {
+        let mut value_file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut value_file, b"value1").unwrap();
+        let read = |offset, len| read_value(&value_file, ValuePtr { offset, len });
+        assert_eq!(read(0, 6).unwrap(), "value1");
+        assert_eq!(read(6, 0).unwrap(), "");
+
+        // rejected without allocating or overflowing
+        assert!(read(0, 1 << 60).is_err());
+        assert!(read(u64::MAX, 2).is_err());
+        assert!(read(3, 4).is_err());
+    }
+
+    #[test]
+    fn inline_small_values() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let options = LogFileOptions {
+            value_inline_threshold: 8,
+            ..Default::default()
+        };
+        let mut test_log_file = KvSepLogFileInner::new(test_file.path(), &options).unwrap();
+        let below = ("key1".to_owned(), "a".repeat(7));
+        let above = ("key2".to_owned(), "b".repeat(8));
+        test_log_file.set(below.0.clone(), below.1.clone()).unwrap();
+        test_log_file.set(above.0.clone(), above.1.clone()).unwrap();
+        assert_eq!(test_log_file.get(&below.0).unwrap(), Some(below.1.clone()));
+        assert_eq!(test_log_file.get(&above.0).unwrap(), Some(above.1.clone()));
+
+        // only the value at the threshold goes to the value log
+        let value_len = fs::metadata(value_log_path(test_file.path()))
+            .unwrap()
+            .len();
+        assert_eq!(value_len, 8);
+
+        // compacted by the scanned cmds, then replayed
+        let cmds = test_log_file.scan().unwrap();
+        drop(test_log_file);
+        fs::write(test_file.path(), cmds.concat()).unwrap();
+        let mut test_log_file = KvSepLogFileInner::new(test_file.path(), &options).unwrap();
+        assert_eq!(test_log_file.get(&below.0).unwrap(), Some(below.1));
+        assert_eq!(test_log_file.get(&above.0).unwrap(), Some(above.1));
+
+        // an inlined value is removed as well
+        test_log_file.remove(below.0.clone()).unwrap();
+        assert!(test_log_file.get(&below.0).unwrap().is_none());
+
+        fs::remove_file(value_log_path(test_file.path())).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use serde_derive::{Deserialize, Serialize};
 use snafu::{Location, ResultExt, Snafu};
+use std::fmt::Debug;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -7,7 +8,7 @@ pub enum Error {
     EncodeLog {
         source: serde_json::Error,
         location: Location,
-        item: LogItem,
+        item: Box<LogItem>,
     },
 
     #[snafu(display("{} decode log {:?} failed: {}", location, json_str, source))]
@@ -16,9 +17,50 @@ pub enum Error {
         location: Location,
         json_str: String,
     },
+
+    #[snafu(display("{} encode log {:?} by {} failed: {}", location, item, codec, source))]
+    EncodeRecord {
+        source: CodecError,
+        location: Location,
+        codec: &'static str,
+        item: Box<LogItem>,
+    },
+
+    #[snafu(display("{} decode log {:?} by {} failed: {}", location, line, codec, source))]
+    DecodeRecord {
+        source: CodecError,
+        location: Location,
+        codec: &'static str,
+        line: String,
+    },
+
+    #[snafu(display("{} log {:?} encoded by {} contains a newline", location, line, codec))]
+    MultiLineRecord {
+        location: Location,
+        codec: &'static str,
+        line: String,
+    },
+
+    #[snafu(display("{} unsupported log version {} of {:?}", location, version, json_str))]
+    UnsupportedVersion {
+        location: Location,
+        version: u32,
+        json_str: String,
+    },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// version of the records written by this build
+pub const LOG_VERSION: u32 = 1;
+
+fn default_version() -> u32 {
+    1
+}
+
+fn is_current_version(version: &u32) -> bool {
+    *version == LOG_VERSION
+}
+
 // log //////////////////////////////////////////////////
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct LogItem {
@@ -26,18 +68,135 @@ pub struct LogItem {
     pub key: String,
     #[serde(default)]
     pub value: Option<String>,
+    /// client-supplied id, a retried op with the same id is a no-op
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_id: Option<String>,
+    /// where the value lives in the value log, only for kv separated files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_ptr: Option<ValuePtr>,
+    /// sequence number of the write, increasing across the files of a store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// unix millis when the record was written, missing in the records
+    /// written before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+    /// format version of the record, missing in the records written
+    /// before it, which are version 1
+    #[serde(
+        rename = "v",
+        default = "default_version",
+        skip_serializing_if = "is_current_version"
+    )]
+    pub version: u32,
+}
+
+/// only the version, read when the rest fails to parse as it may be
+/// of another format
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(rename = "v", default = "default_version")]
+    version: u32,
+}
+
+/// position of a value in the value log
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ValuePtr {
+    pub offset: u64,
+    pub len: u64,
 }
 
 #[allow(unused)]
 impl LogItem {
     pub fn new(cmd: String, key: String, value: Option<String>) -> LogItem {
-        LogItem { cmd, key, value }
+        LogItem {
+            cmd,
+            key,
+            value,
+            op_id: None,
+            value_ptr: None,
+            seq: None,
+            ts: None,
+            version: LOG_VERSION,
+        }
+    }
+
+    pub fn with_op_id(mut self, op_id: Option<String>) -> LogItem {
+        self.op_id = op_id;
+        self
+    }
+
+    pub fn with_value_ptr(mut self, value_ptr: ValuePtr) -> LogItem {
+        self.value_ptr = Some(value_ptr);
+        self
+    }
+
+    pub fn with_seq(mut self, seq: u64) -> LogItem {
+        self.seq = Some(seq);
+        self
+    }
+
+    pub fn with_ts(mut self, ts: Option<u64>) -> LogItem {
+        self.ts = ts;
+        self
     }
 }
 
-#[allow(unused)]
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// encode the records into the lines of the logs and back, the
+/// records are split by `\n`, so an encoded one must not contain it
+pub trait RecordCodec: Debug + Send + Sync {
+    /// recorded in the store, so it won't be read by another codec
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, item: &LogItem) -> Result<String, CodecError>;
+
+    fn decode(&self, line: &str) -> Result<LogItem, CodecError>;
+}
+
+/// encode by the codec, and check it is a single line
+pub fn encode_record(codec: &dyn RecordCodec, item: &LogItem) -> Result<String> {
+    let line = codec.encode(item).context(EncodeRecordSnafu {
+        codec: codec.name(),
+        item: item.clone(),
+    })?;
+    if line.contains('\n') {
+        return MultiLineRecordSnafu {
+            codec: codec.name(),
+            line,
+        }
+        .fail();
+    }
+
+    Ok(line)
+}
+
+pub fn decode_record(codec: &dyn RecordCodec, line: &str) -> Result<LogItem> {
+    codec.decode(line).context(DecodeRecordSnafu {
+        codec: codec.name(),
+        line,
+    })
+}
+
+/// the json codec, the default one
+#[derive(Debug, Clone, Copy, Default)]
 pub struct LogEncoder;
 
+impl RecordCodec for LogEncoder {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, item: &LogItem) -> Result<String, CodecError> {
+        Ok(LogEncoder::encode(item)?)
+    }
+
+    fn decode(&self, line: &str) -> Result<LogItem, CodecError> {
+        Ok(LogEncoder::decode(line)?)
+    }
+}
+
 #[allow(unused)]
 impl LogEncoder {
     pub fn encode(item: &LogItem) -> Result<String> {
@@ -45,14 +204,30 @@ impl LogEncoder {
     }
 
     pub fn decode(json_str: &str) -> Result<LogItem> {
-        serde_json::from_str(json_str).context(DecodeLogSnafu { json_str })
+        let item: LogItem = match serde_json::from_str(json_str) {
+            Ok(item) => item,
+            // a future version may change the format, probe its version
+            // only to report it
+            Err(e) => {
+                return match serde_json::from_str::<Versioned>(json_str) {
+                    Ok(Versioned { version }) if version != 1 => {
+                        UnsupportedVersionSnafu { version, json_str }.fail()
+                    }
+                    _ => Err(e).context(DecodeLogSnafu { json_str }),
+                }
+            }
+        };
+        match item.version {
+            1 => Ok(item),
+            version => UnsupportedVersionSnafu { version, json_str }.fail(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     // use assert_cmd::assert;
-    use super::{LogEncoder, LogItem};
+    use super::{Error, LogEncoder, LogItem, LOG_VERSION};
     #[test]
     fn test_log_item_serde() {
         // decode valid
@@ -100,4 +275,55 @@ mod tests {
         assert!(test_json4.contains("key3"));
         assert!(test_json4.contains("null"));
     }
+
+    #[test]
+    fn empty_value() {
+        // an empty value is not a missing one
+        let set = LogItem::new("set".to_owned(), "key1".to_owned(), Some(String::new()));
+        let rm = LogItem::new("rm".to_owned(), "key1".to_owned(), None);
+        for item in [set, rm] {
+            let json = LogEncoder::encode(&item).unwrap();
+            assert_eq!(LogEncoder::decode(&json).unwrap(), item);
+        }
+
+        let no_value = LogEncoder::decode(r#"{"cmd":"rm","key":"key1"}"#).unwrap();
+        assert!(no_value.value.is_none());
+    }
+
+    #[test]
+    fn version() {
+        // the records written before the version are version 1
+        let v1 = LogEncoder::decode(r#"{"cmd":"set","key":"key1","value":"value1"}"#).unwrap();
+        assert_eq!(v1.version, 1);
+        assert_eq!(
+            v1,
+            LogItem::new(
+                "set".to_owned(),
+                "key1".to_owned(),
+                Some("value1".to_owned())
+            )
+        );
+        let explicit =
+            LogEncoder::decode(r#"{"cmd":"set","key":"key1","value":"value1","v":1}"#).unwrap();
+        assert_eq!(explicit, v1);
+        // and still written in the same format
+        assert!(!LogEncoder::encode(&v1).unwrap().contains("\"v\""));
+        assert_eq!(LOG_VERSION, 1);
+
+        // a future version is rejected even if its format changed
+        let res = LogEncoder::decode(r#"{"v":2,"op":"set","k":"key1"}"#);
+        assert!(matches!(
+            res,
+            Err(Error::UnsupportedVersion { version: 2, .. })
+        ));
+        // or in the same format
+        let res = LogEncoder::decode(r#"{"cmd":"set","key":"key1","value":"value1","v":2}"#);
+        assert!(matches!(
+            res,
+            Err(Error::UnsupportedVersion { version: 2, .. })
+        ));
+        // a broken record of the current version fails to decode
+        let res = LogEncoder::decode(r#"{"cmd":"set","v":1}"#);
+        assert!(matches!(res, Err(Error::DecodeLog { .. })));
+    }
 }
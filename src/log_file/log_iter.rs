@@ -0,0 +1,234 @@
+use super::log_item::{self, LogItem, RecordCodec};
+use log::warn;
+use snafu::{location, Location, ResultExt, Snafu};
+use std::{
+    io::{BufRead, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{} open log_file {} failed: {}", location, path.display(), source))]
+    OpenFile {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} read log at offset {} failed: {}", location, offset, source))]
+    ReadLog {
+        source: std::io::Error,
+        location: Location,
+        offset: u64,
+    },
+
+    #[snafu(display("{} decode log at offset {} failed: {}", location, offset, source))]
+    DecodeLog {
+        #[snafu(source(from(log_item::Error, Box::new)))]
+        source: Box<log_item::Error>,
+        location: Location,
+        offset: u64,
+    },
+
+    #[snafu(display("{} log at offset {} exceeds {} bytes", location, offset, max_len))]
+    RecordTooLong {
+        location: Location,
+        offset: u64,
+        max_len: u64,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// read and decode the records of a log one by one,
+/// the only place to parse a log from its beginning
+pub struct LogIter<R> {
+    reader: R,
+    codec: Arc<dyn RecordCodec>,
+    next_offset: u64,
+    last_offset: u64,
+    last_len: u64,
+    /// a longer line fails rather than being read whole into memory
+    max_len: u64,
+}
+
+impl LogIter<Box<dyn BufRead>> {
+    /// a compressed file is decompressed while reading
+    pub fn open(path: impl AsRef<Path>, codec: Arc<dyn RecordCodec>) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = super::open_records(path, super::DEFAULT_READ_BUF_SIZE)
+            .context(OpenFileSnafu { path })?;
+        Ok(LogIter::new(reader, codec))
+    }
+}
+
+impl<R: BufRead> LogIter<R> {
+    pub fn new(reader: R, codec: Arc<dyn RecordCodec>) -> Self {
+        LogIter::with_offset(reader, 0, codec)
+    }
+
+    /// the reader is positioned at the offset of the log already
+    pub fn with_offset(reader: R, offset: u64, codec: Arc<dyn RecordCodec>) -> Self {
+        LogIter {
+            reader,
+            codec,
+            next_offset: offset,
+            last_offset: offset,
+            last_len: 0,
+            max_len: u64::MAX,
+        }
+    }
+
+    pub fn with_max_len(mut self, max_len: u64) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// offset of the record returned by the last `next`
+    pub fn offset(&self) -> u64 {
+        self.last_offset
+    }
+
+    /// bytes of the record returned by the last `next`, with its `\n`
+    pub fn record_len(&self) -> u64 {
+        self.last_len
+    }
+}
+
+impl<R: BufRead> Iterator for LogIter<R> {
+    type Item = Result<LogItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let offset = self.next_offset;
+            let mut line = String::new();
+            // read one more byte than allowed to tell a too long line
+            let bytes = match (&mut self.reader)
+                .take(self.max_len.saturating_add(1))
+                .read_line(&mut line)
+                .context(ReadLogSnafu { offset })
+            {
+                Ok(bytes) => bytes,
+                Err(e) => return Some(Err(e)),
+            };
+            if bytes == 0 {
+                return None;
+            }
+            if bytes as u64 > self.max_len {
+                return Some(Err(Error::RecordTooLong {
+                    location: location!(),
+                    offset,
+                    max_len: self.max_len,
+                }));
+            }
+            self.next_offset += bytes as u64;
+
+            // a blank line may be left by a botched write, it holds no
+            // record, skip it but keep counting its bytes
+            if line.trim().is_empty() {
+                warn!("skip blank line at offset {} in log", offset);
+                continue;
+            }
+
+            self.last_offset = offset;
+            self.last_len = bytes as u64;
+            // the line is decoded without its `\n`
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            return Some(
+                log_item::decode_record(&*self.codec, line).context(DecodeLogSnafu { offset }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use super::{Error, LogIter};
+    use crate::log_file::log_item::{LogEncoder, LogItem};
+
+    #[test]
+    fn iter() {
+        let items = [
+            LogItem::new(
+                "set".to_owned(),
+                "key1".to_owned(),
+                Some("value1".to_owned()),
+            ),
+            LogItem::new("rm".to_owned(), "key1".to_owned(), None),
+            LogItem::new(
+                "set".to_owned(),
+                "key2".to_owned(),
+                Some("value2".to_owned()),
+            ),
+        ];
+        let lines: Vec<_> = items
+            .iter()
+            .map(|item| LogEncoder::encode(item).unwrap() + "\n")
+            .collect();
+
+        let mut iter = LogIter::new(Cursor::new(lines.concat()), Arc::new(LogEncoder));
+        let mut offset = 0;
+        for (item, line) in items.iter().zip(lines.iter()) {
+            assert_eq!(&iter.next().unwrap().unwrap(), item);
+            assert_eq!(iter.offset(), offset);
+            assert_eq!(iter.record_len(), line.len() as u64);
+            offset += line.len() as u64;
+        }
+        assert!(iter.next().is_none());
+
+        // broken record
+        let mut iter = LogIter::new(
+            Cursor::new(lines[0].clone() + "{broken\n"),
+            Arc::new(LogEncoder),
+        );
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+
+        // blank lines are skipped, the offsets still point to the records
+        let mut iter = LogIter::new(
+            Cursor::new(lines[0].clone() + "\n  \n" + &lines[2] + "\n"),
+            Arc::new(LogEncoder),
+        );
+        assert_eq!(&iter.next().unwrap().unwrap(), &items[0]);
+        assert_eq!(&iter.next().unwrap().unwrap(), &items[2]);
+        assert_eq!(iter.offset(), lines[0].len() as u64 + 4);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn record_too_long() {
+        let line = LogEncoder::encode(&LogItem::new(
+            "set".to_owned(),
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+        ))
+        .unwrap()
+            + "\n";
+        let max_len = line.len() as u64;
+
+        // a line of the max length passes
+        let mut iter =
+            LogIter::new(Cursor::new(line.clone()), Arc::new(LogEncoder)).with_max_len(max_len);
+        assert!(iter.next().unwrap().is_ok());
+
+        // a broken record without `\n` fails at its offset, not read whole
+        let garbage = "x".repeat(1024 * 1024);
+        let mut iter = LogIter::new(Cursor::new(line.clone() + &garbage), Arc::new(LogEncoder))
+            .with_max_len(max_len);
+        assert!(iter.next().unwrap().is_ok());
+        match iter.next().unwrap() {
+            Err(Error::RecordTooLong {
+                offset,
+                max_len: max,
+                ..
+            }) => {
+                assert_eq!(offset, line.len() as u64);
+                assert_eq!(max, max_len);
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -1,107 +1,751 @@
+mod kv_sep_log_file;
 mod log_item;
+mod log_iter;
+mod op_ids;
 mod ptr_log_file;
+mod stats;
 mod value_log_file;
 
+use crate::clock::{Clock, SystemClock};
+use flate2::read::GzDecoder;
 use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
     path::{Path, PathBuf},
-    sync::RwLock,
+    sync::{Arc, RwLock},
 };
 
+pub use log_item::{CodecError, LogEncoder, LogItem, RecordCodec, ValuePtr};
 use snafu::{location, Location, Snafu};
+pub use stats::{LogFileStats, WriteAmpCounter, WriteAmpStats};
+
+/// the bytes of a file holding a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueRange {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+    /// the bytes are the whole record to decode by the codec,
+    /// rather than the value itself
+    pub encoded: bool,
+}
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("{} set in log_file failed: {}", location, source_str))]
+    #[snafu(display("{} set in log_file failed: {}", location, source))]
     LogFileSet {
-        source_str: String,
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} get in log_file failed: {}", location, source_str))]
+    #[snafu(display("{} get in log_file failed: {}", location, source))]
     LogFileGet {
-        source_str: String,
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} remove in log_file failed: {}", location, source_str))]
+    #[snafu(display("{} remove in log_file failed: {}", location, source))]
     LogFileRm {
-        source_str: String,
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} scan in log_file failed: {}", location, source_str))]
+    #[snafu(display("{} write to log_file {} failed, the disk is full", location, path.display()))]
+    DiskFull { location: Location, path: PathBuf },
+
+    #[snafu(display("{} scan in log_file failed: {}", location, source))]
     LogFileScan {
-        source_str: String,
+        source: Box<EngineError>,
+        location: Location,
+    },
+
+    #[snafu(display("{} iter log_file failed: {}", location, source))]
+    LogFileIter {
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} get log_file's len failed: {}", location, source_str))]
+    #[snafu(display("{} get log_file's len failed: {}", location, source))]
     LogFileLen {
-        source_str: String,
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} build log_file failed: {}", location, source_str))]
+    #[snafu(display("{} build log_file failed: {}", location, source))]
     LogFileBuild {
-        source_str: String,
+        source: Box<EngineError>,
+        location: Location,
+    },
+
+    #[snafu(display("{} flush log_file failed: {}", location, source))]
+    LogFileFlush {
+        source: Box<EngineError>,
+        location: Location,
+    },
+
+    #[snafu(display("{} snapshot log_file's index failed: {}", location, source))]
+    LogFileSnapshot {
+        source: Box<EngineError>,
+        location: Location,
+    },
+
+    #[snafu(display("{} seal log_file failed: {}", location, source))]
+    LogFileSeal {
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} log_file remove file failed: {}", location, source_str))]
+    #[snafu(display("{} log_file remove file failed: {}", location, source))]
     LogFileRmFile {
-        source_str: String,
+        source: Box<EngineError>,
         location: Location,
     },
 
-    #[snafu(display("{} log_file rename file failed: {}", location, source_str))]
+    #[snafu(display("{} compressed segment is not supported in {} mode", location, mode))]
+    CompressedMode { location: Location, mode: String },
+
+    #[snafu(display("{} err mode {}", location, mode))]
+    InvalidMode { location: Location, mode: String },
+
+    #[snafu(display("{} log_file rename file failed: {}", location, source))]
     LogFileRenameFile {
-        source_str: String,
+        source: Box<EngineError>,
+        location: Location,
+    },
+
+    #[snafu(display("{} list log_files failed: {}", location, source))]
+    LogFileListDir {
+        source: Box<EngineError>,
         location: Location,
     },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
-pub trait LogFile {
+
+/// the error of the engine under a log_file error, kept typed so it
+/// can be matched and `source` walks down to its cause. boxed by
+/// `Error` to keep it small
+#[derive(Debug, Snafu)]
+pub enum EngineError {
+    #[snafu(context(false), display("{}", source))]
+    Ptr { source: ptr_log_file::Error },
+
+    #[snafu(context(false), display("{}", source))]
+    KvSep { source: kv_sep_log_file::Error },
+
+    #[snafu(context(false), display("{}", source))]
+    Value { source: value_log_file::Error },
+
+    #[snafu(context(false), display("{}", source))]
+    Iter { source: log_iter::Error },
+
+    /// the io of a file, not failed inside an engine
+    #[snafu(context(false), display("{}", source))]
+    Io { source: std::io::Error },
+
+    /// the listing of the dir holding the files
+    #[snafu(context(false), display("{}", source))]
+    Dir { source: walkdir::Error },
+}
+
+/// what to do with a record whose cmd is unknown when building index,
+/// it may be written by a newer version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownCmdPolicy {
+    /// fail to open the log file
+    Strict,
+    /// skip the record with a warning
+    Skip,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFileOptions {
+    pub unknown_cmd: UnknownCmdPolicy,
+    /// estimated bytes per record, used to preallocate the index
+    /// when building it from a file, 0 disables the preallocation
+    pub avg_record_size: u64,
+    /// a failed read may be caused by a broken index (e.g. a corrupt
+    /// snapshot), rebuild the index from the file and retry once
+    pub rebuild_index_on_bad_read: bool,
+    /// how the records are encoded, a store must always be opened by
+    /// the codec it is created with
+    pub codec: Arc<dyn RecordCodec>,
+    /// counts the bytes written by all the files of a store,
+    /// a store gets a new one when opened
+    pub write_amp: Arc<WriteAmpCounter>,
+    /// in kv_sep mode, a value shorter than it is kept in the index log
+    /// rather than the value log, 0 puts all in the value log
+    pub value_inline_threshold: usize,
+    /// re-scan the whole file after building the index to check the
+    /// live keys are matched, on in debug builds only by default
+    pub verify_index: bool,
+    /// in ptr mode, build the index of a file on its first access
+    /// rather than on opening, so opening a store of many segments
+    /// won't index the ones never read
+    pub lazy_index: bool,
+    /// a record longer than it fails building the index rather than
+    /// being read whole into memory, e.g. a broken one missing its `\n`
+    pub max_record_len: u64,
+    /// stamps the records, a store sets its own clock when opened
+    pub clock: Arc<dyn Clock>,
+    /// bytes buffered by the sequential reads building an index, a
+    /// larger buffer speeds up the scan of a large log on a fast disk
+    pub read_buf_size: usize,
+    /// open the files without writing, for a read-only store
+    pub read_only: bool,
+}
+
+impl Default for LogFileOptions {
+    fn default() -> Self {
+        LogFileOptions {
+            unknown_cmd: UnknownCmdPolicy::Strict,
+            avg_record_size: 64,
+            rebuild_index_on_bad_read: true,
+            codec: Arc::new(LogEncoder),
+            write_amp: Arc::default(),
+            value_inline_threshold: 0,
+            verify_index: cfg!(debug_assertions),
+            lazy_index: false,
+            max_record_len: 64 * 1024 * 1024,
+            clock: Arc::new(SystemClock),
+            read_buf_size: DEFAULT_READ_BUF_SIZE,
+            read_only: false,
+        }
+    }
+}
+
+/// the buffer of the sequential reads, far larger than the 8KB of
+/// `BufReader::new` to take fewer syscalls over a large log
+pub const DEFAULT_READ_BUF_SIZE: usize = 256 * 1024;
+
+/// upper bound of the preallocated entries, a log full of
+/// overwrites holds far fewer keys than records
+const MAX_INDEX_CAPACITY_HINT: u64 = 1 << 20;
+
+impl LogFileOptions {
+    /// estimate the entries count of index from the file's size
+    pub fn index_capacity(&self, file_len: u64) -> usize {
+        if self.avg_record_size == 0 {
+            return 0;
+        }
+
+        (file_len / self.avg_record_size).min(MAX_INDEX_CAPACITY_HINT) as usize
+    }
+}
+
+/// the timestamp of a record written now, none before the epoch
+fn now_millis(clock: &dyn Clock) -> Option<u64> {
+    clock
+        .now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .map(|since| since.as_millis() as u64)
+}
+
+pub trait LogFile: Send + Sync {
     fn contains_key(&self, key: &str) -> bool;
 
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    /// same as `contains_key`, but a file failing to load its index
+    /// is an error rather than taken as not holding the key
+    fn try_contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.contains_key(key))
+    }
+
+    /// the keys known by this file, and whether each is live,
+    /// false for a removed one
+    fn key_states(&self) -> Vec<(String, bool)>;
+
+    /// whether the op has been applied to this file recently
+    fn contains_op_id(&self, op_id: &str) -> bool;
 
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// set which is a no-op if `op_id` has been applied
+    fn set_with_op_id(&mut self, key: String, value: String, op_id: Option<String>) -> Result<()>;
 
-    fn remove(&mut self, key: String) -> Result<()>;
+    /// only reads the file at the position, so it can be shared by readers
+    fn get(&self, key: &str) -> Result<Option<String>>;
 
+    /// same as `get`, but an engine caching the values shares them
+    /// with the readers rather than copying them on every read
+    fn get_shared(&self, key: &str) -> Result<Option<Arc<str>>> {
+        self.get(key).map(|value| value.map(Arc::from))
+    }
+
+    /// the value with the unix millis it was written at, none for the
+    /// records without it or an engine not keeping it
+    fn get_with_ts(&self, key: &str) -> Result<Option<(String, Option<u64>)>> {
+        self.get(key).map(|value| value.map(|value| (value, None)))
+    }
+
+    /// set copied from another record, keeping its timestamp and op id,
+    /// e.g. by a merge, so a retried op is still a no-op after it
+    fn copy_set(
+        &mut self,
+        key: String,
+        value: String,
+        _ts: Option<u64>,
+        op_id: Option<String>,
+    ) -> Result<()> {
+        self.set_with_op_id(key, value, op_id)
+    }
+
+    /// remove which is a no-op if `op_id` has been applied. a key
+    /// missing or already removed fails in all the engines
+    fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()>;
+
+    /// remove even if the key is only in an older file, by a tombstone
+    /// hiding it. an engine not indexing the tombstones can only remove
+    /// its own keys
+    fn tombstone_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
+        self.remove_with_op_id(key, op_id)
+    }
+
+    /// the latest record of each key, in the same order every time,
+    /// so compacting the same file gives the same bytes
     fn scan(&mut self) -> Result<Vec<String>>;
 
+    /// same as `scan`, but the newest `keep` sets of each key are kept,
+    /// the engines not keeping the versions keep the latest only
+    fn scan_versions(&mut self, _keep: usize) -> Result<Vec<String>> {
+        self.scan()
+    }
+
+    /// bytes of the file, the dead records and tombstones included
     fn len(&self) -> Result<u64>;
 
+    /// the keys with a value in this file, unlike `len` it doesn't
+    /// grow with the overwrites and removes
+    fn live_count(&self) -> usize {
+        self.key_states().iter().filter(|(_, live)| *live).count()
+    }
+
     fn path(&self) -> PathBuf;
+
+    /// the mode to build this log file by `LogFileBuilder`
+    fn mode(&self) -> &'static str;
+
+    /// sync the written records to the disk
+    fn flush(&mut self) -> Result<()>;
+
+    /// persist the in-memory index, so opening it again only needs to
+    /// replay the records after the snapshot, no-op if no index to persist
+    fn snapshot(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// build the index from the whole file again, dropping the persisted
+    /// one, no-op if the index can't go wrong
+    fn rebuild_index(&mut self, _options: &LogFileOptions) -> Result<()> {
+        Ok(())
+    }
+
+    /// where the value of the key lies in the file, for reading it
+    /// positionally. none if it isn't on disk as is, e.g. inlined in the
+    /// index or in a compressed file, or the key has no value here
+    fn value_range(&self, _key: &str) -> Option<ValueRange> {
+        None
+    }
+
+    /// bytes on disk held by the live record of the key in this file,
+    /// 0 for a key removed or missing here
+    fn live_bytes(&self, _key: &str) -> u64 {
+        0
+    }
+
+    /// release the spare capacity of the in-memory index,
+    /// e.g. after most of the keys are removed
+    fn shrink_to_fit(&mut self) {}
+
+    /// whether the in-memory index is built, a lazy one is built
+    /// on the first access
+    fn is_loaded(&self) -> bool {
+        true
+    }
+
+    /// the file becomes immutable and won't be appended anymore,
+    /// the engines may prepare a faster read path for it
+    fn seal(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// all the os files backing this log file
+    fn file_paths(&self) -> Vec<PathBuf> {
+        vec![self.path()]
+    }
+
+    /// whether the file at the path is still the opened one,
+    /// false if it has been replaced (e.g. compacted by another process)
+    fn is_current(&self) -> bool;
+
+    /// reads and writes of this file since opened, to find the hot ones
+    fn stats(&self) -> LogFileStats;
+
+    /// the highest sequence number written to this file, 0 if none
+    fn last_seq(&self) -> u64;
+
+    /// number the following writes after `seq`, so a new file
+    /// continues the sequence of the older ones
+    fn advance_seq(&mut self, seq: u64);
+
+    /// decoded records of the file from its beginning, for tooling
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<LogItem>>>;
+}
+
+/// read the records of a file, an unopenable file yields one error
+pub(crate) fn iter_log(
+    path: &Path,
+    codec: Arc<dyn RecordCodec>,
+) -> Box<dyn Iterator<Item = Result<LogItem>>> {
+    let to_err = |e: log_iter::Error| Error::LogFileIter {
+        source: Box::new(e.into()),
+        location: location!(),
+    };
+
+    match log_iter::LogIter::open(path, codec) {
+        Ok(iter) => Box::new(iter.map(move |item| item.map_err(to_err))),
+        Err(e) => Box::new(std::iter::once(Err(to_err(e)))),
+    }
+}
+
+/// the records of the file with their offsets, from its beginning
+pub(crate) fn read_records(
+    path: &Path,
+    codec: Arc<dyn RecordCodec>,
+) -> Result<Vec<(u64, LogItem)>> {
+    let to_err = |e: log_iter::Error| Error::LogFileIter {
+        source: Box::new(e.into()),
+        location: location!(),
+    };
+
+    let mut iter = log_iter::LogIter::open(path, codec).map_err(to_err)?;
+    let mut records = Vec::new();
+    while let Some(item) = iter.next() {
+        records.push((iter.offset(), item.map_err(to_err)?));
+    }
+    Ok(records)
+}
+
+/// an archived segment compressed by gzip, e.g. `data_1.gz`,
+/// it's read-only
+pub(crate) fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// read the records from the beginning of the file,
+/// decompressed if it is compressed
+fn open_records(path: &Path, buf_size: usize) -> std::io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if is_compressed(path) {
+        Ok(Box::new(buf_reader(GzDecoder::new(file), buf_size)))
+    } else {
+        Ok(Box::new(buf_reader(file, buf_size)))
+    }
+}
+
+/// buffer the sequential reads, a 0 size would read nothing
+/// so it falls back to the default
+fn buf_reader<R: Read>(inner: R, buf_size: usize) -> BufReader<R> {
+    let buf_size = match buf_size {
+        0 => DEFAULT_READ_BUF_SIZE,
+        buf_size => buf_size,
+    };
+    BufReader::with_capacity(buf_size, inner)
+}
+
+/// the line at the offset of the records in memory
+fn read_line_in(records: &[u8], offset: u64) -> std::io::Result<String> {
+    let start = checked_usize(offset)?;
+    if start > records.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("offset {} is out of {} bytes", offset, records.len()),
+        ));
+    }
+    let end = records[start..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(records.len(), |pos| start + pos + 1);
+    String::from_utf8(records[start..end].to_vec())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// the record of the length at the offset of the records in memory
+fn read_exact_in(records: &[u8], offset: u64, len: u32) -> std::io::Result<String> {
+    let start = checked_usize(offset)?;
+    let end = start.saturating_add(len as usize);
+    if end > records.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "{} bytes at {} is out of {} bytes",
+                len,
+                offset,
+                records.len()
+            ),
+        ));
+    }
+    String::from_utf8(records[start..end].to_vec())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// identity of the file which changes if it is replaced,
+/// none if it can't be told on the platform
+fn file_id(metadata: &std::fs::Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.ino())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// compare the identity of the opened file with the one at the path
+fn is_same_file(file: &File, path: &Path) -> bool {
+    let (opened, current) = match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => (opened, current),
+        _ => return false,
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        opened.dev() == current.dev() && opened.ino() == current.ino()
+    }
+
+    #[cfg(not(unix))]
+    {
+        opened.len() == current.len() && opened.modified().ok() == current.modified().ok()
+    }
+}
+
+/// read at the offset without moving the file's cursor,
+/// so the readers holding `&File` won't race on it
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    retry_interrupted(|| {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::FileExt::read_at(file, buf, offset)
+        }
+
+        #[cfg(windows)]
+        {
+            std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+        }
+    })
+}
+
+/// run the io op again if a signal interrupts it, as the std
+/// `read_exact`/`write_all` do, instead of failing the read
+fn retry_interrupted<T>(mut op: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    loop {
+        match op() {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// an offset or length from the disk which the platform can't address,
+/// e.g. beyond 4GB on 32-bit targets, or a broken one
+fn checked_usize(n: u64) -> std::io::Result<usize> {
+    usize::try_from(n).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} exceeds the addressable range", n),
+        )
+    })
+}
+
+fn checked_offset(offset: u64, delta: usize) -> std::io::Result<u64> {
+    (delta as u64).checked_add(offset).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("offset {} + {} overflows", offset, delta),
+        )
+    })
+}
+
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    // fail before reading anything if the range can't be represented
+    let _ = checked_offset(offset, buf.len())?;
+    while !buf.is_empty() {
+        match read_at(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset = checked_offset(offset, n)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// read the line starting at the offset, with its `\n` if any
+fn read_line_at(file: &File, offset: u64) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = read_at(file, &mut buf, checked_offset(offset, line.len())?)?;
+        if n == 0 {
+            break;
+        }
+
+        if let Some(pos) = buf[..n].iter().position(|b| *b == b'\n') {
+            line.extend_from_slice(&buf[..=pos]);
+            break;
+        }
+        line.extend_from_slice(&buf[..n]);
+    }
+
+    String::from_utf8(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// read the record of the length at the offset, no need to look for its end
+fn read_record_at(file: &File, offset: u64, len: u32) -> std::io::Result<String> {
+    let mut record = vec![0; len as usize];
+    read_exact_at(file, &mut record, offset)?;
+    String::from_utf8(record).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
 
 pub struct LogFileBuilder;
 
 impl LogFileBuilder {
-    pub fn build(path: impl AsRef<Path>, mode: &str) -> Result<Box<RwLock<dyn LogFile>>> {
+    pub fn build(
+        path: impl AsRef<Path>,
+        mode: &str,
+        options: &LogFileOptions,
+    ) -> Result<Box<RwLock<dyn LogFile>>> {
+        if is_compressed(path.as_ref()) && mode != "ptr" {
+            return Err(Error::CompressedMode {
+                location: location!(),
+                mode: mode.to_owned(),
+            });
+        }
+
         match mode {
             "value" => Ok(Box::new(RwLock::new(
-                value_log_file::ValueLogFile::new(path.as_ref()).map_err(|e| {
+                value_log_file::ValueLogFile::new(path.as_ref(), options).map_err(|e| {
                     Error::LogFileBuild {
-                        source_str: format!("{}", e),
+                        source: Box::new(e.into()),
                         location: location!(),
                     }
                 })?,
             ))),
             "ptr" => Ok(Box::new(RwLock::new(
-                ptr_log_file::PtrLogFile::new(path.as_ref()).map_err(|e| Error::LogFileBuild {
-                    source_str: format!("{}", e),
-                    location: location!(),
+                ptr_log_file::PtrLogFile::new(path.as_ref(), options).map_err(|e| {
+                    Error::LogFileBuild {
+                        source: Box::new(e.into()),
+                        location: location!(),
+                    }
+                })?,
+            ))),
+            "kv_sep" => Ok(Box::new(RwLock::new(
+                kv_sep_log_file::KvSepLogFile::new(path.as_ref(), options).map_err(|e| {
+                    Error::LogFileBuild {
+                        source: Box::new(e.into()),
+                        location: location!(),
+                    }
                 })?,
             ))),
 
-            _ => Err(Error::LogFileBuild {
-                source_str: format!("err mode {}", mode),
+            _ => Err(Error::InvalidMode {
                 location: location!(),
+                mode: mode.to_owned(),
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Write};
+
+    use super::{
+        checked_offset, checked_usize, kv_sep_log_file, log_iter, ptr_log_file, read_exact_at,
+        read_line_at, value_log_file, EngineError, Error, LogFileBuilder, LogFileOptions,
+    };
+
+    #[test]
+    fn decode_error_typed() {
+        let mut test_file = tempfile::NamedTempFile::new().unwrap();
+        test_file
+            .write_all(b"not a record\n{\"cmd\":\"set\",\"key\":\"key1\",\"value\":\"value1\"}\n")
+            .unwrap();
+
+        for mode in ["ptr", "kv_sep", "value"] {
+            let e = LogFileBuilder::build(test_file.path(), mode, &LogFileOptions::default())
+                .err()
+                .unwrap();
+            let decode_error = match e {
+                Error::LogFileBuild { source, .. } => match *source {
+                    EngineError::Ptr {
+                        source: ptr_log_file::Error::IterLog { source, .. },
+                    } => source,
+                    EngineError::KvSep {
+                        source: kv_sep_log_file::Error::IterLog { source, .. },
+                    } => source,
+                    EngineError::Value {
+                        source: value_log_file::Error::IterLog { source, .. },
+                    } => source,
+                    source => panic!("unexpected source in {} mode: {}", mode, source),
+                },
+                e => panic!("unexpected error in {} mode: {}", mode, e),
+            };
+            assert!(matches!(
+                decode_error,
+                log_iter::Error::DecodeLog { offset: 0, .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn large_offset() {
+        let mut test_file = tempfile::tempfile().unwrap();
+        test_file.write_all(b"line1\nline2").unwrap();
+        assert_eq!(read_line_at(&test_file, 6).unwrap(), "line2");
+        assert_eq!(read_line_at(&test_file, 1 << 40).unwrap(), "");
+
+        // the ranges can't be represented are errors rather than overflows
+        assert!(checked_offset(u64::MAX, 1).is_err());
+        assert_eq!(checked_offset(u64::MAX - 1, 1).unwrap(), u64::MAX);
+        let mut buf = [0; 2];
+        let e = read_exact_at(&test_file, &mut buf, u64::MAX - 1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = read_exact_at(&test_file, &mut buf, 11).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        assert_eq!(checked_usize(u32::MAX as u64).unwrap(), u32::MAX as usize);
+        if usize::BITS < 64 {
+            assert!(checked_usize(1 << 40).is_err());
+        }
+    }
+
+    #[test]
+    fn retry_interrupted() {
+        // the interrupted op is run again until it's done
+        let mut tries = 0;
+        let result = super::retry_interrupted(|| {
+            tries += 1;
+            if tries < 3 {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            Ok(tries)
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // while the other errors fail at once
+        let mut tries = 0;
+        let result: std::io::Result<()> = super::retry_interrupted(|| {
+            tries += 1;
+            Err(ErrorKind::UnexpectedEof.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(tries, 1);
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashSet, VecDeque};
+
+pub const DEFAULT_RECENT_OP_IDS: usize = 1024;
+
+/// bounded set of the recently applied op ids,
+/// the oldest id is evicted when it is full
+pub struct RecentOpIds {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl RecentOpIds {
+    pub fn new(capacity: usize) -> RecentOpIds {
+        RecentOpIds {
+            capacity,
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    pub fn contains(&self, op_id: &str) -> bool {
+        self.ids.contains(op_id)
+    }
+
+    /// from the oldest to the newest
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.order.iter()
+    }
+
+    /// return false if the id has been seen already
+    pub fn insert(&mut self, op_id: String) -> bool {
+        if self.ids.contains(&op_id) {
+            return false;
+        }
+
+        self.order.push_back(op_id.clone());
+        let _ = self.ids.insert(op_id);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                let _ = self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+impl Default for RecentOpIds {
+    fn default() -> Self {
+        RecentOpIds::new(DEFAULT_RECENT_OP_IDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecentOpIds;
+
+    #[test]
+    fn bounded() {
+        let mut op_ids = RecentOpIds::new(2);
+        assert!(op_ids.insert("op1".to_owned()));
+        assert!(!op_ids.insert("op1".to_owned()));
+        assert!(op_ids.insert("op2".to_owned()));
+        assert!(op_ids.insert("op3".to_owned()));
+
+        // op1 is evicted
+        assert!(!op_ids.contains("op1"));
+        assert!(op_ids.contains("op2"));
+        assert!(op_ids.contains("op3"));
+    }
+}
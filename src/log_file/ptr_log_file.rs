@@ -1,13 +1,21 @@
 use super::{Error as LogFileError, log_item};
-use super::{log_item::LogItem, LogFile};
-use crate::log_file::log_item::LogEncoder;
-use log::{debug, info};
+use super::{
+    log_item::{LogItem, RecordCodec},
+    op_ids::RecentOpIds,
+    stats::StatsCounter,
+    LogFile, LogFileOptions, LogFileStats, UnknownCmdPolicy, ValueRange,
+};
+use crate::clock::Clock;
+use crate::log_file::log_iter::LogIter;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
 use snafu::{location, Location, ResultExt, Snafu};
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    io::{BufRead, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
 };
 
 #[derive(Debug, Snafu)]
@@ -43,12 +51,20 @@ pub enum Error {
 
     #[snafu(display("{} decode {} in {} failed: {}", location, json_str, caller, source))]
     DecodeLog {
-        source: super::log_item::Error,
+        #[snafu(source(from(super::log_item::Error, Box::new)))]
+        source: Box<super::log_item::Error>,
         location: Location,
         json_str: String,
         caller: String,
     },
 
+    #[snafu(display("{} iter log in {} failed: {}", location, caller, source))]
+    IterLog {
+        source: super::log_iter::Error,
+        location: Location,
+        caller: String,
+    },
+
     #[snafu(display("{} remove non-exist key {}", location, key))]
     RemoveNotExistKey { location: Location, key: String },
 
@@ -59,91 +75,432 @@ pub enum Error {
     },
 
     #[snafu(display("{} unknown log {:?}", location, item))]
-    UnknownCmd { location: Location, item: LogItem },
+    UnknownCmd {
+        location: Location,
+        item: Box<LogItem>,
+    },
 
     #[snafu(display("{} what the hell? {}", location, dscr))]
     Unexpected { location: Location, dscr: String },
 
+    #[cfg(feature = "mmap")]
+    #[snafu(display("{} mmap log_file {} failed: {}", location, path.display(), source))]
+    MapFile {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} write index snapshot {} failed: {}", location, path.display(), source))]
+    WriteSnapshot {
+        source: std::io::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} encode index snapshot {} failed: {}", location, path.display(), source))]
+    EncodeSnapshot {
+        source: serde_json::Error,
+        location: Location,
+        path: PathBuf,
+    },
+
     #[snafu(display("{} file in log_file is empty, path {}", location, path.display()))]
     EmptyFile { location: Location, path: PathBuf },
+
+    #[snafu(display("{} write to compressed log_file {}", location, path.display()))]
+    WriteCompressed { location: Location, path: PathBuf },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 // log file //////////////////////////////////////////////////
 pub struct PtrLogFile {
-    inner: PtrLogFileInner,
+    /// built on the first access if the index is lazy
+    inner: OnceLock<PtrLogFileInner>,
+    path: PathBuf,
+    options: LogFileOptions,
+    /// sealed before built, seal it once built
+    sealed: bool,
 }
 
 impl PtrLogFile {
-    pub fn new(path: &Path) -> Result<Self> {
-        Ok(PtrLogFile {
-            inner: PtrLogFileInner::new(path)?,
-        })
+    pub fn new(path: &Path, options: &LogFileOptions) -> Result<Self> {
+        let log_file = PtrLogFile {
+            inner: OnceLock::new(),
+            path: path.to_path_buf(),
+            options: options.clone(),
+            sealed: false,
+        };
+        if !options.lazy_index {
+            let _ = log_file.loaded()?;
+        }
+        Ok(log_file)
+    }
+
+    fn build(&self) -> Result<PtrLogFileInner> {
+        let mut inner = PtrLogFileInner::new(&self.path, &self.options)?;
+        if self.sealed {
+            inner.seal()?;
+        }
+        Ok(inner)
+    }
+
+    /// the inner, built if not yet. the readers racing to build it
+    /// may each build one, only the first is kept
+    fn loaded(&self) -> Result<&PtrLogFileInner> {
+        if let Some(inner) = self.inner.get() {
+            return Ok(inner);
+        }
+
+        let inner = self.build()?;
+        Ok(self.inner.get_or_init(|| inner))
+    }
+
+    fn loaded_mut(&mut self) -> Result<&mut PtrLogFileInner> {
+        if self.inner.get().is_none() {
+            let inner = self.build()?;
+            let _ = self.inner.set(inner);
+        }
+        Ok(self.inner.get_mut().unwrap())
+    }
+
+    /// for the accessors which can't fail, a file failing to build
+    /// is taken as empty, the reads report the error
+    fn loaded_or_warn(&self) -> Option<&PtrLogFileInner> {
+        self.loaded()
+            .map_err(|e| warn!("build log_file:{} failed: {}", self.path.display(), e))
+            .ok()
     }
 }
 
 impl LogFile for PtrLogFile {
-    fn set(&mut self, key: String, value: String) -> super::Result<()> {
-        self.inner
-            .set(key, value)
-            .map_err(|e| LogFileError::LogFileSet {
-                source_str: format!("{}", e),
+    fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> super::Result<()> {
+        self.loaded_mut()
+            .and_then(|inner| inner.set_with_op_id(key, value, op_id))
+            .map_err(|e| {
+                if is_disk_full(&e) {
+                    return LogFileError::DiskFull {
+                        location: location!(),
+                        path: self.path.clone(),
+                    };
+                }
+                LogFileError::LogFileSet {
+                    source: Box::new(e.into()),
+                    location: location!(),
+                }
+            })
+    }
+
+    fn get(&self, key: &str) -> super::Result<Option<String>> {
+        self.loaded()
+            .and_then(|inner| inner.get(key))
+            .map_err(|e| LogFileError::LogFileGet {
+                source: Box::new(e.into()),
                 location: location!(),
             })
     }
 
-    fn get(&mut self, key: String) -> super::Result<Option<String>> {
-        self.inner.get(key).map_err(|e| LogFileError::LogFileGet {
-            source_str: format!("{}", e),
-            location: location!(),
-        })
+    fn get_with_ts(&self, key: &str) -> super::Result<Option<(String, Option<u64>)>> {
+        self.loaded()
+            .and_then(|inner| inner.get_with_ts(key))
+            .map_err(|e| LogFileError::LogFileGet {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
     }
 
-    fn remove(&mut self, key: String) -> super::Result<()> {
-        self.inner.remove(key).map_err(|e| LogFileError::LogFileRm {
-            source_str: format!("{}", e),
-            location: location!(),
-        })
+    fn copy_set(
+        &mut self,
+        key: String,
+        value: String,
+        ts: Option<u64>,
+        op_id: Option<String>,
+    ) -> super::Result<()> {
+        self.loaded_mut()
+            .and_then(|inner| inner.set_with_ts(key, value, op_id, ts))
+            .map_err(|e| {
+                if is_disk_full(&e) {
+                    return LogFileError::DiskFull {
+                        location: location!(),
+                        path: self.path.clone(),
+                    };
+                }
+                LogFileError::LogFileSet {
+                    source: Box::new(e.into()),
+                    location: location!(),
+                }
+            })
+    }
+
+    fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> super::Result<()> {
+        self.loaded_mut()
+            .and_then(|inner| inner.remove_with_op_id(key, op_id))
+            .map_err(|e| {
+                if is_disk_full(&e) {
+                    return LogFileError::DiskFull {
+                        location: location!(),
+                        path: self.path.clone(),
+                    };
+                }
+                LogFileError::LogFileRm {
+                    source: Box::new(e.into()),
+                    location: location!(),
+                }
+            })
+    }
+
+    fn tombstone_with_op_id(&mut self, key: String, op_id: Option<String>) -> super::Result<()> {
+        self.loaded_mut()
+            .and_then(|inner| inner.tombstone_with_op_id(key, op_id))
+            .map_err(|e| {
+                if is_disk_full(&e) {
+                    return LogFileError::DiskFull {
+                        location: location!(),
+                        path: self.path.clone(),
+                    };
+                }
+                LogFileError::LogFileRm {
+                    source: Box::new(e.into()),
+                    location: location!(),
+                }
+            })
     }
 
     fn scan(&mut self) -> super::Result<Vec<String>> {
-        self.inner.scan().map_err(|e| LogFileError::LogFileScan {
-            source_str: format!("{}", e),
-            location: location!(),
-        })
+        self.loaded_mut()
+            .and_then(|inner| inner.scan())
+            .map_err(|e| LogFileError::LogFileScan {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn scan_versions(&mut self, keep: usize) -> super::Result<Vec<String>> {
+        self.loaded_mut()
+            .and_then(|inner| inner.scan_versions(keep))
+            .map_err(|e| LogFileError::LogFileScan {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
     }
 
     fn len(&self) -> super::Result<u64> {
-        self.inner.len().map_err(|e| LogFileError::LogFileLen {
-            source_str: format!("{}", e),
+        let len = match self.inner.get() {
+            Some(inner) => inner.len(),
+            None => std::fs::metadata(&self.path)
+                .map(|metadata| metadata.len())
+                .context(QueryMetaDataSnafu),
+        };
+        len.map_err(|e| LogFileError::LogFileLen {
+            source: Box::new(e.into()),
             location: location!(),
         })
     }
 
     fn contains_key(&self, key: &str) -> bool {
-        self.inner.index.contains_key(key)
+        self.loaded_or_warn()
+            .is_some_and(|inner| inner.index.contains_key(key))
+    }
+
+    fn try_contains_key(&self, key: &str) -> super::Result<bool> {
+        self.loaded()
+            .map(|inner| inner.index.contains_key(key))
+            .map_err(|e| LogFileError::LogFileBuild {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn live_count(&self) -> usize {
+        self.loaded_or_warn().map_or(0, |inner| inner.live_count())
+    }
+
+    fn key_states(&self) -> Vec<(String, bool)> {
+        self.loaded_or_warn().map_or_else(Vec::new, |inner| {
+            inner
+                .index
+                .iter()
+                .map(|(key, entry)| (key.clone(), matches!(entry, IndexEntry::Exist { .. })))
+                .collect()
+        })
+    }
+
+    fn contains_op_id(&self, op_id: &str) -> bool {
+        self.loaded_or_warn()
+            .is_some_and(|inner| inner.op_ids.contains(op_id))
     }
 
     fn path(&self) -> PathBuf {
-        self.inner.path.clone()
+        self.path.clone()
+    }
+
+    fn mode(&self) -> &'static str {
+        "ptr"
+    }
+
+    /// a file not built yet opens the current one once built
+    fn is_current(&self) -> bool {
+        match self.inner.get() {
+            Some(inner) => inner
+                .file
+                .as_ref()
+                .is_some_and(|file| super::is_same_file(file, &self.path)),
+            None => true,
+        }
+    }
+
+    fn file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.path.clone()];
+        let snapshot_path = index_snapshot_path(&self.path);
+        if snapshot_path.exists() {
+            paths.push(snapshot_path);
+        }
+        paths
+    }
+
+    fn flush(&mut self) -> super::Result<()> {
+        match self.inner.get_mut() {
+            Some(inner) => inner.flush().map_err(|e| LogFileError::LogFileFlush {
+                source: Box::new(e.into()),
+                location: location!(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&mut self) -> super::Result<()> {
+        match self.inner.get_mut() {
+            Some(inner) => inner.snapshot().map_err(|e| LogFileError::LogFileSnapshot {
+                source: Box::new(e.into()),
+                location: location!(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn rebuild_index(&mut self, options: &LogFileOptions) -> super::Result<()> {
+        self.loaded_mut()
+            .and_then(|inner| inner.rebuild_index(options))
+            .map_err(|e| LogFileError::LogFileBuild {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
+    }
+
+    fn value_range(&self, key: &str) -> Option<ValueRange> {
+        let inner = self.loaded_or_warn()?;
+        // a compressed file has no range of its records on disk
+        if inner.records.is_some() {
+            return None;
+        }
+        match inner.index.get(key)? {
+            IndexEntry::Exist { offset, len } => Some(ValueRange {
+                path: self.path.clone(),
+                offset: *offset,
+                len: u64::from(*len),
+                encoded: true,
+            }),
+            IndexEntry::Removed(_) => None,
+        }
+    }
+
+    fn live_bytes(&self, key: &str) -> u64 {
+        match self.loaded_or_warn().and_then(|inner| inner.index.get(key)) {
+            Some(IndexEntry::Exist { len, .. }) => u64::from(*len),
+            _ => 0,
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        if let Some(inner) = self.inner.get_mut() {
+            inner.index.shrink_to_fit();
+        }
+    }
+
+    fn seal(&mut self) -> super::Result<()> {
+        self.sealed = true;
+        match self.inner.get_mut() {
+            Some(inner) => inner.seal().map_err(|e| LogFileError::LogFileSeal {
+                source: Box::new(e.into()),
+                location: location!(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// a file not built yet has served nothing, it's not built for it
+    fn stats(&self) -> LogFileStats {
+        match self.inner.get() {
+            Some(inner) => LogFileStats {
+                live_keys: inner.live_count() as u64,
+                ..inner.stats.get()
+            },
+            None => LogFileStats::default(),
+        }
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.loaded_or_warn().map_or(0, |inner| inner.last_seq)
+    }
+
+    fn advance_seq(&mut self, seq: u64) {
+        match self.loaded_mut() {
+            Ok(inner) => inner.last_seq = inner.last_seq.max(seq),
+            Err(e) => warn!("build log_file:{} failed: {}", self.path.display(), e),
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.inner.get().is_some()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = super::Result<LogItem>>> {
+        super::iter_log(&self.path, self.options.codec.clone())
     }
 }
 
+/// a snapshot of the older entries without the length is taken as broken,
+/// and the file is replayed
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum IndexEntry {
-    Exist(u64),
+    /// the record's offset and its bytes with the `\n`
+    Exist {
+        offset: u64,
+        len: u32,
+    },
     Removed(u64),
 }
 
 pub struct PtrLogFileInner {
     index: HashMap<String, IndexEntry>,
+    op_ids: RecentOpIds,
     file: Option<File>,
     path: PathBuf,
+    /// bytes of the file covered by the index snapshot on disk
+    snapshot_len: u64,
+    /// the highest sequence number written
+    last_seq: u64,
+    codec: Arc<dyn RecordCodec>,
+    /// stamps the written records
+    clock: Arc<dyn Clock>,
+    stats: StatsCounter,
+    /// the mapped file after sealed, read from it instead of the file
+    #[cfg(feature = "mmap")]
+    mmap: Option<memmap2::Mmap>,
+    /// the decompressed records of a compressed file, read from it
+    /// instead of the file
+    records: Option<Vec<u8>>,
     // mutable: bool,
 }
 
 impl PtrLogFileInner {
-    pub fn new(path: &Path) -> Result<PtrLogFileInner> {
+    pub fn new(path: &Path, options: &LogFileOptions) -> Result<PtrLogFileInner> {
         // process before to assert path exist
         if !path.exists() {
             return Err(Error::InvalidPath {
@@ -153,25 +510,43 @@ impl PtrLogFileInner {
         }
 
         // init cache
-        let index = build_index(path)?;
+        let (index, op_ids, snapshot_len, last_seq) = build_index(path, options)?;
+
+        // a compressed file is never written, read it from memory
+        let records = if super::is_compressed(path) {
+            let mut records = Vec::new();
+            let _ = super::open_records(path, options.read_buf_size)
+                .and_then(|mut reader| reader.read_to_end(&mut records))
+                .context(ReadFileSnafu)?;
+            Some(records)
+        } else {
+            None
+        };
 
         // open file
         info!("open log_file:{} for writing", path.display());
         let file = File::options()
             .read(true)
-            .append(true)
+            .append(records.is_none() && !options.read_only)
             .open(path)
             .context(OpenFileSnafu { path })?;
         Ok(PtrLogFileInner {
             index,
+            op_ids,
             file: Some(file),
             path: path.to_path_buf(),
+            snapshot_len,
+            last_seq,
+            codec: options.codec.clone(),
+            clock: options.clock.clone(),
+            stats: StatsCounter::new(options.write_amp.clone()),
+            #[cfg(feature = "mmap")]
+            mmap: None,
+            records,
         })
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        debug!("set key:{} value:{} in ptr_index_log_file", key, value);
-
+    fn check_writable(&self) -> Result<()> {
         if self.file.is_none() {
             return Err(Error::EmptyFile {
                 location: location!(),
@@ -179,24 +554,217 @@ impl PtrLogFileInner {
             });
         }
 
-        // get cursor first
+        if self.records.is_some() {
+            return Err(Error::WriteCompressed {
+                location: location!(),
+                path: self.path.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self.file.as_ref() {
+            Some(file) => {
+                file.sync_data().context(ReadFileSnafu)?;
+                self.stats.record_sync();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// write the index to `data_n.index` by renaming a temp file,
+    /// skipped if the file is not appended since the last snapshot
+    pub fn snapshot(&mut self) -> Result<()> {
+        let metadata = match self.file.as_ref() {
+            Some(_) if self.records.is_some() => return Ok(()),
+            Some(file) => file.metadata().context(QueryMetaDataSnafu)?,
+            None => return Ok(()),
+        };
+        if metadata.len() == self.snapshot_len {
+            return Ok(());
+        }
+
+        let snapshot = IndexSnapshot {
+            len: metadata.len(),
+            file_id: super::file_id(&metadata),
+            index: &self.index,
+            op_ids: self.op_ids.iter().collect(),
+            last_seq: self.last_seq,
+        };
+        let snapshot_path = index_snapshot_path(&self.path);
+        let mut tmp_path = snapshot_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let json_str = serde_json::to_string(&snapshot).context(EncodeSnapshotSnafu {
+            path: snapshot_path.clone(),
+        })?;
+        std::fs::write(&tmp_path, json_str)
+            .and_then(|_| std::fs::rename(&tmp_path, &snapshot_path))
+            .context(WriteSnapshotSnafu {
+                path: snapshot_path.clone(),
+            })?;
+
+        debug!(
+            "snapshot index of log_file:{} at len:{}",
+            self.path.display(),
+            metadata.len()
+        );
+        self.snapshot_len = metadata.len();
+        Ok(())
+    }
+
+    /// replay the whole file, the snapshot is removed first as it may
+    /// be the broken one
+    pub fn rebuild_index(&mut self, options: &LogFileOptions) -> Result<()> {
+        warn!("rebuild index of log_file:{}", self.path.display());
+        let snapshot_path = index_snapshot_path(&self.path);
+        if snapshot_path.exists() {
+            std::fs::remove_file(&snapshot_path).context(WriteSnapshotSnafu {
+                path: snapshot_path,
+            })?;
+        }
+
+        let (index, op_ids, snapshot_len, last_seq) = build_index(&self.path, options)?;
+        self.index = index;
+        self.op_ids = op_ids;
+        self.snapshot_len = snapshot_len;
+        self.last_seq = self.last_seq.max(last_seq);
+        Ok(())
+    }
+
+    /// map the file to read the immutable records from memory
+    #[cfg(feature = "mmap")]
+    pub fn seal(&mut self) -> Result<()> {
+        let file = match self.file.as_ref() {
+            Some(_) if self.records.is_some() => return Ok(()),
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        // mapping an empty file is not allowed on some platforms
+        if file.metadata().context(QueryMetaDataSnafu)?.len() == 0 {
+            return Ok(());
+        }
+
+        info!("seal log_file:{} by mmap", self.path.display());
+        // Safety: a sealed file is never written or truncated in place,
+        // compaction replaces it after this log file is dropped
+        let mmap = unsafe { memmap2::Mmap::map(file) }.context(MapFileSnafu {
+            path: self.path.clone(),
+        })?;
+        self.mmap = Some(mmap);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    pub fn seal(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// read the record at the cursor from the decompressed records,
+    /// the mapped region if sealed, or from the file
+    fn read_log(&self, cursor: u64) -> Result<String> {
+        if let Some(records) = self.records.as_ref() {
+            return super::read_line_in(records, cursor).context(ReadFileSnafu);
+        }
+
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = self.mmap.as_ref() {
+            return super::read_line_in(mmap, cursor).context(ReadFileSnafu);
+        }
+
+        super::read_line_at(self.file.as_ref().unwrap(), cursor).context(ReadFileSnafu)
+    }
+
+    /// read the record of the known length, no need to look for its end
+    fn read_record(&self, cursor: u64, len: u32) -> Result<String> {
+        if let Some(records) = self.records.as_ref() {
+            return super::read_exact_in(records, cursor, len).context(ReadFileSnafu);
+        }
+
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = self.mmap.as_ref() {
+            return super::read_exact_in(mmap, cursor, len).context(ReadFileSnafu);
+        }
+
+        super::read_record_at(self.file.as_ref().unwrap(), cursor, len).context(ReadFileSnafu)
+    }
+
+    #[allow(unused)]
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_with_op_id(key, value, None)
+    }
+
+    pub fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> Result<()> {
+        let ts = super::now_millis(&*self.clock);
+        self.set_with_ts(key, value, op_id, ts)
+    }
+
+    /// set stamped by the given timestamp rather than now
+    pub fn set_with_ts(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+        ts: Option<u64>,
+    ) -> Result<()> {
+        debug!("set key:{} value:{} in ptr_index_log_file", key, value);
+
+        self.check_writable()?;
+
+        if self.is_applied(op_id.as_deref()) {
+            info!("set key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
+
+        // get cursor first, the appending starts from the end
         let new_cursor = self
             .file
-            .as_ref()
+            .as_mut()
             .unwrap()
-            .stream_position()
+            .seek(SeekFrom::End(0))
             .context(SeekFileSnafu)?;
 
         // update file
-        let item = LogItem::new("set".to_owned(), key, Some(value));
-        write_disk(self.file.as_mut().unwrap(), item.clone()).context(RecordLogSnafu{caller: "PtrLogFile::set".to_owned()})?;
+        let item = LogItem::new("set".to_owned(), key, Some(value))
+            .with_op_id(op_id)
+            .with_seq(self.last_seq + 1)
+            .with_ts(ts);
+        let bytes = write_disk(self.file.as_mut().unwrap(), &*self.codec, item.clone())
+            .context(RecordLogSnafu {
+                caller: "PtrLogFile::set".to_owned(),
+            })?;
+        self.stats.record_write(bytes);
+        self.last_seq += 1;
 
         // update index
-        let _ = self.index.insert(item.key, IndexEntry::Exist(new_cursor));
+        let len = record_len(new_cursor, bytes as u64)?;
+        let _ = self.index.insert(
+            item.key,
+            IndexEntry::Exist {
+                offset: new_cursor,
+                len,
+            },
+        );
+        if let Some(op_id) = item.op_id {
+            let _ = self.op_ids.insert(op_id);
+        }
         Ok(())
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_with_ts(key)
+            .map(|value| value.map(|(value, _)| value))
+    }
+
+    /// the value with the timestamp of its record
+    pub fn get_with_ts(&self, key: &str) -> Result<Option<(String, Option<u64>)>> {
         debug!("get key:{} in ptr_index_log_file", key);
 
         if self.file.is_none() {
@@ -207,9 +775,9 @@ impl PtrLogFileInner {
         }
 
         // get cursor
-        let cursor = if let Some(entry) = self.index.get(&key) {
+        let (cursor, len) = if let Some(entry) = self.index.get(key) {
             match entry {
-                IndexEntry::Exist(c) => *c,
+                IndexEntry::Exist { offset, len } => (*offset, *len),
                 IndexEntry::Removed(_) => return Ok(None),
             }
         } else {
@@ -217,15 +785,9 @@ impl PtrLogFileInner {
         };
 
         // get log from file by cursor
-        let _ = self
-            .file
-            .as_mut()
-            .unwrap()
-            .seek(std::io::SeekFrom::Start(cursor))
-            .context(SeekFileSnafu)?;
-        let mut buf_file = BufReader::new(self.file.as_mut().unwrap());
-        let mut log_str = String::new();
-        if buf_file.read_line(&mut log_str).context(ReadFileSnafu)? == 0 {
+        self.stats.record_read();
+        let log_str = self.read_record(cursor, len)?;
+        if log_str.is_empty() {
             return Err(Error::Unexpected {
                 location: location!(),
                 dscr: "read line and get eof".to_owned(),
@@ -233,7 +795,22 @@ impl PtrLogFileInner {
         }
 
         // decode log
-        let item = LogEncoder::decode(&log_str).context(DecodeLogSnafu{ caller: "get", json_str: log_str.clone() })?;
+        let line = log_str.strip_suffix('\n').unwrap_or(&log_str);
+        let item = log_item::decode_record(&*self.codec, line).context(DecodeLogSnafu {
+            caller: "get",
+            json_str: log_str.clone(),
+        })?;
+
+        // a broken index may point to another record
+        if item.key != key {
+            return Err(Error::Unexpected {
+                location: location!(),
+                dscr: format!(
+                    "log at cursor {} is not of key {}: {}",
+                    cursor, key, log_str
+                ),
+            });
+        }
 
         match item.value {
             None => Err(Error::Unexpected {
@@ -245,43 +822,64 @@ impl PtrLogFileInner {
                     "get value:{} from key:{} in ptr_index_log_file",
                     item.key, v
                 );
-                Ok(Some(v))
+                Ok(Some((v, item.ts)))
             }
         }
     }
 
+    #[allow(unused)]
     pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_with_op_id(key, None)
+    }
+
+    pub fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
         debug!("rm key:{} in ptr_index_log_file", key);
 
-        if self.file.is_none() {
-            return Err(Error::EmptyFile {
+        let exists = matches!(self.index.get(&key), Some(IndexEntry::Exist { .. }));
+        if !exists && !self.is_applied(op_id.as_deref()) {
+            return Err(Error::RemoveNotExistKey {
                 location: location!(),
-                path: self.path.clone(),
+                key,
             });
         }
+        self.tombstone_with_op_id(key, op_id)
+    }
+
+    /// append a tombstone whether the key is in this file or not,
+    /// it hides the value held by the older files
+    pub fn tombstone_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
+        self.check_writable()?;
+
+        if self.is_applied(op_id.as_deref()) {
+            info!("rm key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
 
         // update file
-        let item = LogItem::new("rm".to_owned(), key, None);
+        let item = LogItem::new("rm".to_owned(), key, None)
+            .with_op_id(op_id)
+            .with_seq(self.last_seq + 1)
+            .with_ts(super::now_millis(&*self.clock));
 
-        if (self.index.contains_key(&item.key))
-            && matches!(self.index.get(&item.key).unwrap(), IndexEntry::Exist(_))
-        {
-            let new_cursor = self
-                .file
-                .as_ref()
-                .unwrap()
-                .stream_position()
-                .context(SeekFileSnafu)?;
-            write_disk(self.file.as_mut().unwrap(), item.clone()).context(RecordLogSnafu{caller: "PtrLogFile::remove".to_owned()})?;
-            // update index
-            let _ = self.index.insert(item.key, IndexEntry::Removed(new_cursor));
-            Ok(())
-        } else {
-            Err(Error::RemoveNotExistKey {
-                location: location!(),
-                key: item.key,
-            })
+        let new_cursor = self
+            .file
+            .as_mut()
+            .unwrap()
+            .seek(SeekFrom::End(0))
+            .context(SeekFileSnafu)?;
+        let bytes = write_disk(self.file.as_mut().unwrap(), &*self.codec, item.clone()).context(
+            RecordLogSnafu {
+                caller: "PtrLogFile::remove".to_owned(),
+            },
+        )?;
+        self.stats.record_write(bytes);
+        self.last_seq += 1;
+        // update index
+        let _ = self.index.insert(item.key, IndexEntry::Removed(new_cursor));
+        if let Some(op_id) = item.op_id {
+            let _ = self.op_ids.insert(op_id);
         }
+        Ok(())
     }
 
     pub fn scan(&mut self) -> Result<Vec<String>> {
@@ -294,19 +892,24 @@ impl PtrLogFileInner {
             });
         }
 
-        let offsets: Vec<_> = self.index.iter().map(|(_, v)| v).collect();
+        // in the written order, not the index's
+        let mut offsets: Vec<_> = self
+            .index
+            .values()
+            .map(|entry| match entry {
+                IndexEntry::Exist { offset, len } => (*offset, Some(*len)),
+                IndexEntry::Removed(offset) => (*offset, None),
+            })
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup();
         let mut cmds = Vec::with_capacity(offsets.len());
-        let mut fin = BufReader::new(self.file.as_mut().unwrap());
-
-        for offset in offsets {
-            let mut line = String::new();
-            let offset = match offset {
-                IndexEntry::Exist(o) => *o,
-                IndexEntry::Removed(o) => *o,
+        for (offset, len) in offsets {
+            let line = match len {
+                Some(len) => self.read_record(offset, len)?,
+                None => self.read_log(offset)?,
             };
-            let _ = fin.seek(SeekFrom::Start(offset)).context(SeekFileSnafu)?;
-            let bytes = fin.read_line(&mut line).context(ReadFileSnafu)?;
-            if bytes == 0 {
+            if line.is_empty() {
                 return Err(Error::Unexpected {
                     location: location!(),
                     dscr: "scan file and get eof".to_owned(),
@@ -319,6 +922,66 @@ impl PtrLogFileInner {
         Ok(cmds)
     }
 
+    /// the newest `keep` sets of each key after its last remove, in the
+    /// written order. they are led by the remove if any, as an older
+    /// file may hold the key
+    pub fn scan_versions(&mut self, keep: usize) -> Result<Vec<String>> {
+        if keep <= 1 {
+            return self.scan();
+        }
+        info!("scan {} versions in ptr_index_log_file", keep);
+
+        if self.file.is_none() {
+            return Err(Error::EmptyFile {
+                location: location!(),
+                path: self.path.clone(),
+            });
+        }
+
+        let mut iter = LogIter::open(&self.path, self.codec.clone())
+            .context(IterLogSnafu { caller: "scan" })?;
+        let mut versions: HashMap<String, Vec<(u64, bool)>> = HashMap::new();
+        while let Some(item) = iter.next() {
+            let item = item.context(IterLogSnafu { caller: "scan" })?;
+            let removed = match item.cmd.as_str() {
+                "set" => false,
+                "rm" => true,
+                _ => continue,
+            };
+            let records = versions.entry(item.key).or_default();
+            if removed {
+                records.clear();
+            }
+            records.push((iter.offset(), removed));
+            // the remove leading them is kept apart from the sets
+            let leading = records.first().is_some_and(|(_, removed)| *removed) as usize;
+            if records.len() > keep + leading {
+                let _ = records.remove(leading);
+            }
+        }
+
+        let mut offsets: Vec<_> = versions
+            .into_values()
+            .flatten()
+            .map(|(offset, _)| offset)
+            .collect();
+        offsets.sort_unstable();
+        let mut cmds = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            cmds.push(self.read_log(offset)?);
+        }
+
+        Ok(cmds)
+    }
+
+    /// counted from the index, the removed keys are kept as tombstones
+    pub fn live_count(&self) -> usize {
+        self.index
+            .values()
+            .filter(|entry| matches!(entry, IndexEntry::Exist { .. }))
+            .count()
+    }
+
     pub fn len(&self) -> Result<u64> {
         if self.file.is_none() {
             return Err(Error::EmptyFile {
@@ -335,80 +998,419 @@ impl PtrLogFileInner {
             .context(QueryMetaDataSnafu)?
             .len())
     }
+
+    fn is_applied(&self, op_id: Option<&str>) -> bool {
+        op_id.is_some_and(|op_id| self.op_ids.contains(op_id))
+    }
 }
 
-fn build_index(path: impl AsRef<Path>) -> Result<HashMap<String, IndexEntry>> {
+/// the index of the first `len` bytes of a log file
+#[derive(Serialize)]
+struct IndexSnapshot<'a> {
+    len: u64,
+    /// a snapshot of a replaced file with the same name is stale
+    file_id: Option<u64>,
+    index: &'a HashMap<String, IndexEntry>,
+    /// from the oldest to the newest
+    op_ids: Vec<&'a String>,
+    last_seq: u64,
+}
+
+#[derive(Deserialize)]
+struct LoadedIndexSnapshot {
+    len: u64,
+    file_id: Option<u64>,
+    index: HashMap<String, IndexEntry>,
+    op_ids: Vec<String>,
+    /// missing in the snapshots written before the sequence numbers
+    #[serde(default)]
+    last_seq: u64,
+}
+
+/// the index snapshot of `data_n` is `data_n.index`
+fn index_snapshot_path(path: &Path) -> PathBuf {
+    let mut snapshot_path = path.as_os_str().to_owned();
+    snapshot_path.push(".index");
+    snapshot_path.into()
+}
+
+/// load the snapshot if it is still valid for the file,
+/// any failure just falls back to replaying the whole file
+fn load_index_snapshot(path: &Path, metadata: &std::fs::Metadata) -> Option<LoadedIndexSnapshot> {
+    let snapshot_path = index_snapshot_path(path);
+    let json_str = std::fs::read_to_string(&snapshot_path).ok()?;
+    let snapshot: LoadedIndexSnapshot = match serde_json::from_str(&json_str) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!(
+                "ignore broken index snapshot:{}, e:{}",
+                snapshot_path.display(),
+                e
+            );
+            return None;
+        }
+    };
+
+    if snapshot.len > metadata.len() || snapshot.file_id != super::file_id(metadata) {
+        info!("ignore stale index snapshot:{}", snapshot_path.display());
+        return None;
+    }
+
+    Some(snapshot)
+}
+
+fn build_index(
+    path: impl AsRef<Path>,
+    options: &LogFileOptions,
+) -> Result<(HashMap<String, IndexEntry>, RecentOpIds, u64, u64)> {
     let path = path.as_ref();
     info!("build_index from file:{}", path.display());
 
-    let mut fin = BufReader::new(File::open(path).context(OpenFileSnafu { path })?);
-    let mut index = HashMap::new();
-    let mut next_cursor = fin.stream_position().context(SeekFileSnafu)?;
-    loop {
-        let mut line = String::new();
-        let bytes = fin.read_line(&mut line).context(ReadFileSnafu)?;
+    let file = File::open(path).context(OpenFileSnafu { path })?;
+    let metadata = file.metadata().context(QueryMetaDataSnafu)?;
 
-        if bytes == 0 {
-            info!("scan log_file:{} finish", path.display());
-            break;
+    // start from the snapshot, only replay the records after it,
+    // a compressed file has no snapshot
+    let compressed = super::is_compressed(path);
+    let snapshot = if compressed {
+        None
+    } else {
+        load_index_snapshot(path, &metadata)
+    };
+    let (mut index, mut op_ids, snapshot_len, mut last_seq) = match snapshot {
+        Some(snapshot) => {
+            let mut op_ids = RecentOpIds::default();
+            for op_id in snapshot.op_ids {
+                let _ = op_ids.insert(op_id);
+            }
+            (snapshot.index, op_ids, snapshot.len, snapshot.last_seq)
+        }
+        None => (
+            HashMap::with_capacity(options.index_capacity(metadata.len())),
+            RecentOpIds::default(),
+            0,
+            0,
+        ),
+    };
+    let reader: Box<dyn BufRead> = if compressed {
+        super::open_records(path, options.read_buf_size).context(OpenFileSnafu { path })?
+    } else {
+        let mut reader = super::buf_reader(file, options.read_buf_size);
+        let _ = reader
+            .seek(SeekFrom::Start(snapshot_len))
+            .context(SeekFileSnafu)?;
+        Box::new(reader)
+    };
+    let mut iter = LogIter::with_offset(reader, snapshot_len, options.codec.clone())
+        .with_max_len(options.max_record_len);
+    let mut replayed = 0_usize;
+    while let Some(item) = iter.next() {
+        let item = item.context(IterLogSnafu { caller: "open" })?;
+        let next_cursor = iter.offset();
+        replayed += 1;
+        last_seq = last_seq.max(item.seq.unwrap_or(0));
+
+        // a duplicated op has been applied before, replay it as a no-op
+        if let Some(op_id) = &item.op_id {
+            if !op_ids.insert(op_id.clone()) {
+                continue;
+            }
         }
 
-        let item = LogEncoder::decode(&line).context(DecodeLogSnafu{ json_str: line, caller: "open"})?;
         match item.cmd.as_str() {
             "set" => {
                 // todo check log valid by reg
-                let _ = index.insert(item.key.clone(), IndexEntry::Exist(next_cursor));
+                let len = record_len(next_cursor, iter.record_len())?;
+                let _ = index.insert(
+                    item.key.clone(),
+                    IndexEntry::Exist {
+                        offset: next_cursor,
+                        len,
+                    },
+                );
             }
             "rm" => {
                 let _ = index.insert(item.key.clone(), IndexEntry::Removed(next_cursor));
             }
-            _ => {
-                return Err(Error::UnknownCmd {
-                    location: location!(),
-                    item: item.clone(),
-                });
-            }
+            _ => match options.unknown_cmd {
+                UnknownCmdPolicy::Strict => {
+                    return Err(Error::UnknownCmd {
+                        location: location!(),
+                        item: Box::new(item.clone()),
+                    });
+                }
+                UnknownCmdPolicy::Skip => {
+                    warn!("skip unknown log {:?} in file:{}", item, path.display());
+                }
+            },
+        }
+    }
+    info!(
+        "scan log_file:{} finish, replayed {} records from {}",
+        path.display(),
+        replayed,
+        snapshot_len
+    );
+
+    // only reports, the records covered by a snapshot may be unreadable
+    if options.verify_index {
+        match verify_index(path, &index, options) {
+            Ok(Some((indexed, scanned))) => warn!(
+                "index of log_file:{} has {} live keys, but {} in the file",
+                path.display(),
+                indexed,
+                scanned
+            ),
+            Ok(None) => {}
+            Err(e) => warn!("skip verifying index of log_file:{}: {}", path.display(), e),
         }
+    }
 
-        // update cursor
-        next_cursor = fin.stream_position().context(SeekFileSnafu)?;
+    Ok((index, op_ids, snapshot_len, last_seq))
+}
+
+/// replay the whole file without the snapshot, return the live keys
+/// counted by the index and by the file if they are mismatched
+fn verify_index(
+    path: &Path,
+    index: &HashMap<String, IndexEntry>,
+    options: &LogFileOptions,
+) -> Result<Option<(usize, usize)>> {
+    let reader =
+        super::open_records(path, options.read_buf_size).context(OpenFileSnafu { path })?;
+    let iter = LogIter::new(reader, options.codec.clone()).with_max_len(options.max_record_len);
+    let mut op_ids = RecentOpIds::default();
+    let mut live = HashMap::new();
+    for item in iter {
+        let item = item.context(IterLogSnafu { caller: "verify" })?;
+        if let Some(op_id) = &item.op_id {
+            if !op_ids.insert(op_id.clone()) {
+                continue;
+            }
+        }
+        match item.cmd.as_str() {
+            "set" => {
+                let _ = live.insert(item.key, true);
+            }
+            "rm" => {
+                let _ = live.insert(item.key, false);
+            }
+            _ => {}
+        }
     }
 
-    Ok(index)
+    let indexed = index
+        .values()
+        .filter(|entry| matches!(entry, IndexEntry::Exist { .. }))
+        .count();
+    let scanned = live.values().filter(|exist| **exist).count();
+    Ok((indexed != scanned).then_some((indexed, scanned)))
+}
+
+/// the length kept in the index, a longer record can't be indexed
+fn record_len(offset: u64, len: u64) -> Result<u32> {
+    u32::try_from(len).map_err(|_| Error::Unexpected {
+        location: location!(),
+        dscr: format!("record at {} of {} bytes is too long to index", offset, len),
+    })
 }
 
 #[derive(Debug, Snafu)]
 pub enum WriteDiskError {
     #[snafu(display("{} encode {:?}: {} before write disk", location, item, source))]
-    EncodeLog { source:log_item::Error, location: Location, item: LogItem },
+    EncodeLog {
+        #[snafu(source(from(log_item::Error, Box::new)))]
+        source: Box<log_item::Error>,
+        location: Location,
+        item: Box<LogItem>,
+    },
 
     #[snafu(display("{} write {} to disk: {}", location, json_str, source))]
     WriteFile { source:std::io::Error, location: Location, json_str: String },
+
+    #[snafu(display("{} write to disk: the disk is full", location))]
+    DiskFull { location: Location },
 }
 
 
-fn write_disk(fout: &mut File, item: LogItem) -> Result<(), WriteDiskError> {
-    let json_str = LogEncoder::encode(&item).context(EncodeLogSnafu{item})? + "\n";
-    fout.write_all(json_str.as_bytes())
-        .context(WriteFileSnafu { json_str })?;
+/// the file appended by the log, which can be cut back
+trait AppendFile: Write {
+    fn end(&mut self) -> std::io::Result<u64>;
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()>;
+}
+
+impl AppendFile for File {
+    fn end(&mut self) -> std::io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+/// return the bytes written, a record failed by a full disk is
+/// truncated, so the log won't be left with a partial line
+fn write_disk(
+    fout: &mut impl AppendFile,
+    codec: &dyn RecordCodec,
+    item: LogItem,
+) -> Result<usize, WriteDiskError> {
+    let json_str = log_item::encode_record(codec, &item).context(EncodeLogSnafu { item })? + "\n";
+    let start = fout.end().context(WriteFileSnafu {
+        json_str: json_str.clone(),
+    })?;
+    match fout.write_all(json_str.as_bytes()) {
+        Ok(()) => Ok(json_str.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+            fout.truncate(start).context(WriteFileSnafu { json_str })?;
+            DiskFullSnafu.fail()
+        }
+        Err(e) => Err(e).context(WriteFileSnafu { json_str }),
+    }
+}
 
-    Ok(())
+/// a full disk is told from the other failures of a write
+fn is_disk_full(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::RecordLog {
+            source: WriteDiskError::DiskFull { .. },
+            ..
+        }
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
         fs::File,
-        io::{BufRead, BufReader},
+        io::{BufRead, BufReader, Seek, SeekFrom},
     };
 
     // use assert_cmd::assert;
-    use super::{write_disk, LogEncoder, LogItem, PtrLogFileInner};
+    use super::{
+        build_index, index_snapshot_path, verify_index, write_disk, AppendFile, IndexEntry,
+        LogItem, PtrLogFile, PtrLogFileInner, WriteDiskError,
+    };
+    use crate::log_file::log_item::LogEncoder;
+    use crate::log_file::{LogFile, LogFileOptions, UnknownCmdPolicy};
+
+    #[test]
+    fn set_error_source() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFile::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        // a handle only opened for reading fails the writes
+        test_log_file.loaded_mut().unwrap().file = Some(File::open(test_file.path()).unwrap());
+
+        let e = test_log_file
+            .set_with_op_id("key1".to_owned(), "value1".to_owned(), None)
+            .unwrap_err();
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+        let mut io_error = None;
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<std::io::Error>() {
+                io_error = Some(e);
+            }
+            source = e.source();
+        }
+        assert!(io_error.unwrap().raw_os_error().is_some());
+    }
+
+    #[test]
+    fn index_record_len() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        let values = [
+            "",
+            "value1",
+            "a longer value with spaces",
+            "\u{4f60}\u{597d}",
+        ];
+        for (i, value) in values.iter().enumerate() {
+            test_log_file
+                .set(format!("key{}", i), value.to_string())
+                .unwrap();
+        }
+        test_log_file.remove("key0".to_owned()).unwrap();
+        test_log_file
+            .set("key0".to_owned(), "value0".to_owned())
+            .unwrap();
+
+        // the length is the record's line, from a write or a replay
+        let check = |test_log_file: &PtrLogFileInner| {
+            let content = std::fs::read(test_file.path()).unwrap();
+            for (key, entry) in test_log_file.index.iter() {
+                if let IndexEntry::Exist { offset, len } = entry {
+                    let start = *offset as usize;
+                    let line_len = content[start..].iter().position(|b| *b == b'\n').unwrap() + 1;
+                    assert_eq!(*len as usize, line_len, "key {}", key);
+                }
+            }
+            assert_eq!(
+                test_log_file.get("key0").unwrap(),
+                Some("value0".to_owned())
+            );
+            for (i, value) in values.iter().enumerate().skip(1) {
+                assert_eq!(
+                    test_log_file.get(&format!("key{}", i)).unwrap(),
+                    Some(value.to_string())
+                );
+            }
+        };
+        check(&test_log_file);
+        drop(test_log_file);
+        check(&PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap());
+    }
+
+    #[test]
+    fn append_offset() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+
+        // the reads and a moved cursor between the writes don't shift
+        // the offsets, the appending always starts from the end
+        for i in 0..100 {
+            test_log_file
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            if i % 3 == 2 {
+                test_log_file.remove(format!("key{}", i / 2)).unwrap();
+            }
+            assert_eq!(
+                test_log_file.get(&format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i))
+            );
+            test_log_file
+                .file
+                .as_mut()
+                .unwrap()
+                .seek(SeekFrom::Start(0))
+                .unwrap();
+        }
+
+        let content = std::fs::read(test_file.path()).unwrap();
+        for (key, entry) in test_log_file.index.iter() {
+            let (offset, cmd) = match entry {
+                IndexEntry::Exist { offset, .. } => (*offset, "set"),
+                IndexEntry::Removed(offset) => (*offset, "rm"),
+            };
+            let line = content[offset as usize..].split(|b| *b == b'\n').next();
+            let item = LogEncoder::decode(std::str::from_utf8(line.unwrap()).unwrap()).unwrap();
+            assert_eq!(&item.key, key);
+            assert_eq!(item.cmd, cmd);
+        }
+    }
 
     #[test]
     fn crud() {
         let test_file = tempfile::NamedTempFile::new().unwrap();
-        let mut test_log_file = PtrLogFileInner::new(test_file.path()).unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
 
         // set
         let kv1 = ("key1".to_owned(), "value1".to_owned());
@@ -419,9 +1421,9 @@ mod tests {
         test_log_file.set(kv3.0.clone(), kv3.1.clone()).unwrap();
 
         // get
-        let res1 = test_log_file.get(kv1.0.clone());
-        let res2 = test_log_file.get(kv2.0.clone());
-        let res3 = test_log_file.get(kv3.0.clone());
+        let res1 = test_log_file.get(&kv1.0);
+        let res2 = test_log_file.get(&kv2.0);
+        let res3 = test_log_file.get(&kv3.0);
         assert!(res1.is_ok());
         assert!(res2.is_ok());
         assert!(res3.is_ok());
@@ -438,16 +1440,18 @@ mod tests {
         // rm
         let res3 = test_log_file.remove(kv3.0.clone());
         assert!(res3.is_ok());
-        let res3 = test_log_file.get(kv3.0.clone());
+        let res3 = test_log_file.get(&kv3.0);
         assert!(res3.is_ok());
         assert!(res3.unwrap().is_none());
+        assert!(test_log_file.remove(kv3.0.clone()).is_err());
 
         // reopen to check replay
         drop(test_log_file);
-        let mut test_log_file = PtrLogFileInner::new(test_file.path()).unwrap();
-        let res1 = test_log_file.get(kv1.0.clone());
-        let res2 = test_log_file.get(kv2.0.clone());
-        let res3 = test_log_file.get(kv3.0.clone());
+        let test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        let res1 = test_log_file.get(&kv1.0);
+        let res2 = test_log_file.get(&kv2.0);
+        let res3 = test_log_file.get(&kv3.0);
         assert!(res1.is_ok());
         assert!(res2.is_ok());
         assert!(res3.is_ok());
@@ -462,6 +1466,61 @@ mod tests {
         assert_eq!(res2.unwrap(), kv2.1);
     }
 
+    /// a disk which is full after `cap` bytes
+    struct FullDisk {
+        buf: Vec<u8>,
+        cap: usize,
+    }
+
+    impl std::io::Write for FullDisk {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            let n = data.len().min(self.cap - self.buf.len());
+            if n == 0 {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            self.buf.extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AppendFile for FullDisk {
+        fn end(&mut self) -> std::io::Result<u64> {
+            Ok(self.buf.len() as u64)
+        }
+
+        fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+            self.buf.truncate(len as usize);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_disk_full() {
+        let item = LogItem::new(
+            "set".to_owned(),
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+        );
+        let len = LogEncoder::encode(&item).unwrap().len() + 1;
+        let mut disk = FullDisk {
+            buf: Vec::new(),
+            cap: len * 3 / 2,
+        };
+        let bytes = write_disk(&mut disk, &LogEncoder, item.clone()).unwrap();
+        assert_eq!(bytes, len);
+
+        // the partial record is cut back
+        assert!(matches!(
+            write_disk(&mut disk, &LogEncoder, item),
+            Err(WriteDiskError::DiskFull { .. })
+        ));
+        assert_eq!(disk.buf.len(), bytes);
+    }
+
     #[test]
     fn test_write_disk() {
         // test file
@@ -479,8 +1538,8 @@ mod tests {
             "key2".to_owned(),
             Some("value2".to_owned()),
         );
-        let res1 = write_disk(&mut test_file_obj, test_log1.clone());
-        let res2 = write_disk(&mut test_file_obj, test_log2.clone());
+        let res1 = write_disk(&mut test_file_obj, &LogEncoder, test_log1.clone());
+        let res2 = write_disk(&mut test_file_obj, &LogEncoder, test_log2.clone());
         assert!(res1.is_ok());
         assert!(res2.is_ok());
         drop(test_file_obj);
@@ -496,8 +1555,251 @@ mod tests {
         assert_eq!(log_strs[1], test_log2);
     }
 
+    #[test]
+    fn unknown_cmd() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        test_log_file
+            .set("key1".to_owned(), "value1".to_owned())
+            .unwrap();
+        drop(test_log_file);
+
+        // inject an unknown cmd written by a newer version
+        let unknown = LogItem::new("expire".to_owned(), "key1".to_owned(), None);
+        let mut test_file_obj = File::options().append(true).open(test_file.path()).unwrap();
+        write_disk(&mut test_file_obj, &LogEncoder, unknown).unwrap();
+        let kv2 = LogItem::new(
+            "set".to_owned(),
+            "key2".to_owned(),
+            Some("value2".to_owned()),
+        );
+        write_disk(&mut test_file_obj, &LogEncoder, kv2).unwrap();
+        drop(test_file_obj);
+
+        // strict
+        assert!(PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).is_err());
+
+        // skip
+        let options = LogFileOptions {
+            unknown_cmd: UnknownCmdPolicy::Skip,
+            ..Default::default()
+        };
+        let test_log_file = PtrLogFileInner::new(test_file.path(), &options).unwrap();
+        assert_eq!(test_log_file.get("key1").unwrap().unwrap(), "value1");
+        assert_eq!(test_log_file.get("key2").unwrap().unwrap(), "value2");
+    }
+
+    #[test]
+    fn index_capacity_hint() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        for i in 0..10000_u32 {
+            test_log_file
+                .set(format!("key{}", i), i.to_string())
+                .unwrap();
+        }
+        drop(test_log_file);
+
+        // the preallocated index is built without growing
+        let (hinted, _, _, _) = build_index(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert!(hinted.capacity() >= 10000);
+
+        let options = LogFileOptions {
+            avg_record_size: 0,
+            ..Default::default()
+        };
+        let (unhinted, _, _, _) = build_index(test_file.path(), &options).unwrap();
+        assert_eq!(hinted, unhinted);
+    }
+
+    #[test]
+    fn read_buf_size() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        // records of many lengths, so they straddle the buffers
+        for i in 0..10000_usize {
+            test_log_file
+                .set(format!("key{}", i % 5000), "v".repeat(i % 300))
+                .unwrap();
+            if i % 7 == 0 {
+                test_log_file.remove(format!("key{}", i % 5000)).unwrap();
+            }
+        }
+        drop(test_log_file);
+        assert!(!index_snapshot_path(test_file.path()).exists());
+
+        // the index is the same whatever the buffer
+        let (index, _, _, last_seq) =
+            build_index(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert_eq!(index.len(), 5000);
+        for read_buf_size in [0, 7, 100, 8 * 1024, 4 * 1024 * 1024] {
+            let options = LogFileOptions {
+                read_buf_size,
+                ..Default::default()
+            };
+            let (other, _, _, other_seq) = build_index(test_file.path(), &options).unwrap();
+            assert_eq!(other, index, "read_buf_size {}", read_buf_size);
+            assert_eq!(other_seq, last_seq);
+        }
+    }
+
+    #[test]
+    fn verify_index_mismatch() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        for i in 0..10_u32 {
+            test_log_file
+                .set(format!("key{}", i), i.to_string())
+                .unwrap();
+        }
+        test_log_file.remove("key0".to_owned()).unwrap();
+        drop(test_log_file);
+
+        let options = LogFileOptions::default();
+        let (mut index, _, _, _) = build_index(test_file.path(), &options).unwrap();
+        assert_eq!(
+            verify_index(test_file.path(), &index, &options).unwrap(),
+            None
+        );
+
+        // an index missing a live key is caught
+        let _ = index.remove("key1");
+        assert_eq!(
+            verify_index(test_file.path(), &index, &options).unwrap(),
+            Some((8, 9))
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_get() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        for i in 0..100_u32 {
+            test_log_file
+                .set(format!("key{}", i % 10), format!("value{}", i))
+                .unwrap();
+        }
+        test_log_file.remove("key0".to_owned()).unwrap();
+        let keys: Vec<_> = (0..11).map(|i| format!("key{}", i)).collect();
+        let sought: Vec<_> = keys
+            .iter()
+            .map(|key| test_log_file.get(key).unwrap())
+            .collect();
+
+        // the mapped gets are identical to the sought ones
+        test_log_file.seal().unwrap();
+        assert!(test_log_file.mmap.is_some());
+        let mapped: Vec<_> = keys
+            .iter()
+            .map(|key| test_log_file.get(key).unwrap())
+            .collect();
+        assert_eq!(mapped, sought);
+        assert_eq!(mapped[9], Some("value99".to_owned()));
+        assert_eq!(mapped[0], None);
+
+        // an empty file is not mapped
+        let empty_file = tempfile::NamedTempFile::new().unwrap();
+        let mut empty_log_file =
+            PtrLogFileInner::new(empty_file.path(), &LogFileOptions::default()).unwrap();
+        empty_log_file.seal().unwrap();
+        assert!(empty_log_file.mmap.is_none());
+    }
+
+    #[test]
+    fn snapshot_index() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        for i in 0..100_u32 {
+            test_log_file
+                .set_with_op_id(format!("key{}", i), i.to_string(), Some(format!("op{}", i)))
+                .unwrap();
+        }
+        test_log_file.snapshot().unwrap();
+        test_log_file.remove("key1".to_owned()).unwrap();
+        test_log_file
+            .set("key100".to_owned(), "100".to_owned())
+            .unwrap();
+        drop(test_log_file);
+        let (replayed, _, snapshot_len, last_seq) =
+            build_index(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert!(snapshot_len > 0);
+        assert_eq!(last_seq, 102);
+
+        // only the records after the snapshot are replayed,
+        // so breaking the first record in place changes nothing
+        let mut content = std::fs::read(test_file.path()).unwrap();
+        let first_end = content.iter().position(|b| *b == b'\n').unwrap();
+        content[..first_end].fill(b'x');
+        std::fs::write(test_file.path(), &content).unwrap();
+        let (index, op_ids, _, last_seq) =
+            build_index(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert_eq!(index, replayed);
+        assert_eq!(last_seq, 102);
+        assert_eq!(index.len(), 101);
+        assert!(op_ids.contains("op99"));
+
+        // the snapshot of a replaced file is stale
+        let replaced = tempfile::NamedTempFile::new_in(test_file.path().parent().unwrap()).unwrap();
+        std::fs::write(replaced.path(), &content).unwrap();
+        std::fs::rename(replaced.path(), test_file.path()).unwrap();
+        assert!(build_index(test_file.path(), &LogFileOptions::default()).is_err());
+
+        std::fs::remove_file(index_snapshot_path(test_file.path())).unwrap();
+    }
+
     #[test]
     fn test_remove_file() {
         // drop
     }
+
+    #[test]
+    fn stats() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        test_log_file
+            .set("key1".to_owned(), "value1".to_owned())
+            .unwrap();
+        test_log_file
+            .set("key2".to_owned(), "value2".to_owned())
+            .unwrap();
+        test_log_file.remove("key1".to_owned()).unwrap();
+        let _ = test_log_file.get("key2").unwrap();
+        // a miss doesn't read the file
+        let _ = test_log_file.get("key3").unwrap();
+
+        let stats = test_log_file.stats.get();
+        assert_eq!(stats.reads, 1);
+        assert_eq!(stats.writes, 3);
+        assert_eq!(stats.bytes_written, test_log_file.len().unwrap());
+    }
+
+    #[test]
+    fn live_count() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            PtrLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        test_log_file
+            .set("key1".to_owned(), "value1".to_owned())
+            .unwrap();
+        let first_len = test_log_file.len().unwrap();
+        for round in 0..10 {
+            test_log_file
+                .set("key2".to_owned(), format!("value{}", round))
+                .unwrap();
+        }
+        assert!(test_log_file.len().unwrap() > first_len * 10);
+        assert_eq!(test_log_file.live_count(), 2);
+
+        // a tombstone is not live
+        test_log_file.remove("key1".to_owned()).unwrap();
+        assert_eq!(test_log_file.live_count(), 1);
+    }
 }
@@ -0,0 +1,120 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// reads and writes served by a log file since it was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogFileStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+    /// the keys with a value in the file now, a key overwritten
+    /// in a newer file is counted in both
+    pub live_keys: u64,
+    /// the times the file is synced to the disk
+    pub syncs: u64,
+}
+
+impl LogFileStats {
+    pub fn add(&mut self, other: &LogFileStats) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.bytes_written += other.bytes_written;
+        self.live_keys += other.live_keys;
+        self.syncs += other.syncs;
+    }
+}
+
+/// atomic counters, so the readers holding `&self` can count too
+#[derive(Debug, Default)]
+pub struct StatsCounter {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+    syncs: AtomicU64,
+    /// the written bytes are counted for the whole store as well
+    write_amp: Arc<WriteAmpCounter>,
+}
+
+impl StatsCounter {
+    pub fn new(write_amp: Arc<WriteAmpCounter>) -> Self {
+        StatsCounter {
+            write_amp,
+            ..Default::default()
+        }
+    }
+
+    pub fn record_read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, bytes: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.write_amp.record_physical(bytes);
+    }
+
+    pub fn record_sync(&self) {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> LogFileStats {
+        LogFileStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            // counted by the files from their indexes
+            live_keys: 0,
+            syncs: self.syncs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// the bytes of the store's user data against the bytes it wrote for them,
+/// appends and compaction rewrites together
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteAmpStats {
+    /// the keys and values set by the user
+    pub logical_bytes: u64,
+    /// the records written to the files
+    pub physical_bytes: u64,
+}
+
+impl WriteAmpStats {
+    /// the bytes written per byte set, 0 before any set
+    pub fn write_amplification(&self) -> f64 {
+        if self.logical_bytes == 0 {
+            return 0.0;
+        }
+
+        self.physical_bytes as f64 / self.logical_bytes as f64
+    }
+}
+
+/// shared by all the files of a store and its compactions
+#[derive(Debug, Default)]
+pub struct WriteAmpCounter {
+    logical_bytes: AtomicU64,
+    physical_bytes: AtomicU64,
+}
+
+impl WriteAmpCounter {
+    pub fn record_logical(&self, bytes: usize) {
+        self.logical_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_physical(&self, bytes: usize) {
+        self.physical_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> WriteAmpStats {
+        WriteAmpStats {
+            logical_bytes: self.logical_bytes.load(Ordering::Relaxed),
+            physical_bytes: self.physical_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,13 +1,20 @@
 use super::Error as LogFileError;
-use super::{log_item::LogItem, LogFile};
-use crate::log_file::log_item::LogEncoder;
-use log::info;
+use super::{
+    log_item::{self, LogItem, RecordCodec},
+    op_ids::RecentOpIds,
+    stats::StatsCounter,
+    LogFile, LogFileOptions, LogFileStats, UnknownCmdPolicy,
+};
+use crate::clock::Clock;
+use crate::log_file::log_iter::LogIter;
+use log::{info, warn};
 use snafu::{location, Location, OptionExt, ResultExt, Snafu};
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::Write,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 #[derive(Debug, Snafu)]
@@ -42,11 +49,20 @@ pub enum Error {
         location: Location,
     },
 
+    #[snafu(display("{} iter log failed: {}", location, source))]
+    IterLog {
+        source: super::log_iter::Error,
+        location: Location,
+    },
+
     #[snafu(display("{} remove non-exist key {}", location, key))]
     RemoveNotExistKey { location: Location, key: String },
 
     #[snafu(display("{} unknown log {:?}", location, item))]
-    UnknownCmd { location: Location, item: LogItem },
+    UnknownCmd {
+        location: Location,
+        item: Box<LogItem>,
+    },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -56,32 +72,43 @@ pub struct ValueLogFile {
 }
 
 impl ValueLogFile {
-    pub fn new(path: &Path) -> Result<Self> {
+    pub fn new(path: &Path, options: &LogFileOptions) -> Result<Self> {
         Ok(ValueLogFile {
-            inner: ValueLogFileInner::new(path)?,
+            inner: ValueLogFileInner::new(path, options)?,
         })
     }
 }
 
 impl LogFile for ValueLogFile {
-    fn set(&mut self, key: String, value: String) -> super::Result<()> {
+    fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> super::Result<()> {
         self.inner
-            .set(key, value)
+            .set_with_op_id(key, value, op_id)
             .map_err(|e| LogFileError::LogFileSet {
-                source_str: format!("{}", e),
+                source: Box::new(e.into()),
                 location: location!(),
             })
     }
 
-    fn get(&mut self, key: String) -> super::Result<Option<String>> {
+    fn get(&self, key: &str) -> super::Result<Option<String>> {
         Ok(self.inner.get(key))
     }
 
-    fn remove(&mut self, key: String) -> super::Result<()> {
-        self.inner.remove(key).map_err(|e| LogFileError::LogFileRm {
-            source_str: format!("{}", e),
-            location: location!(),
-        })
+    fn get_shared(&self, key: &str) -> super::Result<Option<Arc<str>>> {
+        Ok(self.inner.get_shared(key))
+    }
+
+    fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> super::Result<()> {
+        self.inner
+            .remove_with_op_id(key, op_id)
+            .map_err(|e| LogFileError::LogFileRm {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
     }
 
     fn scan(&mut self) -> super::Result<Vec<String>> {
@@ -89,29 +116,99 @@ impl LogFile for ValueLogFile {
     }
 
     fn len(&self) -> super::Result<u64> {
-        unimplemented!()
+        self.inner
+            .file
+            .metadata()
+            .map(|meta| meta.len())
+            .map_err(|e| LogFileError::LogFileLen {
+                source: Box::new(e.into()),
+                location: location!(),
+            })
     }
 
     fn contains_key(&self, key: &str) -> bool {
         self.inner.cache.contains_key(key)
     }
 
+    /// the removed keys are dropped from the cache, all are live
+    fn key_states(&self) -> Vec<(String, bool)> {
+        self.inner
+            .cache
+            .keys()
+            .map(|key| (key.clone(), true))
+            .collect()
+    }
+
+    fn contains_op_id(&self, op_id: &str) -> bool {
+        self.inner.op_ids.contains(op_id)
+    }
+
     fn path(&self) -> PathBuf {
-        unimplemented!()
+        self.inner.path.clone()
+    }
+
+    fn mode(&self) -> &'static str {
+        "value"
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.inner.cache.shrink_to_fit();
+    }
+
+    fn is_current(&self) -> bool {
+        super::is_same_file(&self.inner.file, &self.inner.path)
+    }
+
+    fn flush(&mut self) -> super::Result<()> {
+        self.inner
+            .file
+            .sync_data()
+            .map_err(|e| LogFileError::LogFileFlush {
+                source: Box::new(e.into()),
+                location: location!(),
+            })?;
+        self.inner.stats.record_sync();
+        Ok(())
+    }
+
+    fn stats(&self) -> LogFileStats {
+        LogFileStats {
+            live_keys: self.live_count() as u64,
+            ..self.inner.stats.get()
+        }
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq
+    }
+
+    fn advance_seq(&mut self, seq: u64) {
+        self.inner.last_seq = self.inner.last_seq.max(seq);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = super::Result<LogItem>>> {
+        super::iter_log(&self.inner.path, self.inner.codec.clone())
     }
 }
 
 // log file //////////////////////////////////////////////////
 pub struct ValueLogFileInner {
-    cache: HashMap<String, String>,
+    /// the values are shared with the readers by `get_shared`
+    cache: HashMap<String, Arc<str>>,
+    op_ids: RecentOpIds,
     file: File,
-    // path: PathBuf,
+    path: PathBuf,
+    /// the highest sequence number written
+    last_seq: u64,
+    codec: Arc<dyn RecordCodec>,
+    /// stamps the written records
+    clock: Arc<dyn Clock>,
+    stats: StatsCounter,
     // mutable: bool,
 }
 
-#[allow(unused)]
 impl ValueLogFileInner {
-    pub fn new(path: &Path) -> Result<ValueLogFileInner> {
+    pub fn new(path: &Path, options: &LogFileOptions) -> Result<ValueLogFileInner> {
         // process before to assert path exist
         if !path.exists() {
             return Err(Error::InvalidPath {
@@ -121,7 +218,7 @@ impl ValueLogFileInner {
         }
 
         // init cache
-        let cache = load_from_disk(path)?;
+        let (cache, op_ids, last_seq) = load_from_disk(path, options)?;
 
         // open file
         info!("open log_file:{} for writing", path.display());
@@ -130,25 +227,71 @@ impl ValueLogFileInner {
             .open(path)
             .context(OpenLogFileSnafu { path })?;
 
-        Ok(ValueLogFileInner { cache, file })
+        Ok(ValueLogFileInner {
+            cache,
+            op_ids,
+            file,
+            path: path.to_path_buf(),
+            last_seq,
+            codec: options.codec.clone(),
+            clock: options.clock.clone(),
+            stats: StatsCounter::new(options.write_amp.clone()),
+        })
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let item = LogItem::new("set".to_owned(), key, Some(value));
-        write_disk(&mut self.file, item.clone())?;
-        let _ = self.cache.insert(item.key, item.value.unwrap());
+    pub fn set_with_op_id(
+        &mut self,
+        key: String,
+        value: String,
+        op_id: Option<String>,
+    ) -> Result<()> {
+        if self.is_applied(op_id.as_deref()) {
+            info!("set key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
+
+        let item = LogItem::new("set".to_owned(), key, Some(value))
+            .with_op_id(op_id)
+            .with_seq(self.last_seq + 1)
+            .with_ts(super::now_millis(&*self.clock));
+        let bytes = write_disk(&mut self.file, &*self.codec, item.clone())?;
+        self.stats.record_write(bytes);
+        self.last_seq += 1;
+        let _ = self.cache.insert(item.key, Arc::from(item.value.unwrap()));
+        if let Some(op_id) = item.op_id {
+            let _ = self.op_ids.insert(op_id);
+        }
         Ok(())
     }
 
-    pub fn get(&self, key: String) -> Option<String> {
-        self.cache.get(&key).cloned()
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.cache.get(key).map(|value| value.to_string())
+    }
+
+    /// the value in the cache, the repeated reads share it
+    /// instead of copying it as `get`
+    pub fn get_shared(&self, key: &str) -> Option<Arc<str>> {
+        self.cache.get(key).cloned()
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let item = LogItem::new("rm".to_owned(), key, None);
+    pub fn remove_with_op_id(&mut self, key: String, op_id: Option<String>) -> Result<()> {
+        if self.is_applied(op_id.as_deref()) {
+            info!("rm key:{} with applied op_id:{:?}, skip it", key, op_id);
+            return Ok(());
+        }
+
+        let item = LogItem::new("rm".to_owned(), key, None)
+            .with_op_id(op_id)
+            .with_seq(self.last_seq + 1)
+            .with_ts(super::now_millis(&*self.clock));
         if self.cache.contains_key(&item.key) {
-            write_disk(&mut self.file, item.clone())?;
+            let bytes = write_disk(&mut self.file, &*self.codec, item.clone())?;
+            self.stats.record_write(bytes);
+            self.last_seq += 1;
             let _ = self.cache.remove(&item.key);
+            if let Some(op_id) = item.op_id {
+                let _ = self.op_ids.insert(op_id);
+            }
 
             Ok(())
         } else {
@@ -158,47 +301,77 @@ impl ValueLogFileInner {
             })
         }
     }
+
+    fn is_applied(&self, op_id: Option<&str>) -> bool {
+        op_id.is_some_and(|op_id| self.op_ids.contains(op_id))
+    }
 }
 
-fn load_from_disk(path: impl AsRef<Path>) -> Result<HashMap<String, String>> {
+fn load_from_disk(
+    path: impl AsRef<Path>,
+    options: &LogFileOptions,
+) -> Result<(HashMap<String, Arc<str>>, RecentOpIds, u64)> {
     let path = path.as_ref();
     info!("init cache from file:{}", path.display());
 
     let fin = File::open(path).context(OpenLogFileSnafu { path })?;
-    let buffered = BufReader::new(fin);
+    let file_len = fin.metadata().context(ReadFileSnafu { path })?.len();
     // todo fp way to build HashMap
-    let mut cache = HashMap::new();
-    for line in buffered.lines() {
-        let json_str = line.context(ReadFileSnafu { path })?;
-        let item = LogEncoder::decode(&json_str).context(LogEncoderSnafu)?;
+    let mut cache = HashMap::with_capacity(options.index_capacity(file_len));
+    let mut op_ids = RecentOpIds::default();
+    let mut last_seq = 0;
+    for item in LogIter::new(
+        super::buf_reader(fin, options.read_buf_size),
+        options.codec.clone(),
+    )
+    .with_max_len(options.max_record_len)
+    {
+        let item = item.context(IterLogSnafu)?;
+        last_seq = last_seq.max(item.seq.unwrap_or(0));
+
+        // a duplicated op has been applied before, replay it as a no-op
+        if let Some(op_id) = &item.op_id {
+            if !op_ids.insert(op_id.clone()) {
+                continue;
+            }
+        }
+
         match item.cmd.as_str() {
             "set" => {
                 let _ = cache.insert(
                     item.key.clone(),
-                    item.value.clone().context(UnknownCmdSnafu { item })?,
+                    Arc::from(item.value.clone().context(UnknownCmdSnafu { item })?),
                 );
             }
             "rm" => {
                 let _ = cache.remove(&item.key);
             }
-            _ => {
-                return Err(Error::UnknownCmd {
-                    location: location!(),
-                    item: item.clone(),
-                });
-            }
+            _ => match options.unknown_cmd {
+                UnknownCmdPolicy::Strict => {
+                    return Err(Error::UnknownCmd {
+                        location: location!(),
+                        item: Box::new(item.clone()),
+                    });
+                }
+                UnknownCmdPolicy::Skip => {
+                    warn!("skip unknown log {:?} in file:{}", item, path.display());
+                }
+            },
         }
     }
 
-    Ok(cache)
+    Ok((cache, op_ids, last_seq))
 }
 
-fn write_disk(fout: &mut File, log: LogItem) -> Result<()> {
-    let json_str = LogEncoder::encode(&log).context(LogEncoderSnafu)? + "\n";
+/// return the bytes written
+fn write_disk(fout: &mut File, codec: &dyn RecordCodec, log: LogItem) -> Result<usize> {
+    let json_str = log_item::encode_record(codec, &log).context(LogEncoderSnafu)? + "\n";
     fout.write_all(json_str.as_bytes())
-        .context(WriteFileSnafu { json_str })?;
+        .context(WriteFileSnafu {
+            json_str: json_str.clone(),
+        })?;
 
-    Ok(())
+    Ok(json_str.len())
 }
 
 #[cfg(test)]
@@ -206,28 +379,38 @@ mod tests {
     use std::{
         fs::File,
         io::{BufRead, BufReader},
+        sync::Arc,
     };
 
     // use assert_cmd::assert;
-    use super::{write_disk, LogEncoder, LogItem, ValueLogFileInner};
+    use super::{write_disk, LogItem, ValueLogFile, ValueLogFileInner};
+    use crate::log_file::log_item::LogEncoder;
+    use crate::log_file::{LogFile, LogFileOptions, UnknownCmdPolicy};
 
     #[test]
     fn crud() {
         let test_file = tempfile::NamedTempFile::new().unwrap();
-        let mut test_log_file = ValueLogFileInner::new(test_file.path()).unwrap();
+        let mut test_log_file =
+            ValueLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
 
         // set
         let kv1 = ("key1".to_owned(), "value1".to_owned());
         let kv2 = ("key2".to_owned(), "value2".to_owned());
         let kv3 = ("key3".to_owned(), "value3".to_owned());
-        test_log_file.set(kv1.0.clone(), kv1.1.clone()).unwrap();
-        test_log_file.set(kv2.0.clone(), kv2.1.clone()).unwrap();
-        test_log_file.set(kv3.0.clone(), kv3.1.clone()).unwrap();
+        test_log_file
+            .set_with_op_id(kv1.0.clone(), kv1.1.clone(), None)
+            .unwrap();
+        test_log_file
+            .set_with_op_id(kv2.0.clone(), kv2.1.clone(), None)
+            .unwrap();
+        test_log_file
+            .set_with_op_id(kv3.0.clone(), kv3.1.clone(), None)
+            .unwrap();
 
         // get
-        let res1 = test_log_file.get(kv1.0.clone());
-        let res2 = test_log_file.get(kv2.0.clone());
-        let res3 = test_log_file.get(kv3.0.clone());
+        let res1 = test_log_file.get(&kv1.0);
+        let res2 = test_log_file.get(&kv2.0);
+        let res3 = test_log_file.get(&kv3.0);
         assert!(res1.is_some());
         assert!(res2.is_some());
         assert!(res3.is_some());
@@ -236,17 +419,21 @@ mod tests {
         assert_eq!(res3.unwrap(), "value3");
 
         // rm
-        let res3 = test_log_file.remove(kv3.0.clone());
+        let res3 = test_log_file.remove_with_op_id(kv3.0.clone(), None);
         assert!(res3.is_ok());
-        let res3 = test_log_file.get(kv3.0.clone());
+        let res3 = test_log_file.get(&kv3.0);
         assert!(res3.is_none());
+        assert!(test_log_file
+            .remove_with_op_id(kv3.0.clone(), None)
+            .is_err());
 
         // reopen to check replay
         drop(test_log_file);
-        let test_log_file = ValueLogFileInner::new(test_file.path()).unwrap();
-        let res1 = test_log_file.get(kv1.0.clone());
-        let res2 = test_log_file.get(kv2.0.clone());
-        let res3 = test_log_file.get(kv3.0.clone());
+        let test_log_file =
+            ValueLogFileInner::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        let res1 = test_log_file.get(&kv1.0);
+        let res2 = test_log_file.get(&kv2.0);
+        let res3 = test_log_file.get(&kv3.0);
         assert!(res1.is_some());
         assert!(res2.is_some());
         assert!(res3.is_none());
@@ -254,6 +441,48 @@ mod tests {
         assert_eq!(res2.unwrap(), kv2.1);
     }
 
+    #[test]
+    fn get_shared() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            ValueLogFile::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        test_log_file
+            .set_with_op_id("key1".to_owned(), "value1".to_owned(), None)
+            .unwrap();
+
+        // read by the trait as the store does, sharing the cached value
+        let log_file: &dyn LogFile = &test_log_file;
+        let value1 = log_file.get_shared("key1").unwrap().unwrap();
+        let value2 = log_file.get_shared("key1").unwrap().unwrap();
+        assert_eq!(&*value1, "value1");
+        assert!(Arc::ptr_eq(&value1, &value2));
+        assert_eq!(log_file.get("key1").unwrap(), Some("value1".to_owned()));
+        assert_eq!(log_file.get_shared("key2").unwrap(), None);
+    }
+
+    #[test]
+    fn len() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_log_file =
+            ValueLogFile::new(test_file.path(), &LogFileOptions::default()).unwrap();
+        assert_eq!(test_log_file.len().unwrap(), 0);
+
+        test_log_file
+            .set_with_op_id("key1".to_owned(), "value1".to_owned(), None)
+            .unwrap();
+        test_log_file
+            .remove_with_op_id("key1".to_owned(), None)
+            .unwrap();
+        assert_eq!(
+            test_log_file.len().unwrap(),
+            std::fs::metadata(test_file.path()).unwrap().len()
+        );
+        assert_eq!(
+            test_log_file.stats().bytes_written,
+            test_log_file.len().unwrap()
+        );
+    }
+
     #[test]
     fn test_write_disk() {
         // test file
@@ -271,8 +500,8 @@ mod tests {
             "key2".to_owned(),
             Some("value2".to_owned()),
         );
-        let res1 = write_disk(&mut test_file_obj, test_log1.clone());
-        let res2 = write_disk(&mut test_file_obj, test_log2.clone());
+        let res1 = write_disk(&mut test_file_obj, &LogEncoder, test_log1.clone());
+        let res2 = write_disk(&mut test_file_obj, &LogEncoder, test_log2.clone());
         assert!(res1.is_ok());
         assert!(res2.is_ok());
         drop(test_file_obj);
@@ -289,4 +518,30 @@ mod tests {
         assert_eq!(log_strs[0], test_log1);
         assert_eq!(log_strs[1], test_log2);
     }
+
+    #[test]
+    fn unknown_cmd() {
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let mut test_file_obj = File::create(test_file.path()).unwrap();
+        let kv1 = LogItem::new(
+            "set".to_owned(),
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+        );
+        let unknown = LogItem::new("expire".to_owned(), "key1".to_owned(), None);
+        write_disk(&mut test_file_obj, &LogEncoder, kv1).unwrap();
+        write_disk(&mut test_file_obj, &LogEncoder, unknown).unwrap();
+        drop(test_file_obj);
+
+        // strict
+        assert!(ValueLogFileInner::new(test_file.path(), &LogFileOptions::default()).is_err());
+
+        // skip
+        let options = LogFileOptions {
+            unknown_cmd: UnknownCmdPolicy::Skip,
+            ..Default::default()
+        };
+        let test_log_file = ValueLogFileInner::new(test_file.path(), &options).unwrap();
+        assert_eq!(test_log_file.get("key1").unwrap(), "value1");
+    }
 }
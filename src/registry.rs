@@ -0,0 +1,95 @@
+use log::{info, warn};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::kv_store::{KvStore, KvStoreConfig, Result};
+use crate::lock::MutexExt;
+
+/// caches the opened stores by path, so a process serving many stores
+/// opens each once. the least recently used one is closed once more than
+/// `max_open` are cached.
+///
+/// nothing stops two stores from opening the same dir, they would append
+/// to the same files. so a store still used outside is never evicted,
+/// the cache may exceed `max_open` until it is dropped
+pub struct StoreRegistry {
+    config: KvStoreConfig,
+    max_open: usize,
+    /// the least recently used first
+    stores: Mutex<Vec<(PathBuf, KvStore)>>,
+}
+
+impl StoreRegistry {
+    pub fn new(config: KvStoreConfig, max_open: usize) -> StoreRegistry {
+        StoreRegistry {
+            config,
+            max_open: max_open.max(1),
+            stores: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// a handle of the store at the path, opened if not cached
+    pub fn get(&self, path: impl AsRef<Path>) -> Result<KvStore> {
+        let path = path.as_ref();
+        let mut stores = self.stores.lock_or_recover();
+        if let Some(pos) = stores.iter().position(|(store_path, _)| store_path == path) {
+            let entry = stores.remove(pos);
+            let store = entry.1.clone();
+            stores.push(entry);
+            return Ok(store);
+        }
+
+        info!("registry open store:{}", path.display());
+        let store = KvStore::open_with_config(path, self.config.clone())?;
+        stores.push((path.to_path_buf(), store.clone()));
+        self.evict(&mut stores)?;
+        Ok(store)
+    }
+
+    /// whether the store at the path is cached
+    pub fn contains(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.stores
+            .lock_or_recover()
+            .iter()
+            .any(|(store_path, _)| store_path == path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.stores.lock_or_recover().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// close the least recently used stores not used outside,
+    /// a store failed to close is kept
+    fn evict(&self, stores: &mut Vec<(PathBuf, KvStore)>) -> Result<()> {
+        while stores.len() > self.max_open {
+            // the newest is just returned to the caller, skip it
+            let idle = stores[..stores.len() - 1]
+                .iter()
+                .position(|(_, store)| !store.is_shared());
+            let pos = match idle {
+                Some(pos) => pos,
+                None => {
+                    warn!(
+                        "registry stores:{} exceed {}, but all are in use",
+                        stores.len(),
+                        self.max_open
+                    );
+                    return Ok(());
+                }
+            };
+
+            info!("registry evict store:{}", stores[pos].0.display());
+            stores[pos].1.persist()?;
+            let _ = stores.remove(pos);
+        }
+
+        Ok(())
+    }
+}
@@ -1,8 +1,16 @@
-// use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use assert_cmd::prelude::*;
+use kvs::{
+    CancellationToken, CodecError, CompactorMode, Engine, Error, KeyNormalizer, KvStore,
+    KvStoreConfig, LogEncoder, LogFileOptions, LogItem, MockClock, OpenMode, OpenReport,
+    RecordCodec, Result, StoreRegistry, SyncPolicy, UnknownCmdPolicy,
+};
+use std::borrow::Cow;
+use std::io::Write;
 // use predicates::ord::eq;
 // use predicates::str::{contains, is_empty, PredicateStrExt};
-// use std::process::Command;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 // use walkdir::WalkDir;
@@ -16,14 +24,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -35,16 +43,16 @@ fn overwrite_value() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -56,12 +64,12 @@ fn get_non_existent_value() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, None);
 
     Ok(())
 }
@@ -70,7 +78,17 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(Error::KeyNotFound { .. })
+    ));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(Error::KeyNotFound { .. })
+    ));
+    assert_eq!(store.get("key1")?, None);
     Ok(())
 }
 
@@ -80,7 +98,7 @@ fn remove_key() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key1")?, None);
     Ok(())
 }
 
@@ -119,7 +137,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -129,3 +147,2732 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Writes spread over many segments by the auto compactions should all
+// resolve to their newest value, across reopens, in every engine and mode.
+#[test]
+fn multi_segment_end_to_end() -> Result<()> {
+    let configs = [
+        (Engine::Ptr, CompactorMode::Simple, None),
+        (Engine::Ptr, CompactorMode::Full, None),
+        (Engine::Ptr, CompactorMode::Simple, Some(3)),
+        (Engine::KvSep, CompactorMode::Simple, None),
+        (Engine::KvSep, CompactorMode::Full, None),
+        (Engine::KvSep, CompactorMode::Simple, Some(3)),
+    ];
+    for (engine, mode, max_segments) in configs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut builder = KvStore::builder()
+                .engine(engine)
+                .compactor_mode(mode)
+                .compaction_threshold(4 * 1024);
+            if let Some(max_segments) = max_segments {
+                builder = builder.max_immutable_segments(max_segments);
+            }
+            builder.open(temp_dir.path())
+        };
+
+        // the expected values, checked against a plain map
+        let mut model = std::collections::HashMap::new();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let assert_model = |store: &KvStore, model: &std::collections::HashMap<String, String>| {
+            for key_id in 0..200 {
+                let key = format!("key{}", key_id);
+                assert_eq!(
+                    store.get(&key).unwrap(),
+                    model.get(&key).cloned(),
+                    "{} in {:?} {:?} {:?}",
+                    key,
+                    engine,
+                    mode,
+                    max_segments
+                );
+            }
+        };
+
+        let mut store = open()?;
+        for round in 0..4 {
+            for _ in 0..1000 {
+                let key = format!("key{}", next() % 200);
+                if next() % 4 == 0 {
+                    let res = store.remove(key.clone());
+                    match model.remove(&key) {
+                        Some(_) => res?,
+                        None => assert!(matches!(res, Err(Error::KeyNotFound { .. }))),
+                    }
+                } else {
+                    let value = format!("value{}", next());
+                    store.set(key.clone(), value.clone())?;
+                    let _ = model.insert(key, value);
+                }
+            }
+            assert_model(&store, &model);
+            if round % 2 == 1 {
+                store.compact()?;
+                assert_model(&store, &model);
+            }
+            drop(store);
+            store = open()?;
+            assert_model(&store, &model);
+        }
+        assert!(store.stats().segments.len() > 1);
+
+        let mut keys: Vec<_> = model.keys().cloned().collect();
+        keys.sort();
+        let iterated: Vec<_> = store.iter().map(|pair| pair.unwrap().0).collect();
+        assert_eq!(iterated, keys);
+    }
+
+    Ok(())
+}
+
+// Retrying an op with the same op id should only take effect once.
+#[test]
+fn idempotent_op_id() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let op1 = Some("op1".to_owned());
+    store.set_with_op_id("key1".to_owned(), "value1".to_owned(), op1.clone())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    // the retry of op1 should not overwrite value2
+    store.set_with_op_id("key1".to_owned(), "value1".to_owned(), op1.clone())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    // the retried remove is a no-op rather than a non-exist key error
+    let op2 = Some("op2".to_owned());
+    store.remove_with_op_id("key1".to_owned(), op2.clone())?;
+    store.remove_with_op_id("key1".to_owned(), op2.clone())?;
+    assert_eq!(store.get("key1")?, None);
+
+    // Open from disk again and check the applied ops are still recognized.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_op_id("key1".to_owned(), "value1".to_owned(), op1)?;
+    store.remove_with_op_id("key1".to_owned(), op2)?;
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// The op ids are kept by the records a compaction merges, not by the records it drops.
+#[test]
+fn op_id_after_full_compact() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().engine(engine).open(temp_dir.path())?;
+        let op1 = Some("op1".to_owned());
+        store.set_with_op_id("key1".to_owned(), "value1".to_owned(), op1.clone())?;
+        store.full_compact()?;
+
+        // the merged record still has op1
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        store.set_with_op_id("key1".to_owned(), "value1".to_owned(), op1.clone())?;
+        assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+        // while the overwritten record of op1 is dropped with it
+        store.full_compact()?;
+        store.set_with_op_id("key1".to_owned(), "value1".to_owned(), op1)?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should create the dir tree when opening a nonexistent path.
+#[test]
+fn open_nonexistent_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("a").join("b").join("c");
+    let mut store = KvStore::open(path.as_path())?;
+    assert!(path.is_dir());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(path.as_path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should return an error rather than panic when the path can't be opened.
+#[test]
+fn open_invalid_path() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("file");
+    std::fs::write(&file_path, "").expect("unable to create file");
+
+    // the path is a file, or its parent is a file
+    assert!(KvStore::open(file_path.as_path()).is_err());
+    assert!(KvStore::open(file_path.join("store")).is_err());
+}
+
+// Should fail to open a store with unknown cmd records unless skipping them.
+#[test]
+fn open_with_unknown_cmd() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a record written by a newer version
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("data_0"))
+        .expect("unable to open data file");
+    writeln!(file, r#"{{"cmd":"expire","key":"key1","value":null}}"#)
+        .expect("unable to write data file");
+    drop(file);
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let config = KvStoreConfig {
+        log_file: LogFileOptions {
+            unknown_cmd: UnknownCmdPolicy::Skip,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs compact` should shrink a store full of overwrites.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let before_size = dir_size();
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("before:"));
+    assert!(stdout.contains("after:"));
+    assert!(dir_size() < before_size);
+
+    // quiet
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--quiet"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Full compaction merges all segments and drops removed keys
+#[test]
+fn full_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.compact()?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "mid".to_owned())?;
+    }
+    store.compact()?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let stats = store.full_compact()?;
+    assert_eq!(stats.before_segments, 3);
+    assert_eq!(stats.after_segments, 2);
+    assert!(stats.after_bytes < stats.before_bytes);
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+
+    // reopen and check data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should get the present keys and `None` for the absent ones in a batch.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let keys = ["key1", "key2", "key3", "key4", "key1"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    assert_eq!(
+        store.get_many(keys)?,
+        vec![
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+            None,
+            None,
+            Some("value1".to_owned()),
+        ]
+    );
+    assert!(store.get_many(Vec::new())?.is_empty());
+
+    Ok(())
+}
+
+// Sets racing with compactions in other threads should never be lost.
+#[test]
+fn concurrent_set_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // large values make the sets trigger compactions by themselves
+    let value = |thread_id: usize, i: usize| format!("{}-{}-{}", thread_id, i, "v".repeat(2048));
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let mut store = store.clone();
+            std::thread::spawn(move || {
+                for i in 0..300 {
+                    store
+                        .set(format!("key{}-{}", thread_id, i), value(thread_id, i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    let compact_handles: Vec<_> = (0..4)
+        .map(|_| {
+            let mut store = store.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    if i % 25 == 0 {
+                        store.full_compact().unwrap();
+                    } else {
+                        store.compact().unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles.into_iter().chain(compact_handles) {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for i in 0..300 {
+                assert_eq!(
+                    store.get(format!("key{}-{}", thread_id, i))?,
+                    Some(value(thread_id, i))
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // reopen and check data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// A follower should see the changes of another handle after reloading.
+#[test]
+fn reload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut follower = KvStore::open(temp_dir.path())?;
+    let mut primary = KvStore::open(temp_dir.path())?;
+
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary.compact()?;
+    primary.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(follower.get("key2")?, None);
+
+    follower.reload()?;
+    assert_eq!(follower.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(follower.get("key2")?, Some("value2".to_owned()));
+
+    // segments replaced by the primary are loaded again
+    primary.set("key1".to_owned(), "value3".to_owned())?;
+    primary.remove("key2".to_owned())?;
+    primary.full_compact()?;
+    primary.set("key4".to_owned(), "value4".to_owned())?;
+    follower.reload()?;
+    assert_eq!(follower.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(follower.get("key2")?, None);
+    assert_eq!(follower.get("key4")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// The cli should print "Key not found" for a miss, and exit 1 for rm.
+#[test]
+fn cli_get_set_rm() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap()
+    };
+
+    let output = kvs(&["get", "key1"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Key not found\n");
+
+    let output = kvs(&["set", "key1", "value1"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let output = kvs(&["get", "key1"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "value1\n");
+
+    let output = kvs(&["rm", "key1"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let output = kvs(&["rm", "key1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Key not found\n");
+}
+
+// `kvs set` should read the value as is from a file or stdin instead.
+#[test]
+fn cli_set_value_from_file_or_stdin() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str], stdin: Option<&[u8]>| {
+        let mut child = Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut child_stdin = child.stdin.take().unwrap();
+        if let Some(stdin) = stdin {
+            child_stdin.write_all(stdin).unwrap();
+        }
+        drop(child_stdin);
+        child.wait_with_output().unwrap()
+    };
+
+    let value = "line1\n  line2 with spaces\n\n";
+    let value_path = temp_dir.path().join("value.txt");
+    std::fs::write(&value_path, value).unwrap();
+    let output = kvs(
+        &[
+            "set",
+            "key1",
+            "--value-from-file",
+            value_path.to_str().unwrap(),
+        ],
+        None,
+    );
+    assert!(output.status.success());
+    let output = kvs(
+        &["set", "key2", "--value-from-stdin"],
+        Some(value.as_bytes()),
+    );
+    assert!(output.status.success());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1").unwrap(), Some(value.to_owned()));
+    assert_eq!(store.get("key2").unwrap(), Some(value.to_owned()));
+    drop(store);
+
+    // a value not of utf-8 or from a missing file fails
+    let output = kvs(&["set", "key3", "--value-from-stdin"], Some(&[0xff, 0xfe]));
+    assert_eq!(output.status.code(), Some(exitcode::DATAERR));
+    let missing = temp_dir.path().join("missing");
+    let output = kvs(
+        &[
+            "set",
+            "key3",
+            "--value-from-file",
+            missing.to_str().unwrap(),
+        ],
+        None,
+    );
+    assert_eq!(output.status.code(), Some(exitcode::IOERR));
+    // only one value may be given
+    let output = kvs(&["set", "key3", "value3", "--value-from-stdin"], None);
+    assert!(!output.status.success());
+    let output = kvs(&["set", "key3"], None);
+    assert!(!output.status.success());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key3").unwrap(), None);
+}
+
+// The background flusher should persist the index snapshots.
+#[test]
+fn background_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        flush_interval: Some(std::time::Duration::from_millis(10)),
+        snapshot_index: true,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let snapshot_path = temp_dir.path().join("data_0.index");
+    for _ in 0..200 {
+        if snapshot_path.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(snapshot_path.exists());
+
+    // stopped and flushed on drop
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..101 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// An empty value should be kept as `Some("")`, not taken as removed.
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), String::new())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1")?, Some(String::new()));
+
+    // through compactions and a reopen
+    store.compact()?;
+    assert_eq!(store.get("key1")?, Some(String::new()));
+    store.full_compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some(String::new()));
+    assert_eq!(store.get("key3")?, None);
+
+    store.set("key2".to_owned(), String::new())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// Numbers are stored as strings, `incr` starts a missing key from 0.
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_u64("counter".to_owned())?, None);
+    assert_eq!(
+        store.get_with_default("counter".to_owned(), "0".to_owned())?,
+        "0"
+    );
+    assert_eq!(store.incr("counter".to_owned(), 1)?, 1);
+    assert_eq!(store.incr("counter".to_owned(), 2)?, 3);
+    assert_eq!(store.get("counter")?, Some("3".to_owned()));
+
+    // an existing number from an older segment
+    store.set("visits".to_owned(), "41".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.incr("visits".to_owned(), 1)?, 42);
+    assert_eq!(store.get_u64("visits".to_owned())?, Some(42));
+
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(Error::NotANumber { .. })
+    ));
+    assert!(matches!(
+        store.get_u64("name".to_owned()),
+        Err(Error::NotANumber { .. })
+    ));
+    store.set("max".to_owned(), u64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(Error::IncrOverflow { .. })
+    ));
+    assert_eq!(store.get("name")?, Some("kvs".to_owned()));
+
+    // concurrent incrs don't lose any delta
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let mut store = store.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr("shared".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get_u64("shared".to_owned())?, Some(400));
+
+    Ok(())
+}
+
+// The bytes written, compaction rewrites included, are set against the bytes set.
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().write_amp.write_amplification(), 0.0);
+
+    let mut logical_bytes = 0;
+    for round in 0..2 {
+        for key_id in 0..10 {
+            let (key, value) = (
+                format!("key{}", key_id),
+                format!("value{}_{}", key_id, round),
+            );
+            logical_bytes += (key.len() + value.len()) as u64;
+            store.set(key, value)?;
+        }
+    }
+    let compaction = store.compact()?;
+
+    let write_amp = store.stats().write_amp;
+    assert_eq!(write_amp.logical_bytes, logical_bytes);
+    // the appends to the old mutable, then the compacted file
+    assert_eq!(
+        write_amp.physical_bytes,
+        compaction.before_bytes + compaction.after_bytes
+    );
+    assert_eq!(
+        write_amp.write_amplification(),
+        write_amp.physical_bytes as f64 / logical_bytes as f64
+    );
+    assert!(write_amp.write_amplification() > 1.0);
+
+    Ok(())
+}
+
+// A compaction deferred until idle should wait for the writes to pause.
+#[test]
+fn compact_when_idle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compaction_threshold(100)
+        .compact_when_idle(Duration::from_millis(300))
+        .open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // the mutable is over the threshold but the burst just ended
+    assert_eq!(store.stats().segments.len(), 1);
+
+    let start = std::time::Instant::now();
+    while store.stats().segments.len() == 1 && start.elapsed() < Duration::from_secs(5) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(store.stats().segments.len(), 2);
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Each segment counts the reads and writes directed at it.
+#[test]
+fn segment_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("new_value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    let _ = store.get("key9")?;
+
+    let stats = store.stats();
+    assert_eq!(stats.segments.len(), 2);
+    let (mutable_path, mutable_stats) = stats.segments.last().unwrap();
+    assert_eq!(mutable_path, &temp_dir.path().join("data_1"));
+    assert_eq!(mutable_stats.writes, 6);
+    // the remove checks the key by the live keys, not the files
+    assert_eq!(mutable_stats.reads, 0);
+    assert_eq!(
+        mutable_stats.bytes_written,
+        std::fs::metadata(mutable_path).unwrap().len()
+    );
+    // the compacted file is opened again, its counters start from 0
+    assert_eq!(stats.segments[0].1.writes, 0);
+    assert_eq!(stats.segments[0].1.reads, 1);
+    assert_eq!(stats.total.writes, 6);
+    assert_eq!(stats.total.reads, 1);
+    // a key overwritten in the mutable is live in both
+    assert_eq!(mutable_stats.live_keys, 4);
+    assert_eq!(stats.total.live_keys, 14);
+
+    Ok(())
+}
+
+// The total size should be the sum of the segment files.
+#[test]
+fn total_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("new_value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let stats = store.stats();
+    assert_eq!(stats.segments.len(), 2);
+    let segments_size: u64 = stats
+        .segments
+        .iter()
+        .map(|(path, _)| std::fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(segments_size > 0);
+    assert_eq!(stats.total_size, segments_size);
+
+    Ok(())
+}
+
+// Iterating should yield the newest value of each live key, in order.
+#[test]
+fn iter() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        for lazy_index in [false, true] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut store = KvStore::builder().engine(engine).open(temp_dir.path())?;
+            for key_id in 0..6 {
+                store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            }
+            store.compact()?;
+            store.set("key1".to_owned(), "new_value1".to_owned())?;
+            store.remove("key2".to_owned())?;
+            store.compact()?;
+            store.remove("key3".to_owned())?;
+            store.set("key4".to_owned(), "new_value4".to_owned())?;
+            store.set("key2".to_owned(), "new_value2".to_owned())?;
+            store.set("key6".to_owned(), "value6".to_owned())?;
+            store.remove("key6".to_owned())?;
+            drop(store);
+
+            let store = KvStore::builder()
+                .engine(engine)
+                .lazy_index(lazy_index)
+                .open(temp_dir.path())?;
+            assert!(store.stats().segments.len() > 1);
+            let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+            let expected = [
+                ("key0", "value0"),
+                ("key1", "new_value1"),
+                ("key2", "new_value2"),
+                ("key4", "new_value4"),
+                ("key5", "value5"),
+            ];
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            assert_eq!(pairs, expected);
+        }
+    }
+
+    Ok(())
+}
+
+// `multi_compact` merges the immutables holding the most garbage in place of the newest of them.
+#[test]
+fn multi_compact() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .engine(engine)
+            .multi_compact_segments(2)
+            .open(temp_dir.path())?;
+        for prefix in ["a", "b", "c", "d"] {
+            for i in 0..10 {
+                store.set(format!("{}{}", prefix, i), format!("value_{}{}", prefix, i))?;
+            }
+            match prefix {
+                "a" => store.set("x".to_owned(), "old".to_owned())?,
+                "b" => store.set("x".to_owned(), "new".to_owned())?,
+                // the tombstone hides the key in an older file left out
+                "c" => store.remove("b2".to_owned())?,
+                _ => {}
+            }
+            store.compact()?;
+        }
+        for i in 0..9 {
+            store.set(format!("a{}", i), format!("new_a{}", i))?;
+        }
+        for i in 0..5 {
+            store.set(format!("c{}", i), format!("new_c{}", i))?;
+        }
+        store.set("b0".to_owned(), "new_b0".to_owned())?;
+
+        let ratios = store.garbage_ratios()?;
+        let paths: Vec<_> = ratios.iter().map(|(path, _)| path.clone()).collect();
+        let ratios: Vec<_> = ratios.into_iter().map(|(_, ratio)| ratio).collect();
+        assert_eq!(ratios.len(), 4);
+        assert!(ratios[0] > ratios[2] && ratios[2] > ratios[1] && ratios[1] > ratios[3]);
+        assert_eq!(ratios[3], 0.0);
+
+        // the worst two, "a" and "c", are merged into the place of "c"
+        let stats = store.multi_compact()?;
+        assert_eq!(stats.before_segments, 5);
+        assert_eq!(stats.after_segments, 4);
+        assert!(stats.after_bytes < stats.before_bytes);
+        let after: Vec<_> = store
+            .garbage_ratios()?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(
+            after,
+            [paths[1].clone(), paths[2].clone(), paths[3].clone()]
+        );
+        assert!(!paths[0].exists());
+        // only the tombstone is left as garbage
+        assert!(store.garbage_ratios()?[1].1 < ratios[2]);
+
+        let assert_values = |store: &KvStore| -> Result<()> {
+            for i in 0..10 {
+                let a = if i < 9 { "new_a" } else { "value_a" };
+                let b = if i == 0 { "new_b" } else { "value_b" };
+                let c = if i < 5 { "new_c" } else { "value_c" };
+                assert_eq!(store.get(format!("a{}", i))?, Some(format!("{}{}", a, i)));
+                if i != 2 {
+                    assert_eq!(store.get(format!("b{}", i))?, Some(format!("{}{}", b, i)));
+                }
+                assert_eq!(store.get(format!("c{}", i))?, Some(format!("{}{}", c, i)));
+                assert_eq!(store.get(format!("d{}", i))?, Some(format!("value_d{}", i)));
+            }
+            assert_eq!(store.get("b2")?, None);
+            assert_eq!(store.get("x")?, Some("new".to_owned()));
+            Ok(())
+        };
+        assert_values(&store)?;
+        drop(store);
+        assert_values(&KvStore::builder().engine(engine).open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+// A `multi_compact` failing to remove the merged files should lose no key, neither in the
+// running store nor after reopened.
+#[test]
+fn multi_compact_remove_failed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .multi_compact_segments(2)
+        .open(temp_dir.path())?;
+    for prefix in ["a", "b"] {
+        for i in 0..10 {
+            store.set(format!("{}{}", prefix, i), format!("old_{}{}", prefix, i))?;
+            store.set(format!("{}{}", prefix, i), format!("value_{}{}", prefix, i))?;
+        }
+        match prefix {
+            "a" => store.set("x".to_owned(), "value_x".to_owned())?,
+            _ => store.remove("x".to_owned())?,
+        }
+        store.compact()?;
+    }
+    store.set("c0".to_owned(), "value_c0".to_owned())?;
+
+    // the snapshot of the older file can't be removed after the merged
+    // file is renamed into the place of the newer one
+    let paths: Vec<_> = store
+        .garbage_ratios()?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert_eq!(paths.len(), 2);
+    let mut snapshot_path = paths[0].clone().into_os_string();
+    snapshot_path.push(".index");
+    let snapshot_path = std::path::PathBuf::from(snapshot_path);
+    let _ = std::fs::remove_file(&snapshot_path);
+    std::fs::create_dir(&snapshot_path).unwrap();
+    std::fs::write(snapshot_path.join("busy"), "").unwrap();
+    assert!(store.multi_compact().is_err());
+
+    let assert_values = |store: &KvStore| -> Result<()> {
+        for prefix in ["a", "b"] {
+            for i in 0..10 {
+                assert_eq!(
+                    store.get(format!("{}{}", prefix, i))?,
+                    Some(format!("value_{}{}", prefix, i))
+                );
+            }
+        }
+        assert_eq!(store.get("c0")?, Some("value_c0".to_owned()));
+        assert_eq!(store.get("x")?, None);
+        Ok(())
+    };
+    assert_values(&store)?;
+    drop(store);
+    std::fs::remove_dir_all(&snapshot_path).unwrap();
+    assert_values(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// `estimate_disk_usage` counts the bytes of the overwritten and removed records as reclaimable.
+#[test]
+fn estimate_disk_usage() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().engine(engine).open(temp_dir.path())?;
+        for round in 0..10 {
+            for i in 0..20 {
+                store.set(format!("key{}", i), format!("{}{}", round, "v".repeat(200)))?;
+            }
+            if round % 3 == 0 {
+                store.compact()?;
+            }
+        }
+        for i in 0..5 {
+            store.remove(format!("key{}", i))?;
+        }
+
+        let usage = store.estimate_disk_usage()?;
+        assert!(usage.reclaimable > 0);
+        assert_eq!(usage.total, usage.live + usage.reclaimable);
+        // most of the records are dead, 15 of the 200 sets are live
+        assert!(usage.reclaimable > usage.total * 8 / 10);
+
+        // a full compaction frees about the reclaimable bytes
+        store.full_compact()?;
+        let after = store.estimate_disk_usage()?;
+        let freed = usage.total - after.total;
+        assert!(freed.abs_diff(usage.reclaimable) < usage.total / 20);
+        assert!(after.reclaimable < after.total / 10);
+    }
+
+    Ok(())
+}
+
+// `value_range` gives the bytes of the latest value on disk, read raw they decode to it.
+#[test]
+fn value_range() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().engine(engine).open(temp_dir.path())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.compact()?;
+        for i in 0..5 {
+            store.set(format!("key{}", i), format!("new \"value\"\n{}", i))?;
+        }
+        store.remove("key9".to_owned())?;
+
+        for i in 0..9 {
+            let key = format!("key{}", i);
+            let range = store.value_range(&key).expect("value on disk");
+            let mut buf = vec![0; range.len as usize];
+            let file = std::fs::File::open(&range.path).unwrap();
+            file.read_exact_at(&mut buf, range.offset).unwrap();
+            let raw = String::from_utf8(buf).unwrap();
+            let value = if range.encoded {
+                LogEncoder.decode(raw.trim_end_matches('\n')).unwrap().value
+            } else {
+                Some(raw)
+            };
+            assert_eq!(value, store.get(&key)?);
+        }
+        assert_eq!(store.value_range("key9"), None);
+        assert_eq!(store.value_range("key10"), None);
+    }
+
+    // a compressed file has no range to read raw
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compress_segments(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.value_range("key1"), None);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The signals arriving during a large write and read don't fail them.
+#[cfg(unix)]
+#[test]
+fn interrupted_by_signals() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    extern "C" fn on_signal(_: libc::c_int) {}
+
+    // without `SA_RESTART`, the blocking calls return `EINTR`
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .sync_policy(SyncPolicy::EveryWrite)
+        .open(temp_dir.path())?;
+    let value = "v".repeat(16 << 20);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let target = unsafe { libc::pthread_self() };
+    let signaller = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                unsafe { libc::pthread_kill(target, libc::SIGUSR1) };
+                std::thread::sleep(Duration::from_micros(100));
+            }
+        })
+    };
+
+    let set = store.set("key1".to_owned(), value.clone());
+    let got = store.get("key1");
+    // stop the signals before this thread may exit
+    done.store(true, Ordering::SeqCst);
+    signaller.join().unwrap();
+
+    set?;
+    assert!(got? == Some(value));
+
+    Ok(())
+}
+
+// The compactions should keep the newest versions of a key up to the limit.
+#[test]
+fn keep_versions() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .engine(engine)
+            .keep_versions(3)
+            .open(temp_dir.path())?;
+        for version in 1..=5 {
+            store.set("key1".to_owned(), format!("value{}", version))?;
+        }
+        // the history of a removed key starts over
+        store.set("key2".to_owned(), "old_value".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key2".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        let assert_versions = |store: &KvStore| -> Result<()> {
+            for (n, version) in [5, 4, 3].into_iter().enumerate() {
+                assert_eq!(
+                    store.get_version("key1", n)?,
+                    Some(format!("value{}", version))
+                );
+            }
+            assert_eq!(store.get_version("key1", 3)?, None);
+            assert_eq!(store.get_version("key2", 0)?, Some("value2".to_owned()));
+            assert_eq!(store.get_version("key2", 1)?, Some("value1".to_owned()));
+            assert_eq!(store.get_version("key2", 2)?, None);
+            assert_eq!(store.get("key1")?, Some("value5".to_owned()));
+            Ok(())
+        };
+        // the engines other than ptr keep the latest only when
+        // compacting the mutable
+        if engine == Engine::Ptr {
+            store.compact()?;
+            assert_versions(&store)?;
+        }
+        store.full_compact()?;
+        assert_versions(&store)?;
+    }
+
+    Ok(())
+}
+
+// A stray blank line between records shouldn't stop the store from opening.
+#[test]
+fn open_with_blank_line() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("data_0"))
+        .expect("unable to open data file");
+    writeln!(file).expect("unable to write data file");
+    drop(file);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Every write gets a sequence number higher than all the earlier ones.
+#[test]
+fn flush_and_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.flush_and_checkpoint()?, 0);
+
+    let mut last_seq = 0;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        let seq = store.flush_and_checkpoint()?;
+        assert!(seq > last_seq);
+        last_seq = seq;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.flush_and_checkpoint()? > last_seq);
+    last_seq = store.flush_and_checkpoint()?;
+
+    // continued in the new files after compactions
+    store.compact()?;
+    assert_eq!(store.flush_and_checkpoint()?, last_seq);
+    store.set("key1".to_owned(), "new_value1".to_owned())?;
+    assert_eq!(store.flush_and_checkpoint()?, last_seq + 1);
+    store.full_compact()?;
+    last_seq = store.flush_and_checkpoint()?;
+
+    // and after reopen
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.flush_and_checkpoint()?, last_seq);
+    store.set("key2".to_owned(), "new_value2".to_owned())?;
+    assert_eq!(store.flush_and_checkpoint()?, last_seq + 1);
+
+    Ok(())
+}
+
+// The compaction output is written to the scratch dir, then moved into place.
+#[test]
+fn compact_in_scratch_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let scratch_dir = TempDir::new().expect("unable to create temporary scratch directory");
+    let config = KvStoreConfig {
+        compaction_dir: Some(scratch_dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let stats = store.compact()?;
+    assert!(stats.after_bytes < stats.before_bytes);
+    assert_eq!(store.get("key1")?, Some("value9".to_owned()));
+    assert_eq!(std::fs::read_dir(scratch_dir.path()).unwrap().count(), 0);
+
+    // a failed compaction leaves the segment and no stray files
+    drop(store);
+    let config = KvStoreConfig {
+        compaction_dir: Some(scratch_dir.path().join("missing")),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["CODEC", "VERSION", "data_0", "data_1", "data_2"]);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value9".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `set_if_absent` only writes a missing key, a removed one counts as missing.
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_if_absent("lock".to_owned(), "owner1".to_owned())?);
+    assert!(!store.set_if_absent("lock".to_owned(), "owner2".to_owned())?);
+    assert_eq!(store.get("lock")?, Some("owner1".to_owned()));
+
+    // removed in an older segment
+    store.remove("lock".to_owned())?;
+    store.compact()?;
+    assert!(store.set_if_absent("lock".to_owned(), "owner2".to_owned())?);
+    assert_eq!(store.get("lock")?, Some("owner2".to_owned()));
+
+    // present in an older segment
+    store.compact()?;
+    assert!(!store.set_if_absent("lock".to_owned(), "owner3".to_owned())?);
+    assert_eq!(store.get("lock")?, Some("owner2".to_owned()));
+
+    // only one of the racing handles wins
+    let handles: Vec<_> = (0..4)
+        .map(|id| {
+            let mut store = store.clone();
+            std::thread::spawn(move || {
+                store
+                    .set_if_absent("race".to_owned(), format!("owner{}", id))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let wins = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|won| *won)
+        .count();
+    assert_eq!(wins, 1);
+
+    Ok(())
+}
+
+// A broken offset in the index snapshot is repaired by rebuilding the index.
+#[test]
+fn rebuild_broken_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        flush_interval: Some(std::time::Duration::from_secs(3600)),
+        snapshot_index: true,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // flushed with the snapshot on drop
+    drop(store);
+
+    // point key1 beyond the file, and key2 to the record of key0
+    let snapshot_path = temp_dir.path().join("data_0.index");
+    let mut snapshot: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&snapshot_path).unwrap()).unwrap();
+    let content = std::fs::read_to_string(temp_dir.path().join("data_0")).unwrap();
+    let key0_len = content.find('\n').unwrap() + 1;
+    snapshot["index"]["key1"] = serde_json::json!({ "Exist": { "offset": 1 << 20, "len": 10 } });
+    snapshot["index"]["key2"] = serde_json::json!({ "Exist": { "offset": 0, "len": key0_len } });
+    std::fs::write(&snapshot_path, snapshot.to_string()).unwrap();
+
+    let config = KvStoreConfig {
+        log_file: LogFileOptions {
+            rebuild_index_on_bad_read: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(store.get("key1").is_err());
+    assert!(store.get("key2").is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert!(!snapshot_path.exists());
+
+    Ok(())
+}
+
+// `close` returns the failure of the final flush, which dropping the store only logs.
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        snapshot_index: true,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+    let snapshot_path = temp_dir.path().join("data_0.index");
+    assert!(snapshot_path.exists());
+
+    // a directory in the place of the snapshot fails writing it
+    std::fs::remove_file(&snapshot_path).unwrap();
+    std::fs::create_dir(&snapshot_path).unwrap();
+    std::fs::write(snapshot_path.join("blocker"), "").unwrap();
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    match store.close() {
+        Err(Error::Flush { .. }) => {}
+        res => panic!("unexpected close result: {:?}", res),
+    }
+
+    // the records are synced all the same
+    std::fs::remove_dir_all(&snapshot_path).unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The immutables are merged once they exceed the limit.
+#[test]
+fn max_immutable_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_immutable_segments: Some(3),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        let stats = store.compact()?;
+        // the mutable is counted too
+        assert!(stats.after_segments <= 3 + 1);
+    }
+    assert!(store.stats().segments.len() <= 3 + 1);
+
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// `open_with_report` times each file, in the order of the segments.
+#[test]
+fn open_with_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut store, report) = KvStore::open_with_report(temp_dir.path(), Default::default())?;
+    assert_eq!(report.segments.len(), 1);
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.compact()?;
+    }
+    drop(store);
+
+    let config = KvStoreConfig {
+        slow_open_threshold: std::time::Duration::ZERO,
+        ..Default::default()
+    };
+    let (store, report): (KvStore, OpenReport) =
+        KvStore::open_with_report(temp_dir.path(), config)?;
+    let paths: Vec<_> = report
+        .segments
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect();
+    assert_eq!(
+        paths,
+        store
+            .stats()
+            .segments
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(paths.len(), 4);
+    let segments_total: std::time::Duration =
+        report.segments.iter().map(|(_, elapsed)| *elapsed).sum();
+    assert!(report.total >= segments_total);
+
+    Ok(())
+}
+
+/// json with the chars reversed, a format other than the default
+#[derive(Debug)]
+struct ReversedJson;
+
+impl RecordCodec for ReversedJson {
+    fn name(&self) -> &'static str {
+        "reversed_json"
+    }
+
+    fn encode(&self, item: &LogItem) -> std::result::Result<String, CodecError> {
+        Ok(serde_json::to_string(item)?.chars().rev().collect())
+    }
+
+    fn decode(&self, line: &str) -> std::result::Result<LogItem, CodecError> {
+        let json: String = line.chars().rev().collect();
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+// The records round trip through the codec the store is created with.
+#[test]
+fn record_codec() -> Result<()> {
+    let codec_config = |codec: Arc<dyn RecordCodec>| KvStoreConfig {
+        log_file: LogFileOptions {
+            codec,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    for codec in [
+        Arc::new(LogEncoder) as Arc<dyn RecordCodec>,
+        Arc::new(ReversedJson),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), codec_config(codec.clone()))?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.compact()?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.full_compact()?;
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_config(temp_dir.path(), codec_config(codec.clone()))?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+        assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+        drop(store);
+
+        // the records are in the codec's format
+        let mutable = std::fs::read_to_string(temp_dir.path().join("data_3")).unwrap();
+        let line = mutable.lines().next().unwrap();
+        assert_eq!(codec.decode(line).unwrap().key, "key4");
+
+        // and can't be opened by another codec
+        let other: Arc<dyn RecordCodec> = if codec.name() == "json" {
+            Arc::new(ReversedJson)
+        } else {
+            Arc::new(LogEncoder)
+        };
+        assert!(matches!(
+            KvStore::open_with_config(temp_dir.path(), codec_config(other)),
+            Err(Error::CodecMismatch { .. })
+        ));
+    }
+
+    Ok(())
+}
+
+// A store of version 1 opens, one of a newer layout is rejected.
+#[test]
+fn layout_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let version_path = temp_dir.path().join("VERSION");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(std::fs::read_to_string(&version_path).unwrap(), "1");
+
+    // a store written before the version file is of version 1, and
+    // opening it writes nothing
+    std::fs::remove_file(&version_path).unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    assert!(!version_path.exists());
+
+    std::fs::write(&version_path, "2\n").unwrap();
+    match KvStore::open(temp_dir.path()) {
+        Err(e @ Error::UnsupportedLayout { .. }) => {
+            assert!(e.to_string().contains("upgrade kvs"));
+            assert_eq!(e.exit_code(), exitcode::CONFIG);
+        }
+        res => panic!("unexpected open result: {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A segment failed to be created fails the open at once.
+#[test]
+fn create_segment_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a directory in the place of the temp file of the first segment
+    std::fs::create_dir(temp_dir.path().join("data_0.tmp")).unwrap();
+    match KvStore::open(temp_dir.path()) {
+        Err(e @ Error::CreateSegment { .. }) => {
+            assert!(e.to_string().contains("data_0"));
+            assert_eq!(e.exit_code(), exitcode::IOERR);
+        }
+        res => panic!("unexpected open result: {:?}", res.map(|_| ())),
+    }
+    assert!(!temp_dir.path().join("data_0").exists());
+
+    // and nothing is left half created
+    std::fs::remove_dir(temp_dir.path().join("data_0.tmp")).unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!temp_dir.path().join("data_0.tmp").exists());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A forced next id should name the first segment rotated after the open.
+#[test]
+fn next_id() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let mut paths = std::fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("data_"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, ["data_0", "data_1"]);
+
+    // not above the segments in the dir
+    for next_id in [0, 1] {
+        match KvStore::builder().next_id(next_id).open(temp_dir.path()) {
+            Err(e @ Error::NextId { .. }) => assert_eq!(e.exit_code(), exitcode::CONFIG),
+            res => panic!("unexpected open result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    let mut store = KvStore::builder().next_id(10).open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert!(temp_dir.path().join("data_10").exists());
+    assert!(!temp_dir.path().join("data_2").exists());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    drop(store);
+
+    // and the later opens continue from it
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert!(temp_dir.path().join("data_11").exists());
+
+    Ok(())
+}
+
+// `contains_key` follows the overwrites and removes across the segments.
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1"));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key3"));
+
+    // overwritten and removed in the newer segments
+    store.set("key1".to_owned(), "new_value1".to_owned())?;
+    store.set("key3".to_owned(), "new_value3".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "new_value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key2"));
+    assert!(store.contains_key("key3"));
+    assert!(store.set_if_absent("key4".to_owned(), "value4".to_owned())?);
+    assert!(store.contains_key("key4"));
+
+    // the same through a full compaction and a reopen
+    store.full_compact()?;
+    for (key, present) in [
+        ("key1", true),
+        ("key2", false),
+        ("key3", true),
+        ("key4", true),
+    ] {
+        assert_eq!(store.contains_key(key), present);
+        assert_eq!(store.get(key)?.is_some(), present);
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, present) in [
+        ("key1", true),
+        ("key2", false),
+        ("key3", true),
+        ("key4", true),
+    ] {
+        assert_eq!(store.contains_key(key), present);
+    }
+
+    Ok(())
+}
+
+// `kvs get --default` prints the default only for a missing key.
+#[test]
+fn cli_get_default() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap()
+    };
+
+    let output = kvs(&["get", "key1", "--default", "fallback"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "fallback\n");
+
+    let output = kvs(&["set", "key1", "value1"]);
+    assert!(output.status.success());
+    let output = kvs(&["get", "key1", "--default", "fallback"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "value1\n");
+}
+
+// The registry closes the least recently used store, persisting its index, once over the limit.
+#[test]
+fn store_registry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let paths: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .map(|name| temp_dir.path().join(name))
+        .collect();
+    let registry = StoreRegistry::new(KvStoreConfig::default(), 2);
+
+    for path in paths[..2].iter() {
+        let mut store = registry.get(path)?;
+        store.set("key".to_owned(), path.display().to_string())?;
+    }
+    // "a" is used again, so "b" is the least recently used
+    let _ = registry.get(&paths[0])?;
+    let _ = registry.get(&paths[2])?;
+    assert_eq!(registry.len(), 2);
+    assert!(!registry.contains(&paths[1]));
+    assert!(paths[1].join("data_0.index").exists());
+    assert!(!paths[0].join("data_0.index").exists());
+
+    // a store still in use is kept
+    let in_use = registry.get(&paths[0])?;
+    let _ = registry.get(&paths[1])?;
+    assert!(registry.contains(&paths[0]));
+    assert!(!registry.contains(&paths[2]));
+    assert_eq!(in_use.get("key")?, Some(paths[0].display().to_string()));
+
+    // reopened from the persisted index
+    let store = registry.get(&paths[1])?;
+    assert_eq!(store.get("key")?, Some(paths[1].display().to_string()));
+
+    Ok(())
+}
+
+// With `skip_redundant_sets`, setting a key to the value it holds appends nothing.
+#[test]
+fn skip_redundant_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        skip_redundant_sets: true,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let records = |name: &str| {
+        std::fs::read_to_string(temp_dir.path().join(name))
+            .unwrap()
+            .lines()
+            .count()
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(records("data_0"), 1);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(records("data_0"), 2);
+
+    // the value held by an immutable is checked as well
+    store.compact()?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(records("data_1"), 0);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Misnamed segments are replayed by their modified times into a fresh single-segment store.
+#[test]
+fn rebuild_from_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let mut store = KvStore::open(&store_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.set("key1".to_owned(), "new_value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    // the newer segment is named as if it was older
+    let old_segment = temp_dir.path().join("segment_b");
+    let new_segment = temp_dir.path().join("segment_a");
+    std::fs::rename(store_dir.join("data_0"), &old_segment).unwrap();
+    std::fs::rename(store_dir.join("data_1"), &new_segment).unwrap();
+    let now = std::time::SystemTime::now();
+    std::fs::File::options()
+        .write(true)
+        .open(&old_segment)
+        .unwrap()
+        .set_modified(now - std::time::Duration::from_secs(60))
+        .unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&new_segment)
+        .unwrap()
+        .set_modified(now)
+        .unwrap();
+
+    let out_dir = temp_dir.path().join("rebuilt");
+    let store = KvStore::rebuild_from(&[new_segment.clone(), old_segment.clone()], &out_dir)?;
+    assert_eq!(store.get("key1")?, Some("new_value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.stats().segments.len(), 1);
+    drop(store);
+
+    // a dir holding a store is not overwritten
+    assert!(matches!(
+        KvStore::rebuild_from(&[old_segment, new_segment], &out_dir),
+        Err(Error::RebuildTarget { .. })
+    ));
+
+    Ok(())
+}
+
+// The options chained on the builder take effect on the opened store.
+#[test]
+fn kv_store_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .sync_policy(SyncPolicy::EveryWrite)
+        .compaction_threshold(256)
+        .max_immutable_segments(2)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // compacted by the low threshold, then merged by the segment limit
+    let segments = store.stats().segments.len();
+    assert!(segments > 1 && segments <= 3);
+    drop(store);
+
+    let mut store = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+    assert!(matches!(
+        store.set("key1".to_owned(), "value".to_owned()),
+        Err(Error::ReadOnly { .. })
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(Error::ReadOnly { .. })
+    ));
+    assert!(matches!(store.compact(), Err(Error::ReadOnly { .. })));
+    drop(store);
+
+    let kv_sep_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .engine(Engine::KvSep)
+        .open(kv_sep_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(kv_sep_dir.path().join("data_0.vlog").exists());
+    let store = KvStore::builder()
+        .engine(Engine::KvSep)
+        .open(kv_sep_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A read-only open writes nothing, so it works on a read-only dir and never creates a store.
+#[cfg(unix)]
+#[test]
+fn read_only_writes_nothing() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = || KvStore::builder().read_only(true).open(temp_dir.path());
+    let missing = temp_dir.path().join("missing");
+    assert!(matches!(
+        KvStore::builder().read_only(true).open(&missing),
+        Err(Error::StoreNotFound { .. })
+    ));
+    assert!(!missing.exists());
+    assert!(matches!(read_only(), Err(Error::StoreNotFound { .. })));
+
+    // a store written before the CODEC and VERSION files, whose last
+    // segment is compressed
+    let mut store = KvStore::builder()
+        .compress_segments(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    for name in ["data_1", "CODEC", "VERSION"] {
+        std::fs::remove_file(temp_dir.path().join(name)).unwrap();
+    }
+    let list = || {
+        let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    let before = list();
+
+    let set_mode = |mode| {
+        let permissions = std::fs::Permissions::from_mode(mode);
+        std::fs::set_permissions(temp_dir.path(), permissions).unwrap();
+    };
+    set_mode(0o555);
+    let store = read_only();
+    set_mode(0o755);
+    let store = store?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(list(), before);
+
+    Ok(())
+}
+
+// Keys looking like segment names or paths are plain keys, the segments are untouched.
+#[test]
+fn keys_like_paths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = ["data_5", "../evil", "key/with/slashes", "/abs", "data_0"];
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in keys {
+        store.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    store.remove("data_0".to_owned())?;
+    drop(store);
+
+    let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["CODEC", "VERSION", "data_0"]);
+    assert!(!temp_dir.path().join("../evil").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().segments.len(), 1);
+    for key in keys[..4].iter() {
+        assert_eq!(store.get(key)?, Some(format!("value of {}", key)));
+    }
+    assert_eq!(store.get("data_0")?, None);
+
+    Ok(())
+}
+
+// `SyncPolicy::EveryN` syncs the mutable once every n writes.
+#[test]
+fn sync_every_n_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .sync_policy(SyncPolicy::EveryN(10))
+        .open(temp_dir.path())?;
+    for key_id in 0..25 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.stats().total.syncs, 2);
+
+    // a flush syncs the rest, and starts counting again
+    store.flush_and_checkpoint()?;
+    assert_eq!(store.stats().total.syncs, 3);
+    for key_id in 0..9 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.stats().total.syncs, 3);
+    store.remove("key9".to_owned())?;
+    assert_eq!(store.stats().total.syncs, 4);
+
+    Ok(())
+}
+
+// `kvs get` exits with 0 for a missing key, but not for a store it can't decode.
+#[test]
+fn cli_get_exit_code() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = || {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap()
+    };
+
+    let output = kvs();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Key not found\n");
+
+    let mut data_file = std::fs::File::options()
+        .append(true)
+        .open(temp_dir.path().join("data_0"))
+        .unwrap();
+    data_file.write_all(b"not a record\n").unwrap();
+    drop(data_file);
+    let output = kvs();
+    assert_eq!(output.status.code(), Some(exitcode::DATAERR));
+}
+
+// Two compactions fired together don't interleave, the store stays consistent.
+#[test]
+fn concurrent_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id % 100), format!("value{}", key_id))?;
+    }
+
+    let barrier = Arc::new(std::sync::Barrier::new(2));
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            let mut store = store.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                store.compact().unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // one compaction of the only mutable, whichever order they run in
+    let segments = store.stats().segments;
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].1.live_keys, 100);
+    for key_id in 900..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id % 100))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99")?, Some("value999".to_owned()));
+
+    Ok(())
+}
+
+// Shrinking the indexes after a bulk remove keeps the rest readable.
+#[test]
+fn shrink_to_fit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..900 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.full_compact()?;
+    store.shrink_to_fit();
+
+    assert_eq!(store.stats().total.live_keys, 100);
+    assert_eq!(store.get("key0")?, None);
+    for key_id in 900..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+
+    Ok(())
+}
+
+// With auto-compact disabled, the mutable grows past the threshold until compacted explicitly.
+#[test]
+fn no_auto_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .auto_compact(false)
+        .open(temp_dir.path())?;
+    let large_value = "v".repeat(8 * 1024);
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id % 10), large_value.clone())?;
+    }
+    let segments = store.stats().segments;
+    assert_eq!(segments.len(), 1);
+    assert!(std::fs::metadata(&segments[0].0).unwrap().len() > 1024 * 1024);
+
+    store.compact()?;
+    assert_eq!(store.stats().segments.len(), 2);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--no-auto-compact", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+// `OpenMode::MustExist` fails on a dir without a store, the default creates one.
+#[test]
+fn open_mode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("missing");
+    let must_exist = || {
+        KvStore::builder()
+            .open_mode(OpenMode::MustExist)
+            .open(&path)
+    };
+
+    assert!(matches!(must_exist(), Err(Error::StoreNotFound { .. })));
+    assert!(!path.exists());
+    // an empty dir is not initialized either
+    std::fs::create_dir(&path).unwrap();
+    assert!(matches!(must_exist(), Err(Error::StoreNotFound { .. })));
+    std::fs::remove_dir(&path).unwrap();
+
+    let mut store = KvStore::builder()
+        .open_mode(OpenMode::CreateIfMissing)
+        .open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = must_exist()?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Removing a key held only by an immutable writes a tombstone to the mutable.
+#[test]
+fn remove_from_immutable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1")?, None);
+    assert!(!store.contains_key("key1"));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(Error::KeyNotFound { .. })
+    ));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    // the tombstone survives compacting the mutable
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// An archived segment compressed by gzip should still be read by the store.
+#[test]
+fn compressed_segment() -> Result<()> {
+    let compress = |dir: &std::path::Path, name: &str| {
+        let path = dir.join(name);
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(dir.join(format!("{}.gz", name))).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&std::fs::read(&path).unwrap()).unwrap();
+        let _ = encoder.finish().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(dir.join(format!("{}.index", name)));
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    drop(store);
+
+    compress(temp_dir.path(), "data_0");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..11 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // the last compressed segment is not written, a new one is created
+    compress(temp_dir.path(), "data_1");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    store.set("key11".to_owned(), "value11".to_owned())?;
+    assert!(temp_dir.path().join("data_2").exists());
+
+    // a full compaction merges the compressed segments away
+    store.full_compact()?;
+    assert!(!temp_dir.path().join("data_0.gz").exists());
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key11")?, Some("value11".to_owned()));
+
+    Ok(())
+}
+
+// With `compress_segments`, the compactions gzip the immutables they write.
+#[test]
+fn compress_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compress_segments(true)
+            .multi_compact_segments(2)
+            .open(temp_dir.path())
+    };
+    let mut store = open()?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let plain_len = std::fs::metadata(temp_dir.path().join("data_0"))
+        .unwrap()
+        .len();
+    store.compact()?;
+    assert!(!temp_dir.path().join("data_0").exists());
+    let gz_len = std::fs::metadata(temp_dir.path().join("data_0.gz"))
+        .unwrap()
+        .len();
+    assert!(gz_len * 2 < plain_len, "{} of {}", gz_len, plain_len);
+    // the mutable stays plain
+    assert!(temp_dir.path().join("data_1").exists());
+
+    let assert_values = |store: &KvStore| -> Result<()> {
+        for key_id in 0..200 {
+            let value = match key_id {
+                0 => None,
+                1 => Some("new_value1".to_owned()),
+                key_id => Some(format!("value{}", key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, value);
+        }
+        Ok(())
+    };
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new_value1".to_owned())?;
+    store.compact()?;
+    assert!(temp_dir.path().join("data_1.gz").exists());
+    assert_values(&store)?;
+    drop(store);
+    let mut store = open()?;
+    assert_values(&store)?;
+
+    // the merged ones are compressed again
+    store.multi_compact()?;
+    assert!(!temp_dir.path().join("data_0.gz").exists());
+    assert!(temp_dir.path().join("data_1.gz").exists());
+    assert_values(&store)?;
+    store.full_compact()?;
+    assert!(!temp_dir.path().join("data_1.gz").exists());
+    assert_values(&store)?;
+    drop(store);
+    assert_values(&open()?)?;
+    let plain: Vec<_> = std::fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("data_") && !name.ends_with(".gz"))
+        .collect();
+    assert_eq!(plain.len(), 1, "{:?}", plain);
+
+    Ok(())
+}
+
+// A key normalizer makes the keys of the same canonical form the same key.
+#[test]
+fn key_normalizer() -> Result<()> {
+    #[derive(Debug)]
+    struct CaseFold;
+
+    impl KeyNormalizer for CaseFold {
+        fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+            match key.chars().any(|c| c.is_uppercase()) {
+                true => Cow::Owned(key.to_lowercase()),
+                false => Cow::Borrowed(key),
+            }
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .key_normalizer(Arc::new(CaseFold))
+            .open(temp_dir.path())
+    };
+    let mut store = open()?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("Key")?, Some("value1".to_owned()));
+    assert!(store.contains_key("KEY"));
+    store.set("KEY".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key")?, Some("value2".to_owned()));
+    assert_eq!(store.incr("Counter".to_owned(), 2)?, 2);
+    assert_eq!(store.incr("counter".to_owned(), 3)?, 5);
+    assert_eq!(
+        store.get_many(vec!["kEy".to_owned(), "COUNTER".to_owned()])?,
+        vec![Some("value2".to_owned()), Some("5".to_owned())]
+    );
+
+    // only the canonical keys are written, through the compactions too
+    store.compact()?;
+    store.set("Other".to_owned(), "value3".to_owned())?;
+    store.remove("OTHER".to_owned())?;
+    let keys: Vec<_> = store.iter().map(|pair| pair.unwrap().0).collect();
+    assert_eq!(keys, ["counter", "key"]);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("KeY")?, Some("value2".to_owned()));
+    assert_eq!(store.get("other")?, None);
+    drop(store);
+    // without it, the keys are as written
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key")?, Some("value2".to_owned()));
+    assert_eq!(store.get("Key")?, None);
+
+    Ok(())
+}
+
+// A lazy index of a segment should be built only once a read reaches it.
+#[test]
+fn lazy_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for segment_id in 0..5 {
+        store.set(format!("key{}", segment_id), format!("value{}", segment_id))?;
+        store.compact()?;
+    }
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::builder().lazy_index(true).open(temp_dir.path())?;
+    assert_eq!(store.stats().segments.len(), 6);
+    assert_eq!(store.stats().loaded_segments, 1);
+
+    // the lookup goes from the newest and stops at the segment found
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.stats().loaded_segments, 3);
+    assert!(store.contains_key("key0"));
+    assert_eq!(store.stats().loaded_segments, 6);
+
+    // the presence is checked by the files
+    assert_eq!(store.get("key6")?, None);
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1"));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(Error::KeyNotFound { .. })
+    ));
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    assert_eq!(store.get("key6")?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// Building the indexes in parallel should open the same store, and fail
+// with the first broken segment.
+#[test]
+fn open_parallelism() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for segment_id in 0..8 {
+        store.set(format!("key{}", segment_id), format!("value{}", segment_id))?;
+        store.set("key".to_owned(), format!("value{}", segment_id))?;
+        store.compact()?;
+    }
+    drop(store);
+
+    let (_, sequential) = KvStore::open_with_report(temp_dir.path(), KvStoreConfig::default())?;
+    let config = KvStoreConfig {
+        open_parallelism: 3,
+        ..Default::default()
+    };
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), config.clone())?;
+    assert_eq!(report.segments.len(), 9);
+    let paths = |report: &OpenReport| -> Vec<_> {
+        report
+            .segments
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect()
+    };
+    assert_eq!(paths(&report), paths(&sequential));
+    for segment_id in 0..8 {
+        assert_eq!(
+            store.get(format!("key{}", segment_id))?,
+            Some(format!("value{}", segment_id))
+        );
+    }
+    assert_eq!(store.get("key")?, Some("value7".to_owned()));
+    drop(store);
+
+    let broken = |id: usize| {
+        let path = report.segments[id].0.clone();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("not a record\n{}", content)).unwrap();
+        path
+    };
+    let first = broken(2);
+    let _ = broken(6);
+    match KvStore::open_with_config(temp_dir.path(), config) {
+        Err(Error::Open { path, .. }) => assert_eq!(path, first),
+        res => panic!("unexpected open result: {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// The compactor configured for the store should be the one run on a full mutable.
+#[test]
+fn compactor_mode() -> Result<()> {
+    let segments_after_sets = |mode: CompactorMode| -> Result<usize> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .compaction_threshold(1024)
+            .compactor_mode(mode)
+            .open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "v".repeat(100))?;
+        }
+        Ok(store.stats().segments.len())
+    };
+
+    // a full compaction merges the immutables into one
+    assert!(segments_after_sets(CompactorMode::Simple)? > 2);
+    assert_eq!(segments_after_sets(CompactorMode::Full)?, 2);
+
+    Ok(())
+}
+
+// A key should be looked up by a borrowed or an owned string alike.
+#[test]
+fn get_by_str() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let key = "key1".to_owned();
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get(&key)?, Some("value1".to_owned()));
+    assert_eq!(store.get(key)?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// Replacing a key should return the value it overwrites.
+#[test]
+fn replace_returns_previous() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.replace("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.replace("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    // the previous value may be in an immutable
+    store.compact()?;
+    assert_eq!(
+        store.replace("key1".to_owned(), "value3".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.replace("key1".to_owned(), "value4".to_owned())?, None);
+
+    Ok(())
+}
+
+// A segment line should be decoded by the exported format types, without a store.
+#[test]
+fn decode_segment_line() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let content = std::fs::read_to_string(temp_dir.path().join("data_0")).unwrap();
+    let items: Vec<LogItem> = content
+        .lines()
+        .map(|line| LogEncoder::decode(line).unwrap())
+        .collect();
+    assert_eq!(items.len(), 2);
+    assert_eq!(
+        (
+            items[0].cmd.as_str(),
+            items[0].key.as_str(),
+            items[0].value.as_deref()
+        ),
+        ("set", "key1", Some("value1"))
+    );
+    assert_eq!(
+        (
+            items[1].cmd.as_str(),
+            items[1].key.as_str(),
+            items[1].value.as_deref()
+        ),
+        ("rm", "key1", None)
+    );
+    assert!(items[0].seq < items[1].seq);
+
+    Ok(())
+}
+
+// Removing a key twice should fail the second time in every engine.
+#[test]
+fn remove_twice() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().engine(engine).open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        store.remove("key1".to_owned())?;
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        drop(store);
+
+        // the tombstone is replayed the same
+        let mut store = KvStore::builder().engine(engine).open(temp_dir.path())?;
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(Error::KeyNotFound { .. })
+        ));
+    }
+
+    Ok(())
+}
+
+// `kvs dump-segment <file>` should print the records of the segment with their offsets.
+#[test]
+fn cli_dump_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let content = std::fs::read_to_string(temp_dir.path().join("data_0")).unwrap();
+    let second = content.find('\n').unwrap() + 1;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump-segment", "data_0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("0 set key1 value1\n{} rm key1\n", second));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump-segment", "--json", "data_0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(format!("\"offset\":{}", second)));
+
+    // a missing file fails
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump-segment", "data_9"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// A cancelled compaction should keep the files as they were, and the store consistent.
+#[test]
+fn cancel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .auto_compact(false)
+        .open(temp_dir.path())?;
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    for key_id in 0..1000 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let read_dir = || -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let content = std::fs::read(&path).unwrap();
+                (path, content)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let files = read_dir();
+
+    // cancelled before merging, nothing is swapped
+    let token: CancellationToken = store.cancellation_token();
+    store.clone().cancellation_token().cancel();
+    assert!(token.is_cancelled());
+    assert!(matches!(
+        store.full_compact(),
+        Err(Error::CompactionCancelled { .. })
+    ));
+    assert!(matches!(
+        store.compact(),
+        Err(Error::CompactionCancelled { .. })
+    ));
+    assert_eq!(read_dir(), files);
+
+    // still serving, and consistent after reopened
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    for key_id in 1..2000 {
+        let value = (key_id >= 1000).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(format!("key{}", key_id))?, value);
+    }
+
+    Ok(())
+}
+
+// `kvs --dir` should expand a leading `~` to the home dir.
+#[test]
+fn cli_dir_tilde() -> Result<()> {
+    let home = TempDir::new().expect("unable to create temporary working directory");
+    let cwd = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", "~/mydata", "set", "key1", "value1"])
+        .env("HOME", home.path())
+        .current_dir(&cwd)
+        .assert()
+        .success();
+
+    let store = KvStore::open(home.path().join("mydata"))?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(!cwd.path().join("~").exists());
+
+    Ok(())
+}
+
+// `kvs --dir` should resolve a relative dir under the current dir.
+#[test]
+fn cli_dir_relative() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", "./data", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--dir", "data"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let store = KvStore::open(temp_dir.path().join("data"))?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs bench` should print the throughput of both phases.
+#[test]
+fn cli_bench() {
+    for engine in ["ptr", "value"] {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .args([
+                "bench",
+                "--ops",
+                "50",
+                "--value-size",
+                "8",
+                "--engine",
+                engine,
+            ])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("set: 50 ops"));
+        assert!(stdout.contains("get: 50 ops"));
+        assert!(stdout.contains("ops/sec"));
+    }
+}
+
+// The last-modified time of a key should advance across the overwrites.
+#[test]
+fn get_with_meta() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+        ));
+        let config = KvStoreConfig {
+            clock: clock.clone(),
+            engine,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let (value, first) = store.get_with_meta("key1")?.unwrap();
+        assert_eq!(value, "value1");
+        assert_eq!(first, SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+
+        clock.advance(Duration::from_secs(5));
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        let (value, second) = store.get_with_meta("key1")?.unwrap();
+        assert_eq!(value, "value2");
+        assert_eq!(second, first + Duration::from_secs(5));
+        assert_eq!(store.get_with_meta("key2")?, None);
+
+        // kept by the compactions and after reopened
+        clock.advance(Duration::from_secs(5));
+        store.full_compact()?;
+        drop(store);
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(
+            store.get_with_meta("key1")?,
+            Some(("value2".to_owned(), second))
+        );
+    }
+
+    // a record written before the timestamps is of the unix epoch
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("data_0"),
+        "{\"cmd\":\"set\",\"key\":\"key1\",\"value\":\"value1\"}\n",
+    )
+    .unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_meta("key1")?,
+        Some(("value1".to_owned(), SystemTime::UNIX_EPOCH))
+    );
+
+    Ok(())
+}
+
+// The shared value should be the same as `get` across the segments and removes.
+#[test]
+fn get_shared() -> Result<()> {
+    for engine in [Engine::Ptr, Engine::KvSep] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig {
+            engine,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.full_compact()?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.remove("key2".to_owned())?;
+
+        assert_eq!(store.get_shared("key1")?.as_deref(), Some("value3"));
+        assert_eq!(store.get_shared("key2")?, None);
+        assert_eq!(store.get_shared("key3")?, None);
+    }
+
+    Ok(())
+}